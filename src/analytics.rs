@@ -0,0 +1,405 @@
+// analytics.rs
+
+use chrono::Datelike;
+use futures::stream::TryStreamExt;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::search_stream;
+use crate::DbError;
+use crate::PnlFilter;
+use crate::PnlLog;
+use crate::PositionFilter;
+use crate::PositionLog;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const ANALYTICS_PAGE_SIZE: u32 = 1000;
+
+/// Cumulative PnL after one period or one closed position.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EquityPoint {
+    /// Close time of the position or time of the balance entry, if known.
+    pub timestamp: Option<i64>,
+    /// `close_time_str` of the position or `date` of the balance entry.
+    pub label: String,
+    pub pnl: Decimal,
+    pub equity: Decimal,
+}
+
+/// Performance of a series of PnLs, either the periods of the "balance"
+/// collection or closed positions in order of closing.
+///
+/// Equity starts at zero, so drawdowns are amounts rather than ratios and
+/// Calmar is the total PnL over the max drawdown. Sharpe and Sortino are per
+/// period (or per trade) and are not annualized. Ratios that would divide
+/// by zero are `None`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PerformanceReport {
+    pub equity_curve: Vec<EquityPoint>,
+    pub total_pnl: Decimal,
+    /// Largest fall of equity from a previous peak.
+    pub max_drawdown: Decimal,
+    /// Mean of the deepest point of each drawdown, including one that has
+    /// not recovered yet.
+    pub average_drawdown: Decimal,
+    pub sharpe_ratio: Option<Decimal>,
+    pub sortino_ratio: Option<Decimal>,
+    pub calmar_ratio: Option<Decimal>,
+    /// Closed positions; zero for balance reports.
+    pub num_trades: usize,
+    pub win_rate: Option<Decimal>,
+    /// Gross profit over gross loss.
+    pub profit_factor: Option<Decimal>,
+    /// Mean PnL per trade.
+    pub expectancy: Option<Decimal>,
+    /// Mean seconds from open to close.
+    pub average_holding_time: Option<i64>,
+}
+
+fn sqrt(value: Decimal) -> Option<Decimal> {
+    Decimal::from_f64(value.to_f64()?.sqrt())
+}
+
+fn ratio(numerator: Decimal, denominator: Decimal) -> Option<Decimal> {
+    (!denominator.is_zero()).then(|| (numerator / denominator).normalize())
+}
+
+impl PerformanceReport {
+    fn from_equity_curve(equity_curve: Vec<EquityPoint>) -> Self {
+        let pnls: Vec<_> = equity_curve.iter().map(|point| point.pnl).collect();
+        let total_pnl: Decimal = pnls.iter().sum();
+
+        let (mut peak, mut max_drawdown, mut drawdown) = (Decimal::ZERO, Decimal::ZERO, None);
+        let mut drawdowns = vec![];
+        for point in &equity_curve {
+            if point.equity >= peak {
+                peak = point.equity;
+                drawdowns.extend(drawdown.take());
+            } else {
+                let depth = peak - point.equity;
+                max_drawdown = max_drawdown.max(depth);
+                drawdown = Some(drawdown.map_or(depth, |d: Decimal| d.max(depth)));
+            }
+        }
+        drawdowns.extend(drawdown);
+        let average_drawdown = if drawdowns.is_empty() {
+            Decimal::ZERO
+        } else {
+            drawdowns.iter().sum::<Decimal>() / Decimal::from(drawdowns.len())
+        };
+
+        let (sharpe_ratio, sortino_ratio) = if pnls.len() < 2 {
+            (None, None)
+        } else {
+            let n = Decimal::from(pnls.len());
+            let mean = total_pnl / n;
+            let variance = pnls
+                .iter()
+                .map(|p| (p - mean) * (p - mean))
+                .sum::<Decimal>()
+                / (n - Decimal::ONE);
+            let downside = pnls
+                .iter()
+                .filter(|p| p.is_sign_negative())
+                .map(|p| p * p)
+                .sum::<Decimal>()
+                / n;
+            (
+                sqrt(variance).and_then(|std| ratio(mean, std)),
+                sqrt(downside).and_then(|std| ratio(mean, std)),
+            )
+        };
+
+        Self {
+            equity_curve,
+            total_pnl,
+            max_drawdown,
+            average_drawdown: average_drawdown.normalize(),
+            sharpe_ratio,
+            sortino_ratio,
+            calmar_ratio: ratio(total_pnl, max_drawdown),
+            ..Default::default()
+        }
+    }
+
+    /// Report of the "balance" entries, in time order.
+    pub fn from_pnl_logs(pnl_logs: &[PnlLog]) -> Self {
+        let mut pnl_logs: Vec<_> = pnl_logs.iter().collect();
+        sort_by_time(&mut pnl_logs);
+
+        let mut equity = Decimal::ZERO;
+        let equity_curve = pnl_logs
+            .into_iter()
+            .map(|log| {
+                equity += log.pnl;
+                EquityPoint {
+                    timestamp: log.effective_timestamp(),
+                    label: log.date.clone(),
+                    pnl: log.pnl,
+                    equity,
+                }
+            })
+            .collect();
+        Self::from_equity_curve(equity_curve)
+    }
+
+    /// Report of the closed positions among `positions`, using `pnl` as
+    /// stored. Positions that are still open or were canceled are ignored.
+    pub fn from_positions<'a>(positions: impl IntoIterator<Item = &'a PositionLog>) -> Self {
+        let mut closed: Vec<_> = positions
+            .into_iter()
+            .filter_map(|p| {
+                let close_timestamp = p.close_timestamp?;
+                p.state.is_closed().then_some((close_timestamp, p))
+            })
+            .collect();
+        closed.sort_by_key(|(close_timestamp, p)| (*close_timestamp, p.id));
+
+        let mut equity = Decimal::ZERO;
+        let equity_curve = closed
+            .iter()
+            .map(|(close_timestamp, p)| {
+                equity += p.pnl;
+                EquityPoint {
+                    timestamp: Some(*close_timestamp),
+                    label: p.close_time_str.clone(),
+                    pnl: p.pnl,
+                    equity,
+                }
+            })
+            .collect();
+        let mut report = Self::from_equity_curve(equity_curve);
+        if closed.is_empty() {
+            return report;
+        }
+
+        let n = Decimal::from(closed.len());
+        let wins = closed.iter().filter(|(_, p)| p.pnl > Decimal::ZERO).count();
+        let gross_profit: Decimal = closed.iter().map(|(_, p)| p.pnl.max(Decimal::ZERO)).sum();
+        let gross_loss: Decimal = closed
+            .iter()
+            .map(|(_, p)| (-p.pnl).max(Decimal::ZERO))
+            .sum();
+        let holding_time: i64 = closed
+            .iter()
+            .map(|(close_timestamp, p)| close_timestamp - p.open_timestamp)
+            .sum();
+
+        report.num_trades = closed.len();
+        report.win_rate = ratio(Decimal::from(wins), n);
+        report.profit_factor = ratio(gross_profit, gross_loss);
+        report.expectancy = ratio(report.total_pnl, n);
+        report.average_holding_time = Some(holding_time / closed.len() as i64);
+        report
+    }
+}
+
+/// Field to break a report down by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PerformanceGroup {
+    Fund,
+    Token,
+    /// Positions without a `trading_strategy` are left out.
+    Strategy,
+}
+
+impl PerformanceGroup {
+    fn key(&self, position: &PositionLog) -> Option<String> {
+        match self {
+            PerformanceGroup::Fund => Some(position.fund_name.clone()),
+            PerformanceGroup::Token => Some(position.token_name.clone()),
+            PerformanceGroup::Strategy => position.trading_strategy.map(|s| format!("{:?}", s)),
+        }
+    }
+}
+
+/// Reports of `positions` by fund name, token name or strategy. Strategies
+/// are keyed by their `Debug` form, e.g. "Inago(Up)".
+pub fn group_performance<'a>(
+    positions: impl IntoIterator<Item = &'a PositionLog>,
+    group: PerformanceGroup,
+) -> BTreeMap<String, PerformanceReport> {
+    let mut groups: BTreeMap<String, Vec<&PositionLog>> = BTreeMap::new();
+    for position in positions {
+        if let Some(key) = group.key(position) {
+            groups.entry(key).or_default().push(position);
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(key, positions)| (key, PerformanceReport::from_positions(positions)))
+        .collect()
+}
+
+async fn closed_positions(
+    db: &dyn StorageBackend,
+    filter: &PositionFilter,
+) -> Result<Vec<PositionLog>, DbError> {
+    let item = PositionLog::default();
+    let positions = search_stream(
+        db,
+        &item,
+        SearchMode::Ascending,
+        ANALYTICS_PAGE_SIZE,
+        Some("open_timestamp"),
+        Some(filter.to_document()),
+    );
+    positions
+        .try_filter(|p| futures::future::ready(p.state.is_closed()))
+        .try_collect()
+        .await
+}
+
+/// Report of the closed positions matching `filter`.
+pub async fn position_performance(
+    db: &dyn StorageBackend,
+    filter: &PositionFilter,
+) -> Result<PerformanceReport, DbError> {
+    let positions = closed_positions(db, filter).await?;
+    Ok(PerformanceReport::from_positions(&positions))
+}
+
+/// Reports of the closed positions matching `filter`, by `group`.
+pub async fn position_performance_by(
+    db: &dyn StorageBackend,
+    filter: &PositionFilter,
+    group: PerformanceGroup,
+) -> Result<BTreeMap<String, PerformanceReport>, DbError> {
+    let positions = closed_positions(db, filter).await?;
+    Ok(group_performance(&positions, group))
+}
+
+/// Orders "balance" entries by time, since ids wrap around. Entries whose
+/// time is unknown come last, in id order.
+fn sort_by_time<T: std::borrow::Borrow<PnlLog>>(pnl_logs: &mut [T]) {
+    pnl_logs.sort_by_key(|log| {
+        let log = log.borrow();
+        let timestamp = log.effective_timestamp();
+        (timestamp.is_none(), timestamp, log.id)
+    });
+}
+
+/// Entries of the "balance" collection matching `filter`, in time order.
+pub async fn pnl_logs(db: &dyn StorageBackend, filter: &PnlFilter) -> Result<Vec<PnlLog>, DbError> {
+    let item = PnlLog::default();
+    let mut pnl_logs: Vec<PnlLog> = search_stream(
+        db,
+        &item,
+        SearchMode::Ascending,
+        ANALYTICS_PAGE_SIZE,
+        Some("id"),
+        Some(filter.to_document()),
+    )
+    .try_filter(|log| futures::future::ready(filter.matches(log)))
+    .try_collect()
+    .await?;
+    sort_by_time(&mut pnl_logs);
+    Ok(pnl_logs)
+}
+
+/// Report of the "balance" entries matching `filter`, in time order.
+pub async fn balance_performance(
+    db: &dyn StorageBackend,
+    filter: &PnlFilter,
+) -> Result<PerformanceReport, DbError> {
+    let pnl_logs = pnl_logs(db, filter).await?;
+    Ok(PerformanceReport::from_pnl_logs(&pnl_logs))
+}
+
+/// Length of a PnL rollup period. Periods are in UTC and weeks start on
+/// Monday.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PnlPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl PnlPeriod {
+    /// Start of the period containing `timestamp`.
+    pub fn start(&self, timestamp: i64) -> Option<i64> {
+        let date = chrono::DateTime::from_timestamp(timestamp, 0)?.date_naive();
+        let date = match self {
+            PnlPeriod::Day => date,
+            PnlPeriod::Week => {
+                date - chrono::Days::new(date.weekday().num_days_from_monday() as u64)
+            }
+            PnlPeriod::Month => date.with_day(1)?,
+        };
+        Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp())
+    }
+}
+
+/// Sum of the PnL entries of one period.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PnlRollup {
+    /// Start of the period.
+    pub timestamp: i64,
+    /// First day of the period, "%Y-%m-%d".
+    pub date: String,
+    pub pnl: Decimal,
+    /// Sum of this and the earlier periods.
+    pub cumulative_pnl: Decimal,
+    pub num_entries: usize,
+}
+
+/// Sums `pnl_logs` by `period`, in time order. Entries with neither a
+/// timestamp nor a readable date are left out.
+pub fn rollup_pnl(pnl_logs: &[PnlLog], period: PnlPeriod) -> Vec<PnlRollup> {
+    let mut periods: BTreeMap<i64, (Decimal, usize)> = BTreeMap::new();
+    for log in pnl_logs {
+        let Some(start) = log.effective_timestamp().and_then(|t| period.start(t)) else {
+            log::warn!("rollup_pnl: no date, id = {:?}", log.id);
+            continue;
+        };
+        let (pnl, num) = periods.entry(start).or_default();
+        *pnl += log.pnl;
+        *num += 1;
+    }
+
+    let mut cumulative_pnl = Decimal::ZERO;
+    periods
+        .into_iter()
+        .map(|(timestamp, (pnl, num_entries))| {
+            cumulative_pnl += pnl;
+            PnlRollup {
+                timestamp,
+                date: chrono::DateTime::from_timestamp(timestamp, 0)
+                    .map(|time| time.format("%Y-%m-%d").to_string())
+                    .unwrap_or_default(),
+                pnl,
+                cumulative_pnl,
+                num_entries,
+            }
+        })
+        .collect()
+}
+
+/// The `n` days with the highest PnL, best first.
+pub fn best_pnl_days(pnl_logs: &[PnlLog], n: usize) -> Vec<PnlRollup> {
+    let mut days = rollup_pnl(pnl_logs, PnlPeriod::Day);
+    days.sort_by(|a, b| b.pnl.cmp(&a.pnl).then(a.timestamp.cmp(&b.timestamp)));
+    days.truncate(n);
+    days
+}
+
+/// The `n` days with the lowest PnL, worst first.
+pub fn worst_pnl_days(pnl_logs: &[PnlLog], n: usize) -> Vec<PnlRollup> {
+    let mut days = rollup_pnl(pnl_logs, PnlPeriod::Day);
+    days.sort_by(|a, b| a.pnl.cmp(&b.pnl).then(a.timestamp.cmp(&b.timestamp)));
+    days.truncate(n);
+    days
+}
+
+/// Rollup of the "balance" entries matching `filter`.
+pub async fn pnl_rollup(
+    db: &dyn StorageBackend,
+    filter: &PnlFilter,
+    period: PnlPeriod,
+) -> Result<Vec<PnlRollup>, DbError> {
+    let pnl_logs = pnl_logs(db, filter).await?;
+    Ok(rollup_pnl(&pnl_logs, period))
+}
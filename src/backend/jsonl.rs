@@ -0,0 +1,239 @@
+// backend/jsonl.rs
+
+use async_trait::async_trait;
+use bson::{doc, Bson, Document};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::{
+    document_from_json, document_to_json, validate_replacement, IndexSpec, MemoryBackend,
+    StorageBackend,
+};
+use crate::DbError;
+use crate::SearchMode;
+
+const EXTENSION: &str = "jsonl";
+
+/// Append-only store writing one JSON Lines file per collection
+/// (`position.jsonl`, `price.jsonl`, `balance.jsonl`, `app-state.jsonl`, ...).
+///
+/// Every change is appended as a record: `put` with the full document after
+/// the change, `delete` with the `_id` of the removed document, `clear` and
+/// `index`. The files are replayed into memory when the store is opened, so
+/// queries are served by a `MemoryBackend`.
+pub struct JsonlBackend {
+    dir: PathBuf,
+    memory: MemoryBackend,
+    files: Mutex<HashMap<String, File>>,
+}
+
+impl JsonlBackend {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, DbError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let memory = MemoryBackend::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            let Some(collection) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let count = Self::replay(&memory, collection, &path)?;
+            log::info!("Replayed {} records of `{}`", count, collection);
+        }
+
+        Ok(Self {
+            dir,
+            memory,
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn replay(memory: &MemoryBackend, collection: &str, path: &Path) -> Result<usize, DbError> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut count = 0;
+        for (number, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = match document_from_json(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    // A crash can leave a partially written last line behind.
+                    log::warn!(
+                        "{:?}:{}: skipping unreadable record: {}",
+                        path,
+                        number + 1,
+                        e
+                    );
+                    continue;
+                }
+            };
+            match record.get_str("op") {
+                Ok("put") => {
+                    if let Ok(document) = record.get_document("doc") {
+                        memory.put_document(collection, document.clone());
+                    }
+                }
+                Ok("delete") => {
+                    if let Some(id) = record.get("_id") {
+                        memory.remove_document(collection, id);
+                    }
+                }
+                Ok("clear") => memory.clear(collection),
+                Ok("index") => {
+                    if let (Ok(name), Ok(keys)) =
+                        (record.get_str("name"), record.get_document("keys"))
+                    {
+                        let unique = record.get_bool("unique").unwrap_or(false);
+                        let mut index = IndexSpec::new(name, keys.clone(), unique);
+                        if let Ok(filter) = record.get_document("partial_filter") {
+                            index = index.partial_filter(filter.clone());
+                        }
+                        memory.add_indexes(collection, &[index]);
+                    }
+                }
+                _ => log::warn!("{:?}:{}: unknown record", path, number + 1),
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn append(
+        files: &mut HashMap<String, File>,
+        dir: &Path,
+        collection: &str,
+        record: Document,
+    ) -> Result<(), DbError> {
+        if !files.contains_key(collection) {
+            let path = dir.join(format!("{}.{}", collection, EXTENSION));
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            files.insert(collection.to_owned(), file);
+        }
+        let file = files.get_mut(collection).unwrap();
+        let mut line = document_to_json(&record);
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for JsonlBackend {
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), DbError> {
+        let mut files = self.files.lock().unwrap();
+        let document = self.memory.insert_document(collection, document)?;
+        Self::append(
+            &mut files,
+            &self.dir,
+            collection,
+            doc! { "op": "put", "doc": document },
+        )
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        self.find_one_and_update(collection, query, update, upsert)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        let mut files = self.files.lock().unwrap();
+        let document = self
+            .memory
+            .update_document(collection, &query, &update, upsert)?;
+        if let Some(document) = &document {
+            Self::append(
+                &mut files,
+                &self.dir,
+                collection,
+                doc! { "op": "put", "doc": document.clone() },
+            )?;
+        }
+        Ok(document)
+    }
+
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        validate_replacement(&document)?;
+        self.update_one(collection, query, document, upsert).await
+    }
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError> {
+        let mut files = self.files.lock().unwrap();
+        let document = self.memory.delete_document(collection, &query)?;
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        Self::append(
+            &mut files,
+            &self.dir,
+            collection,
+            doc! { "op": "delete", "_id": id },
+        )
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError> {
+        let mut files = self.files.lock().unwrap();
+        self.memory.clear(collection);
+        Self::append(&mut files, &self.dir, collection, doc! { "op": "clear" })
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        self.memory
+            .find(collection, query, mode, limit, id, sort_key)
+    }
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError> {
+        let mut files = self.files.lock().unwrap();
+        for index in self.memory.add_indexes(collection, &indexes) {
+            let mut record = doc! {
+                "op": "index",
+                "name": index.name,
+                "keys": index.keys,
+                "unique": index.unique,
+            };
+            if let Some(filter) = index.partial_filter {
+                record.insert("partial_filter", filter);
+            }
+            Self::append(&mut files, &self.dir, collection, record)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+// backend/memory.rs
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use super::query::{apply_update, compare, get_path, is_operator_document, matches, upsert_seed};
+use super::{validate_replacement, validate_sort_key, IndexSpec, StorageBackend};
+use crate::decimal;
+use crate::DbError;
+use crate::SearchMode;
+
+/// Hashable form of a value, equal for values that `values_equal` takes as
+/// equal, e.g. numbers of different widths. A missing value is null.
+fn value_key(value: Option<&Bson>) -> String {
+    match value {
+        None | Some(Bson::Null) | Some(Bson::Undefined) => "null".to_owned(),
+        Some(Bson::String(s)) => format!("s:{}", s),
+        Some(value) => match decimal::from_number(value) {
+            Some(number) => format!("n:{}", number.normalize()),
+            None => format!("{:?}", value),
+        },
+    }
+}
+
+struct UniqueKey {
+    name: String,
+    fields: Vec<String>,
+    partial_filter: Option<Document>,
+    /// Slots of the indexed documents by key.
+    entries: HashMap<String, BTreeSet<u64>>,
+}
+
+impl UniqueKey {
+    /// The key of `document` in this index, or `None` if it is not indexed.
+    fn key(&self, document: &Document) -> Result<Option<String>, DbError> {
+        if let Some(filter) = &self.partial_filter {
+            if !matches(document, filter)? {
+                return Ok(None);
+            }
+        }
+        // Like MongoDB, a missing field is indexed as null.
+        let values: Vec<_> = self
+            .fields
+            .iter()
+            .map(|field| value_key(get_path(document, field)))
+            .collect();
+        Ok(Some(values.join("\u{0}")))
+    }
+}
+
+fn add_slot(entries: &mut HashMap<String, BTreeSet<u64>>, key: String, slot: u64) {
+    entries.entry(key).or_default().insert(slot);
+}
+
+fn remove_slot(entries: &mut HashMap<String, BTreeSet<u64>>, key: &str, slot: u64) {
+    if let Some(slots) = entries.get_mut(key) {
+        slots.remove(&slot);
+        if slots.is_empty() {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Documents keyed by insertion order, with hash indexes on `_id`, `id` and
+/// the unique indexes, so that writes and lookups by id do not scan the
+/// collection.
+#[derive(Default)]
+struct MemoryCollection {
+    documents: BTreeMap<u64, Document>,
+    next_slot: u64,
+    by_object_id: HashMap<String, BTreeSet<u64>>,
+    by_id: HashMap<String, BTreeSet<u64>>,
+    unique_keys: Vec<UniqueKey>,
+}
+
+impl MemoryCollection {
+    fn check_unique(&self, document: &Document, skip: Option<u64>) -> Result<(), DbError> {
+        let taken = |slots: Option<&BTreeSet<u64>>| {
+            slots.is_some_and(|slots| slots.iter().any(|slot| Some(*slot) != skip))
+        };
+        if let Some(id) = document.get("_id") {
+            if taken(self.by_object_id.get(&value_key(Some(id)))) {
+                return Err(DbError::DuplicateKey(
+                    "duplicate key error index: _id_".to_string(),
+                ));
+            }
+        }
+        for key in &self.unique_keys {
+            if let Some(value) = key.key(document)? {
+                if taken(key.entries.get(&value)) {
+                    return Err(DbError::DuplicateKey(format!(
+                        "duplicate key error index: {}",
+                        key.name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&mut self, slot: u64, document: &Document) {
+        if let Some(id) = document.get("_id") {
+            add_slot(&mut self.by_object_id, value_key(Some(id)), slot);
+        }
+        add_slot(&mut self.by_id, value_key(document.get("id")), slot);
+        for key in &mut self.unique_keys {
+            if let Ok(Some(value)) = key.key(document) {
+                add_slot(&mut key.entries, value, slot);
+            }
+        }
+    }
+
+    fn unindex(&mut self, slot: u64, document: &Document) {
+        if let Some(id) = document.get("_id") {
+            remove_slot(&mut self.by_object_id, &value_key(Some(id)), slot);
+        }
+        remove_slot(&mut self.by_id, &value_key(document.get("id")), slot);
+        for key in &mut self.unique_keys {
+            if let Ok(Some(value)) = key.key(document) {
+                remove_slot(&mut key.entries, &value, slot);
+            }
+        }
+    }
+
+    fn push(&mut self, document: Document) {
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.index(slot, &document);
+        self.documents.insert(slot, document);
+    }
+
+    fn replace(&mut self, slot: u64, document: Document) {
+        if let Some(old) = self.documents.remove(&slot) {
+            self.unindex(slot, &old);
+        }
+        self.index(slot, &document);
+        self.documents.insert(slot, document);
+    }
+
+    fn remove(&mut self, slot: u64) -> Option<Document> {
+        let document = self.documents.remove(&slot)?;
+        self.unindex(slot, &document);
+        Some(document)
+    }
+
+    fn clear(&mut self) {
+        self.documents.clear();
+        self.by_object_id.clear();
+        self.by_id.clear();
+        for key in &mut self.unique_keys {
+            key.entries.clear();
+        }
+    }
+
+    /// The slots that can match `query`, in insertion order, when it asks
+    /// for one `_id` or `id`; `None` when every document has to be checked.
+    fn candidates(&self, query: &Document) -> Option<Vec<u64>> {
+        let plain = |key: &str| {
+            query
+                .get(key)
+                .filter(|value| is_operator_document(value).is_none())
+        };
+        let slots = if let Some(id) = plain("_id") {
+            self.by_object_id.get(&value_key(Some(id)))
+        } else if let Some(id) = plain("id") {
+            self.by_id.get(&value_key(Some(id)))
+        } else {
+            return None;
+        };
+        Some(slots.map_or(vec![], |slots| slots.iter().copied().collect()))
+    }
+
+    /// The matching documents with their slots, in insertion order.
+    fn matching<'a>(
+        &'a self,
+        query: &'a Document,
+    ) -> impl Iterator<Item = Result<(u64, &'a Document), DbError>> + 'a {
+        let slots: Box<dyn Iterator<Item = u64> + 'a> = match self.candidates(query) {
+            Some(slots) => Box::new(slots.into_iter()),
+            None => Box::new(self.documents.keys().copied()),
+        };
+        slots.filter_map(move |slot| {
+            let document = self.documents.get(&slot)?;
+            match matches(document, query) {
+                Ok(true) => Some(Ok((slot, document))),
+                Ok(false) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })
+    }
+
+    fn position(&self, query: &Document) -> Result<Option<u64>, DbError> {
+        self.matching(query)
+            .next()
+            .transpose()
+            .map(|found| found.map(|(slot, _)| slot))
+    }
+}
+
+/// Process-local store with the same semantics as the MongoDB backend.
+///
+/// Nothing is persisted; it is meant for unit tests and back tests.
+#[derive(Default)]
+pub struct MemoryBackend {
+    collections: Mutex<HashMap<String, MemoryCollection>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn insert_document(
+        &self,
+        collection: &str,
+        mut document: Document,
+    ) -> Result<Document, DbError> {
+        let mut collections = self.collections.lock().unwrap();
+        let collection = collections.entry(collection.to_owned()).or_default();
+        if !document.contains_key("_id") {
+            document.insert("_id", bson::oid::ObjectId::new());
+        }
+        collection.check_unique(&document, None)?;
+        collection.push(document.clone());
+        Ok(document)
+    }
+
+    /// Applies `update` to the first matching document and returns it as
+    /// stored afterwards.
+    pub(crate) fn update_document(
+        &self,
+        collection: &str,
+        query: &Document,
+        update: &Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        let mut collections = self.collections.lock().unwrap();
+        let collection = collections.entry(collection.to_owned()).or_default();
+        match collection.position(query)? {
+            Some(slot) => {
+                let mut document = collection.documents[&slot].clone();
+                apply_update(&mut document, update, false)?;
+                collection.check_unique(&document, Some(slot))?;
+                collection.replace(slot, document.clone());
+                Ok(Some(document))
+            }
+            None if upsert => {
+                let mut document = upsert_seed(query);
+                apply_update(&mut document, update, true)?;
+                if !document.contains_key("_id") {
+                    document.insert("_id", bson::oid::ObjectId::new());
+                }
+                collection.check_unique(&document, None)?;
+                collection.push(document.clone());
+                Ok(Some(document))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn delete_document(
+        &self,
+        collection: &str,
+        query: &Document,
+    ) -> Result<Document, DbError> {
+        let mut collections = self.collections.lock().unwrap();
+        let collection = collections.entry(collection.to_owned()).or_default();
+        match collection.position(query)? {
+            Some(slot) => Ok(collection.remove(slot).unwrap_or_default()),
+            None => Err(DbError::NotFound("Item not found".to_string())),
+        }
+    }
+
+    pub(crate) fn clear(&self, collection: &str) {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(collection) = collections.get_mut(collection) {
+            collection.clear();
+        }
+    }
+
+    /// Registers the unique indexes among `indexes` and returns the ones that
+    /// were not known before.
+    pub(crate) fn add_indexes(&self, collection: &str, indexes: &[IndexSpec]) -> Vec<IndexSpec> {
+        let mut collections = self.collections.lock().unwrap();
+        let collection = collections.entry(collection.to_owned()).or_default();
+        let mut added = vec![];
+        for index in indexes.iter().filter(|index| index.unique) {
+            if collection
+                .unique_keys
+                .iter()
+                .any(|key| key.name == index.name)
+            {
+                continue;
+            }
+            let mut key = UniqueKey {
+                name: index.name.clone(),
+                fields: index.keys.keys().cloned().collect(),
+                partial_filter: index.partial_filter.clone(),
+                entries: HashMap::new(),
+            };
+            for (slot, document) in &collection.documents {
+                if let Ok(Some(value)) = key.key(document) {
+                    add_slot(&mut key.entries, value, *slot);
+                }
+            }
+            collection.unique_keys.push(key);
+            added.push(index.clone());
+        }
+        added
+    }
+
+    /// Stores `document` as is, replacing the document with the same `_id`.
+    /// Used when replaying a log, so no constraints are checked.
+    pub(crate) fn put_document(&self, collection: &str, document: Document) {
+        let mut collections = self.collections.lock().unwrap();
+        let collection = collections.entry(collection.to_owned()).or_default();
+        let slot = document.get("_id").and_then(|id| {
+            let slots = collection.by_object_id.get(&value_key(Some(id)))?;
+            slots.iter().next().copied()
+        });
+        match slot {
+            Some(slot) => collection.replace(slot, document),
+            None => collection.push(document),
+        }
+    }
+
+    pub(crate) fn remove_document(&self, collection: &str, id: &Bson) {
+        let mut collections = self.collections.lock().unwrap();
+        if let Some(collection) = collections.get_mut(collection) {
+            let slots = collection
+                .by_object_id
+                .get(&value_key(Some(id)))
+                .cloned()
+                .unwrap_or_default();
+            for slot in slots {
+                collection.remove(slot);
+            }
+        }
+    }
+
+    pub(crate) fn find(
+        &self,
+        collection: &str,
+        mut query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        validate_sort_key(sort_key)?;
+
+        let descending = match mode {
+            SearchMode::Ascending => Some(false),
+            SearchMode::Descending => Some(true),
+            SearchMode::ById => {
+                if let Some(id_value) = id {
+                    query.insert("id", id_value);
+                } else {
+                    return Err(DbError::InvalidQuery("ID not provided".to_string()));
+                }
+                None
+            }
+        };
+
+        let mut items = vec![];
+        {
+            let collections = self.collections.lock().unwrap();
+            if let Some(collection) = collections.get(collection) {
+                for found in collection.matching(&query) {
+                    items.push(found?.1.clone());
+                }
+            }
+        }
+
+        if let Some(descending) = descending {
+            // Ties are broken by id, as keyset pagination relies on it.
+            items.sort_by(|a, b| {
+                let ordering = [sort_key, "id"]
+                    .iter()
+                    .map(|key| {
+                        compare(
+                            get_path(a, key).unwrap_or(&Bson::Null),
+                            get_path(b, key).unwrap_or(&Bson::Null),
+                        )
+                    })
+                    .fold(std::cmp::Ordering::Equal, std::cmp::Ordering::then);
+                if descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+            if let Some(limit_value) = limit {
+                items.truncate(limit_value as usize);
+            }
+        }
+
+        if items.is_empty() {
+            Err(DbError::NotFound("Item not found".to_string()))
+        } else {
+            Ok(items)
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), DbError> {
+        self.insert_document(collection, document)?;
+        Ok(())
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        self.update_document(collection, &query, &update, upsert)?;
+        Ok(())
+    }
+
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        self.update_document(collection, &query, &update, upsert)
+    }
+
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        validate_replacement(&document)?;
+        self.update_document(collection, &query, &document, upsert)?;
+        Ok(())
+    }
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError> {
+        self.delete_document(collection, &query)?;
+        Ok(())
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError> {
+        self.clear(collection);
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        self.find(collection, query, mode, limit, id, sort_key)
+    }
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError> {
+        self.add_indexes(collection, &indexes);
+        Ok(())
+    }
+}
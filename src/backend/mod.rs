@@ -0,0 +1,284 @@
+// backend/mod.rs
+
+mod jsonl;
+mod memory;
+mod mongo;
+pub(crate) mod query;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use std::sync::Arc;
+
+use crate::DbError;
+use crate::SearchMode;
+
+pub use jsonl::JsonlBackend;
+pub use memory::MemoryBackend;
+pub use mongo::HelperCollection;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBackend;
+
+pub(crate) fn validate_sort_key(sort_key: &str) -> Result<(), DbError> {
+    match sort_key {
+        "id" | "open_timestamp" | "close_timestamp" | "price_point.timestamp" | "timestamp" => {
+            Ok(())
+        }
+        _ => Err(DbError::InvalidQuery(format!(
+            "Invalid sort key: {}",
+            sort_key
+        ))),
+    }
+}
+
+pub(crate) fn document_to_json(document: &Document) -> String {
+    Bson::Document(document.clone())
+        .into_relaxed_extjson()
+        .to_string()
+}
+
+pub(crate) fn document_from_json(text: &str) -> Result<Document, DbError> {
+    let value: serde_json::Value = serde_json::from_str(text)?;
+    match Bson::try_from(value) {
+        Ok(Bson::Document(document)) => Ok(document),
+        Ok(_) => Err(DbError::Serialization(
+            "stored value is not a document".into(),
+        )),
+        Err(e) => Err(DbError::Serialization(Box::new(e))),
+    }
+}
+
+/// Rejects replacement documents that contain update operators, which
+/// `apply_update` would otherwise treat as an update.
+pub(crate) fn validate_replacement(document: &Document) -> Result<(), DbError> {
+    match document.keys().find(|key| key.starts_with('$')) {
+        Some(key) => Err(DbError::InvalidQuery(format!(
+            "Replacement document contains operator: {}",
+            key
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexSpec {
+    pub name: String,
+    pub keys: Document,
+    pub unique: bool,
+    /// Only documents matching this query are indexed, so that e.g. a unique
+    /// field may be missing from several documents.
+    pub partial_filter: Option<Document>,
+}
+
+impl IndexSpec {
+    pub fn new(name: &str, keys: Document, unique: bool) -> Self {
+        Self {
+            name: name.to_owned(),
+            keys,
+            unique,
+            partial_filter: None,
+        }
+    }
+
+    /// Limits the index to the documents matching `filter`. The SQLite
+    /// backend only supports `{"<field>": {"$exists": true}}` conditions.
+    pub fn partial_filter(mut self, filter: Document) -> Self {
+        self.partial_filter = Some(filter);
+        self
+    }
+}
+
+/// Document store used by `Entity`, `TransactionLog` and `ModelParams`.
+///
+/// Queries and updates are expressed as MongoDB-style documents so that the
+/// Mongo implementation can pass them through unchanged.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), DbError>;
+
+    /// Inserts `documents` without stopping at duplicates, like an unordered
+    /// bulk insert, and returns how many were inserted. Other errors abort
+    /// the rest of the batch.
+    async fn insert_many(
+        &self,
+        collection: &str,
+        documents: Vec<Document>,
+    ) -> Result<usize, DbError> {
+        let mut inserted = 0;
+        for document in documents {
+            match self.insert_one(collection, document).await {
+                Ok(()) => inserted += 1,
+                Err(DbError::DuplicateKey(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(inserted)
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError>;
+
+    /// Atomically applies `update` to the first document matching `query`
+    /// and returns the document as stored afterwards.
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError>;
+
+    /// Replaces the first document matching `query` with `document` as a
+    /// whole, inserting it when nothing matches and `upsert` is set.
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError>;
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError>;
+
+    /// Deletes every document matching `query` and returns how many there
+    /// were.
+    async fn delete_many(&self, collection: &str, query: Document) -> Result<usize, DbError> {
+        let mut deleted = 0;
+        loop {
+            match self.delete_one(collection, query.clone()).await {
+                Ok(()) => deleted += 1,
+                Err(e) if e.is_not_found() => return Ok(deleted),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError>;
+
+    async fn search(
+        &self,
+        collection: &str,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError>;
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError>;
+
+    /// Runs a MongoDB aggregation pipeline on the server. Stores that cannot
+    /// do this return `DbError::Unsupported`, and callers compute the result
+    /// in Rust instead.
+    async fn aggregate(
+        &self,
+        collection: &str,
+        _pipeline: Vec<Document>,
+    ) -> Result<Vec<Document>, DbError> {
+        Err(DbError::Unsupported(format!(
+            "aggregation on `{}`",
+            collection
+        )))
+    }
+}
+
+#[async_trait]
+impl<B: StorageBackend + ?Sized> StorageBackend for Arc<B> {
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), DbError> {
+        (**self).insert_one(collection, document).await
+    }
+
+    async fn insert_many(
+        &self,
+        collection: &str,
+        documents: Vec<Document>,
+    ) -> Result<usize, DbError> {
+        (**self).insert_many(collection, documents).await
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        (**self).update_one(collection, query, update, upsert).await
+    }
+
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        (**self)
+            .find_one_and_update(collection, query, update, upsert)
+            .await
+    }
+
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        (**self)
+            .replace_one(collection, query, document, upsert)
+            .await
+    }
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError> {
+        (**self).delete_one(collection, query).await
+    }
+
+    async fn delete_many(&self, collection: &str, query: Document) -> Result<usize, DbError> {
+        (**self).delete_many(collection, query).await
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError> {
+        (**self).delete_all(collection).await
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        (**self)
+            .search(collection, query, mode, limit, id, sort_key)
+            .await
+    }
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError> {
+        (**self).create_indexes(collection, indexes).await
+    }
+
+    async fn aggregate(
+        &self,
+        collection: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Vec<Document>, DbError> {
+        (**self).aggregate(collection, pipeline).await
+    }
+}
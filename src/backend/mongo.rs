@@ -0,0 +1,290 @@
+// backend/mongo.rs
+
+use async_trait::async_trait;
+use bson::to_document;
+use bson::Document;
+use futures::stream::TryStreamExt;
+use mongodb::bson::doc;
+use mongodb::options::*;
+use mongodb::Database;
+use mongodb::{Collection, IndexModel};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{validate_sort_key, IndexSpec, StorageBackend};
+use crate::DbError;
+use crate::SearchMode;
+
+async fn ensure_collection_exists(collection: &Collection<Document>) {
+    let doc = doc! { "_id": bson::oid::ObjectId::new() };
+    let _ = collection.insert_one(doc, None).await.ok();
+}
+
+async fn get_existing_indexes<T>(collection: &Collection<T>) -> Result<Vec<String>, DbError> {
+    let mut indexes = collection.list_indexes(None).await?;
+    let mut index_names = Vec::new();
+
+    while let Some(index) = indexes.try_next().await? {
+        let document: Document = to_document(&index)?;
+        if let Ok(name) = document.get_str("name") {
+            index_names.push(name.to_string());
+        }
+    }
+
+    log::debug!("Existing indexes: {:?}", index_names);
+    Ok(index_names)
+}
+
+#[async_trait]
+impl StorageBackend for Database {
+    async fn insert_one(&self, collection: &str, document: Document) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        collection.insert_one(document, None).await?;
+        Ok(())
+    }
+
+    async fn insert_many(
+        &self,
+        collection: &str,
+        documents: Vec<Document>,
+    ) -> Result<usize, DbError> {
+        use mongodb::error::ErrorKind;
+
+        if documents.is_empty() {
+            return Ok(0);
+        }
+        let num = documents.len();
+        let collection = self.collection::<Document>(collection);
+        let options = InsertManyOptions::builder().ordered(false).build();
+        match collection.insert_many(documents, options).await {
+            Ok(result) => Ok(result.inserted_ids.len()),
+            Err(e) => match e.kind.as_ref() {
+                ErrorKind::BulkWrite(failure)
+                    if failure.write_concern_error.is_none()
+                        && failure
+                            .write_errors
+                            .as_ref()
+                            .is_some_and(|errors| errors.iter().all(|e| e.code == 11000)) =>
+                {
+                    let failed = failure.write_errors.as_ref().map_or(0, Vec::len);
+                    Ok(num - failed)
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        HelperCollection::update(&collection, query, update, upsert).await
+    }
+
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        let collection = self.collection::<Document>(collection);
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(upsert)
+            .return_document(ReturnDocument::After)
+            .build();
+        Ok(collection
+            .find_one_and_update(query, update, options)
+            .await?)
+    }
+
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        let options = ReplaceOptions::builder().upsert(upsert).build();
+        collection.replace_one(query, document, options).await?;
+        Ok(())
+    }
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        HelperCollection::delete(&collection, query).await
+    }
+
+    async fn delete_many(&self, collection: &str, query: Document) -> Result<usize, DbError> {
+        let collection = self.collection::<Document>(collection);
+        let result = collection.delete_many(query, None).await?;
+        Ok(result.deleted_count as usize)
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        HelperCollection::delete_all(&collection).await
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        let collection = self.collection::<Document>(collection);
+        HelperCollection::search(&collection, query, mode, limit, id, sort_key).await
+    }
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError> {
+        let collection = self.collection::<Document>(collection);
+        ensure_collection_exists(&collection).await;
+        let existing_indexes = get_existing_indexes(&collection).await?;
+
+        for index in indexes {
+            if !existing_indexes.contains(&index.name) {
+                log::info!("Creating index `{}`...", index.name);
+                let options = IndexOptions::builder()
+                    .name(index.name.clone())
+                    .unique(index.unique.then_some(true))
+                    .partial_filter_expression(index.partial_filter)
+                    .build();
+                let index_model = IndexModel::builder()
+                    .keys(index.keys)
+                    .options(options)
+                    .build();
+                collection.create_index(index_model, None).await?;
+                log::info!("Index `{}` has been created successfully!", index.name);
+            } else {
+                log::debug!("Index `{}` already exists, skipping.", index.name);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn aggregate(
+        &self,
+        collection: &str,
+        pipeline: Vec<Document>,
+    ) -> Result<Vec<Document>, DbError> {
+        let collection = self.collection::<Document>(collection);
+        let options = AggregateOptions::builder().allow_disk_use(true).build();
+        let cursor = collection.aggregate(pipeline, options).await?;
+        Ok(cursor.try_collect().await?)
+    }
+}
+
+#[async_trait]
+pub trait HelperCollection<T> {
+    async fn update(&self, query: Document, update: Document, upsert: bool) -> Result<(), DbError>;
+    async fn delete(&self, query: Document) -> Result<(), DbError>;
+    async fn delete_all(&self) -> Result<(), DbError>;
+    async fn search(
+        &self,
+        query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<T>, DbError>;
+}
+
+#[async_trait]
+impl<T> HelperCollection<T> for Collection<T>
+where
+    T: DeserializeOwned + Unpin + Send + Sync + Serialize + std::fmt::Debug,
+{
+    async fn update(&self, query: Document, update: Document, upsert: bool) -> Result<(), DbError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(upsert)
+            .return_document(ReturnDocument::After)
+            .build();
+        let _ = self.find_one_and_update(query, update, options).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, query: Document) -> Result<(), DbError> {
+        let result = self.delete_one(query, None).await?;
+        if result.deleted_count == 1 {
+            Ok(())
+        } else {
+            Err(DbError::NotFound("Item not found".to_string()))
+        }
+    }
+
+    async fn delete_all(&self) -> Result<(), DbError> {
+        let options = DropCollectionOptions::builder().build();
+        self.drop(options).await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        mut query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<T>, DbError> {
+        let mut items: Vec<T> = vec![];
+
+        validate_sort_key(sort_key)?;
+
+        let find_options = match mode {
+            SearchMode::Ascending => {
+                let builder = FindOptions::builder()
+                    .allow_disk_use(Some(true))
+                    .sort(doc! { sort_key: 1, "id": 1 });
+
+                if let Some(limit_value) = limit {
+                    builder.limit(limit_value as i64).build()
+                } else {
+                    builder.build()
+                }
+            }
+            SearchMode::Descending => {
+                let builder = FindOptions::builder()
+                    .allow_disk_use(Some(true))
+                    .sort(doc! { sort_key: -1, "id": -1 });
+
+                if let Some(limit_value) = limit {
+                    builder.limit(limit_value as i64).build()
+                } else {
+                    builder.build()
+                }
+            }
+            SearchMode::ById => {
+                if let Some(id_value) = id {
+                    query.insert("id", id_value);
+                } else {
+                    return Err(DbError::InvalidQuery("ID not provided".to_string()));
+                }
+                FindOptions::builder().allow_disk_use(Some(true)).build()
+            }
+        };
+
+        let mut cursor = self.find(query, find_options).await?;
+        while let Some(item) = cursor.try_next().await? {
+            items.push(item);
+        }
+
+        if items.is_empty() {
+            Err(DbError::NotFound("Item not found".to_string()))
+        } else {
+            Ok(items)
+        }
+    }
+}
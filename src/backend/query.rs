@@ -0,0 +1,336 @@
+// backend/query.rs
+//
+// Evaluation of the subset of MongoDB query and update documents used by this
+// crate, for backends that do not speak the Mongo wire protocol.
+
+use bson::{Bson, Document};
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+
+use crate::decimal;
+use crate::DbError;
+
+pub(crate) fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut current = document;
+    let mut parts = path.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let value = current.get(part)?;
+        if parts.peek().is_none() {
+            return Some(value);
+        }
+        match value {
+            Bson::Document(inner) => current = inner,
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub(crate) fn set_path(document: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if !matches!(document.get(head), Some(Bson::Document(_))) {
+                document.insert(head, Document::new());
+            }
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                set_path(inner, rest, value);
+            }
+        }
+        None => {
+            document.insert(path, value);
+        }
+    }
+}
+
+fn remove_path(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(Bson::Document(inner)) = document.get_mut(head) {
+                remove_path(inner, rest);
+            }
+        }
+        None => {
+            document.remove(path);
+        }
+    }
+}
+
+fn as_f64(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(v) => Some(*v as f64),
+        Bson::Int64(v) => Some(*v as f64),
+        Bson::Double(v) => Some(*v),
+        Bson::Decimal128(v) => v.to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Both values as decimals if either is a Decimal128, so that they are
+/// compared and added without rounding.
+fn as_decimals(a: &Bson, b: &Bson) -> Option<(Decimal, Decimal)> {
+    if !matches!(a, Bson::Decimal128(_)) && !matches!(b, Bson::Decimal128(_)) {
+        return None;
+    }
+    Some((decimal::from_number(a)?, decimal::from_number(b)?))
+}
+
+/// Ordering between two BSON values. Numbers of different widths compare by
+/// value; values of unrelated types are ordered by type like MongoDB does.
+pub(crate) fn compare(a: &Bson, b: &Bson) -> Ordering {
+    if let Some((a, b)) = as_decimals(a, b) {
+        return a.cmp(&b);
+    }
+    if let (Some(a), Some(b)) = (as_f64(a), as_f64(b)) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => a.cmp(b),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.cmp(b),
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        _ => 11,
+    }
+}
+
+pub(crate) fn values_equal(a: &Bson, b: &Bson) -> bool {
+    if let Some((a, b)) = as_decimals(a, b) {
+        return a == b;
+    }
+    match (as_f64(a), as_f64(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub(crate) fn is_operator_document(value: &Bson) -> Option<&Document> {
+    match value {
+        Bson::Document(inner) if inner.keys().next().is_some_and(|k| k.starts_with('$')) => {
+            Some(inner)
+        }
+        _ => None,
+    }
+}
+
+fn match_operators(value: Option<&Bson>, operators: &Document) -> Result<bool, DbError> {
+    let null = Bson::Null;
+    let actual = value.unwrap_or(&null);
+    for (operator, expected) in operators {
+        // Like MongoDB, ranges only match values of the same type, so that
+        // e.g. a null timestamp is not before every time.
+        let comparable = value.is_some() && type_rank(actual) == type_rank(expected);
+        let ok = match operator.as_str() {
+            "$eq" => values_equal(actual, expected),
+            "$ne" => !values_equal(actual, expected),
+            "$gt" => comparable && compare(actual, expected) == Ordering::Greater,
+            "$gte" => comparable && compare(actual, expected) != Ordering::Less,
+            "$lt" => comparable && compare(actual, expected) == Ordering::Less,
+            "$lte" => comparable && compare(actual, expected) != Ordering::Greater,
+            "$in" | "$nin" => {
+                let Bson::Array(candidates) = expected else {
+                    return Err(DbError::InvalidQuery(format!(
+                        "{} requires an array",
+                        operator
+                    )));
+                };
+                let found = candidates.iter().any(|c| values_equal(actual, c));
+                if operator == "$in" {
+                    found
+                } else {
+                    !found
+                }
+            }
+            "$exists" => {
+                let exists = value.is_some();
+                match expected {
+                    Bson::Boolean(flag) => exists == *flag,
+                    other => exists == as_f64(other).is_some_and(|v| v != 0.0),
+                }
+            }
+            _ => {
+                return Err(DbError::InvalidQuery(format!(
+                    "Unsupported query operator: {}",
+                    operator
+                )))
+            }
+        };
+        if !ok {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn match_clauses(document: &Document, clauses: &Bson, any: bool) -> Result<bool, DbError> {
+    let Bson::Array(clauses) = clauses else {
+        return Err(DbError::InvalidQuery(
+            "$or/$and requires an array".to_string(),
+        ));
+    };
+    for clause in clauses {
+        let Bson::Document(clause) = clause else {
+            return Err(DbError::InvalidQuery(
+                "$or/$and clauses must be documents".to_string(),
+            ));
+        };
+        if matches(document, clause)? == any {
+            return Ok(any);
+        }
+    }
+    Ok(!any)
+}
+
+/// Returns true when `document` satisfies `query`.
+pub(crate) fn matches(document: &Document, query: &Document) -> Result<bool, DbError> {
+    for (key, condition) in query {
+        let ok = match key.as_str() {
+            "$or" => match_clauses(document, condition, true)?,
+            "$and" => match_clauses(document, condition, false)?,
+            _ => {
+                let value = get_path(document, key);
+                match is_operator_document(condition) {
+                    Some(operators) => match_operators(value, operators)?,
+                    None => match value {
+                        Some(value) => values_equal(value, condition),
+                        None => matches!(condition, Bson::Null),
+                    },
+                }
+            }
+        };
+        if !ok {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Builds the document inserted by an upsert from the equality clauses of
+/// its query.
+pub(crate) fn upsert_seed(query: &Document) -> Document {
+    let mut seed = Document::new();
+    for (key, condition) in query {
+        if key.starts_with('$') || is_operator_document(condition).is_some() {
+            continue;
+        }
+        set_path(&mut seed, key, condition.clone());
+    }
+    seed
+}
+
+fn add_numbers(a: &Bson, b: &Bson) -> Option<Bson> {
+    if let Some((a, b)) = as_decimals(a, b) {
+        return decimal::to_bson(a.checked_add(b)?).ok();
+    }
+    match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Some(
+            a.checked_add(*b)
+                .map(Bson::Int32)
+                .unwrap_or(Bson::Int64(*a as i64 + *b as i64)),
+        ),
+        (Bson::Int32(a), Bson::Int64(b)) => Some(Bson::Int64(*a as i64 + b)),
+        (Bson::Int64(a), Bson::Int32(b)) => Some(Bson::Int64(a + *b as i64)),
+        (Bson::Int64(a), Bson::Int64(b)) => Some(Bson::Int64(a + b)),
+        _ => Some(Bson::Double(as_f64(a)? + as_f64(b)?)),
+    }
+}
+
+/// Applies a MongoDB update document in place. Documents without update
+/// operators replace the stored fields, keeping `_id`.
+pub(crate) fn apply_update(
+    document: &mut Document,
+    update: &Document,
+    inserting: bool,
+) -> Result<(), DbError> {
+    if !update.keys().any(|k| k.starts_with('$')) {
+        let id = document.get("_id").cloned();
+        *document = update.clone();
+        if let Some(id) = id {
+            document.insert("_id", id);
+        }
+        return Ok(());
+    }
+
+    for (operator, fields) in update {
+        let Bson::Document(fields) = fields else {
+            return Err(DbError::InvalidQuery(format!(
+                "{} requires a document",
+                operator
+            )));
+        };
+        for (path, value) in fields {
+            match operator.as_str() {
+                "$set" => set_path(document, path, value.clone()),
+                "$setOnInsert" => {
+                    if inserting {
+                        set_path(document, path, value.clone())
+                    }
+                }
+                "$unset" => remove_path(document, path),
+                "$inc" => {
+                    let current = get_path(document, path).cloned().unwrap_or(Bson::Int32(0));
+                    let sum = add_numbers(&current, value).ok_or_else(|| {
+                        DbError::InvalidQuery(format!("Cannot apply $inc to field {}", path))
+                    })?;
+                    set_path(document, path, sum);
+                }
+                "$max" | "$min" => {
+                    let wanted = if operator == "$max" {
+                        Ordering::Greater
+                    } else {
+                        Ordering::Less
+                    };
+                    let replace = match get_path(document, path) {
+                        Some(current) => compare(value, current) == wanted,
+                        None => true,
+                    };
+                    if replace {
+                        set_path(document, path, value.clone());
+                    }
+                }
+                "$push" => {
+                    let mut items = match get_path(document, path) {
+                        Some(Bson::Array(items)) => items.clone(),
+                        Some(Bson::Null) | None => vec![],
+                        Some(_) => {
+                            return Err(DbError::InvalidQuery(format!(
+                                "Cannot apply $push to non-array field {}",
+                                path
+                            )))
+                        }
+                    };
+                    match value {
+                        Bson::Document(each) if each.contains_key("$each") => {
+                            if let Ok(values) = each.get_array("$each") {
+                                items.extend(values.iter().cloned());
+                            }
+                        }
+                        value => items.push(value.clone()),
+                    }
+                    set_path(document, path, Bson::Array(items));
+                }
+                _ => {
+                    return Err(DbError::InvalidQuery(format!(
+                        "Unsupported update operator: {}",
+                        operator
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
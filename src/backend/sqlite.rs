@@ -0,0 +1,574 @@
+// backend/sqlite.rs
+
+use async_trait::async_trait;
+use bson::{Bson, Document};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection, OptionalExtension};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::query::{apply_update, matches, upsert_seed};
+use super::{
+    document_from_json, document_to_json, validate_replacement, validate_sort_key, IndexSpec,
+    StorageBackend,
+};
+use crate::DbError;
+use crate::SearchMode;
+
+// Document paths stored as real columns next to the JSON document. Anything
+// else (DebugLog, FundConfig, ...) is only available inside `document`.
+const COLUMNS: &[(&str, &str)] = &[
+    ("id", "id"),
+    ("key", "key"),
+    ("name", "name"),
+    ("fund_name", "fund_name"),
+    ("token_name", "token_name"),
+    ("state", "state"),
+    ("position_type", "position_type"),
+    ("open_timestamp", "open_timestamp"),
+    ("close_timestamp", "close_timestamp"),
+    ("price_point.timestamp", "price_timestamp"),
+    ("timestamp", "timestamp"),
+    ("instance_key", "instance_key"),
+];
+
+fn column_for(path: &str) -> Option<&'static str> {
+    COLUMNS
+        .iter()
+        .find(|(p, _)| *p == path)
+        .map(|(_, column)| *column)
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn to_sql_value(value: Option<&Bson>) -> Value {
+    match value {
+        Some(Bson::Int32(v)) => Value::Integer(*v as i64),
+        Some(Bson::Int64(v)) => Value::Integer(*v),
+        Some(Bson::Double(v)) => Value::Real(*v),
+        Some(Bson::Boolean(v)) => Value::Integer(*v as i64),
+        Some(Bson::String(v)) => Value::Text(v.clone()),
+        _ => Value::Null,
+    }
+}
+
+/// Translates the filter of a partial index, which may only require
+/// columns to be present.
+fn partial_index_clause(filter: &Document) -> Option<String> {
+    let mut clauses = vec![];
+    for (path, condition) in filter {
+        let column = column_for(path)?;
+        match condition {
+            Bson::Document(operators)
+                if operators.len() == 1 && operators.get_bool("$exists") == Ok(true) =>
+            {
+                clauses.push(format!("{} IS NOT NULL", quote(column)));
+            }
+            _ => return None,
+        }
+    }
+    Some(format!(" WHERE {}", clauses.join(" AND ")))
+}
+
+/// Translates the column-backed part of `query` into a WHERE clause.
+/// Returns false as the last element when some conditions could not be
+/// expressed in SQL and must be checked on the decoded documents.
+fn where_clause(query: &Document) -> (String, Vec<Value>, bool) {
+    let (clauses, params, complete) = conditions(query);
+    let sql = if clauses.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", clauses.join(" AND "))
+    };
+    (sql, params, complete)
+}
+
+fn conditions(query: &Document) -> (Vec<String>, Vec<Value>, bool) {
+    let mut clauses = vec![];
+    let mut params = vec![];
+    let mut complete = true;
+
+    for (path, condition) in query {
+        if path == "$and" || path == "$or" {
+            let Bson::Array(branches) = condition else {
+                complete = false;
+                continue;
+            };
+            // A branch that is only partly translated still selects a
+            // superset of its documents, so the clause stays usable as a
+            // pre-filter; an untranslatable `$or` branch would match
+            // everything, though.
+            let mut parts = vec![];
+            let mut part_params = vec![];
+            let mut usable = true;
+            for branch in branches {
+                let Bson::Document(branch) = branch else {
+                    usable = false;
+                    break;
+                };
+                let (branch_clauses, branch_params, branch_complete) = conditions(branch);
+                complete &= branch_complete;
+                if branch_clauses.is_empty() {
+                    if path == "$or" {
+                        usable = false;
+                        break;
+                    }
+                    continue;
+                }
+                parts.push(format!("({})", branch_clauses.join(" AND ")));
+                part_params.extend(branch_params);
+            }
+            if !usable {
+                complete = false;
+            } else if !parts.is_empty() {
+                let separator = if path == "$or" { " OR " } else { " AND " };
+                clauses.push(format!("({})", parts.join(separator)));
+                params.extend(part_params);
+            }
+            continue;
+        }
+
+        let Some(column) = column_for(path) else {
+            complete = false;
+            continue;
+        };
+        let column = quote(column);
+        match condition {
+            Bson::Document(operators) if operators.keys().any(|k| k.starts_with('$')) => {
+                for (operator, value) in operators {
+                    let sql_operator = match operator.as_str() {
+                        "$eq" => "=",
+                        "$gt" => ">",
+                        "$gte" => ">=",
+                        "$lt" => "<",
+                        "$lte" => "<=",
+                        "$in" => {
+                            if let Bson::Array(values) = value {
+                                let placeholders = vec!["?"; values.len()].join(", ");
+                                clauses.push(format!("{} IN ({})", column, placeholders));
+                                params.extend(values.iter().map(|v| to_sql_value(Some(v))));
+                            } else {
+                                complete = false;
+                            }
+                            continue;
+                        }
+                        _ => {
+                            complete = false;
+                            continue;
+                        }
+                    };
+                    clauses.push(format!("{} {} ?", column, sql_operator));
+                    params.push(to_sql_value(Some(value)));
+                }
+            }
+            Bson::Null => clauses.push(format!("{} IS NULL", column)),
+            value => {
+                clauses.push(format!("{} = ?", column));
+                params.push(to_sql_value(Some(value)));
+            }
+        }
+    }
+
+    (clauses, params, complete)
+}
+
+struct SqliteInner {
+    connection: Connection,
+    tables: HashSet<String>,
+}
+
+impl SqliteInner {
+    fn ensure_table(&mut self, collection: &str) -> Result<(), DbError> {
+        if self.tables.contains(collection) {
+            return Ok(());
+        }
+
+        let table = quote(collection);
+        self.connection.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (document TEXT NOT NULL)",
+                table
+            ),
+            [],
+        )?;
+
+        let mut existing = HashSet::new();
+        {
+            let mut statement = self
+                .connection
+                .prepare(&format!("PRAGMA table_info({})", table))?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                existing.insert(row.get::<_, String>(1)?);
+            }
+        }
+        for (path, column) in COLUMNS {
+            if !existing.contains(*column) {
+                self.connection.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN {}", table, quote(column)),
+                    [],
+                )?;
+                // Rows written before the column existed.
+                self.connection.execute(
+                    &format!(
+                        "UPDATE {} SET {} = json_extract(document, ?1)",
+                        table,
+                        quote(column)
+                    ),
+                    [format!("$.{}", path)],
+                )?;
+            }
+        }
+
+        self.tables.insert(collection.to_owned());
+        Ok(())
+    }
+
+    fn write_row(
+        &self,
+        collection: &str,
+        rowid: Option<i64>,
+        document: &Document,
+    ) -> Result<(), DbError> {
+        let table = quote(collection);
+        let mut params: Vec<Value> = COLUMNS
+            .iter()
+            .map(|(path, _)| to_sql_value(super::query::get_path(document, path)))
+            .collect();
+        params.push(Value::Text(document_to_json(document)));
+
+        let sql = match rowid {
+            Some(rowid) => {
+                let assignments: Vec<String> = COLUMNS
+                    .iter()
+                    .map(|(_, column)| format!("{} = ?", quote(column)))
+                    .collect();
+                params.push(Value::Integer(rowid));
+                format!(
+                    "UPDATE {} SET {}, document = ? WHERE rowid = ?",
+                    table,
+                    assignments.join(", ")
+                )
+            }
+            None => {
+                let columns: Vec<String> = COLUMNS.iter().map(|(_, c)| quote(c)).collect();
+                let placeholders = vec!["?"; COLUMNS.len() + 1].join(", ");
+                format!(
+                    "INSERT INTO {} ({}, document) VALUES ({})",
+                    table,
+                    columns.join(", "),
+                    placeholders
+                )
+            }
+        };
+        self.connection.execute(&sql, params_from_iter(params))?;
+        Ok(())
+    }
+
+    fn update_in_transaction(
+        &self,
+        collection: &str,
+        query: &Document,
+        update: &Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        match self.first_match(collection, query)? {
+            Some((rowid, mut document)) => {
+                apply_update(&mut document, update, false)?;
+                self.write_row(collection, Some(rowid), &document)?;
+                Ok(Some(document))
+            }
+            None if upsert => {
+                let mut document = upsert_seed(query);
+                apply_update(&mut document, update, true)?;
+                if !document.contains_key("_id") {
+                    document.insert("_id", bson::oid::ObjectId::new());
+                }
+                self.write_row(collection, None, &document)?;
+                Ok(Some(document))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn first_match(
+        &self,
+        collection: &str,
+        query: &Document,
+    ) -> Result<Option<(i64, Document)>, DbError> {
+        let (where_sql, params, _) = where_clause(query);
+        let sql = format!(
+            "SELECT rowid, document FROM {}{} ORDER BY rowid",
+            quote(collection),
+            where_sql
+        );
+        let mut statement = self.connection.prepare(&sql)?;
+        let mut rows = statement.query(params_from_iter(params))?;
+        while let Some(row) = rows.next()? {
+            let document = document_from_json(&row.get::<_, String>(1)?)?;
+            if matches(&document, query)? {
+                return Ok(Some((row.get(0)?, document)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Embedded single-file store. Scalar fields listed in `COLUMNS` are indexed
+/// columns; the whole document is kept as extended JSON.
+pub struct SqliteBackend {
+    inner: Mutex<SqliteInner>,
+}
+
+impl SqliteBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DbError> {
+        Ok(Self::from_connection(Connection::open(path)?))
+    }
+
+    pub fn open_in_memory() -> Result<Self, DbError> {
+        Ok(Self::from_connection(Connection::open_in_memory()?))
+    }
+
+    fn from_connection(connection: Connection) -> Self {
+        Self {
+            inner: Mutex::new(SqliteInner {
+                connection,
+                tables: HashSet::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteBackend {
+    async fn insert_one(&self, collection: &str, mut document: Document) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+        if !document.contains_key("_id") {
+            document.insert("_id", bson::oid::ObjectId::new());
+        }
+        inner.write_row(collection, None, &document)
+    }
+
+    async fn update_one(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        self.find_one_and_update(collection, query, update, upsert)
+            .await?;
+        Ok(())
+    }
+
+    async fn find_one_and_update(
+        &self,
+        collection: &str,
+        query: Document,
+        update: Document,
+        upsert: bool,
+    ) -> Result<Option<Document>, DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+
+        // Take the write lock up front so that other processes sharing the
+        // file cannot interleave between the read and the write.
+        inner.connection.execute_batch("BEGIN IMMEDIATE")?;
+        let result = inner.update_in_transaction(collection, &query, &update, upsert);
+        match result {
+            Ok(_) => inner.connection.execute_batch("COMMIT")?,
+            Err(_) => inner.connection.execute_batch("ROLLBACK")?,
+        }
+        result
+    }
+
+    async fn replace_one(
+        &self,
+        collection: &str,
+        query: Document,
+        document: Document,
+        upsert: bool,
+    ) -> Result<(), DbError> {
+        validate_replacement(&document)?;
+        self.update_one(collection, query, document, upsert).await
+    }
+
+    async fn delete_one(&self, collection: &str, query: Document) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+        match inner.first_match(collection, &query)? {
+            Some((rowid, _)) => {
+                inner.connection.execute(
+                    &format!("DELETE FROM {} WHERE rowid = ?", quote(collection)),
+                    [rowid],
+                )?;
+                Ok(())
+            }
+            None => Err(DbError::NotFound("Item not found".to_string())),
+        }
+    }
+
+    async fn delete_all(&self, collection: &str) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+        inner
+            .connection
+            .execute(&format!("DELETE FROM {}", quote(collection)), [])?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        collection: &str,
+        mut query: Document,
+        mode: SearchMode,
+        limit: Option<u32>,
+        id: Option<u32>,
+        sort_key: &str,
+    ) -> Result<Vec<Document>, DbError> {
+        validate_sort_key(sort_key)?;
+
+        let order = match mode {
+            SearchMode::Ascending => Some("ASC"),
+            SearchMode::Descending => Some("DESC"),
+            SearchMode::ById => {
+                if let Some(id_value) = id {
+                    query.insert("id", id_value);
+                } else {
+                    return Err(DbError::InvalidQuery("ID not provided".to_string()));
+                }
+                None
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+
+        let (where_sql, mut params, complete) = where_clause(&query);
+        let mut sql = format!("SELECT document FROM {}{}", quote(collection), where_sql);
+        match order {
+            Some(order) => {
+                let column = column_for(sort_key).ok_or_else(|| {
+                    DbError::InvalidQuery(format!("Invalid sort key: {}", sort_key))
+                })?;
+                sql.push_str(&format!(
+                    " ORDER BY {} {}, \"id\" {}, rowid",
+                    quote(column),
+                    order,
+                    order
+                ));
+            }
+            None => sql.push_str(" ORDER BY rowid"),
+        }
+        let sql_limit = if complete && order.is_some() {
+            limit
+        } else {
+            None
+        };
+        if let Some(limit_value) = sql_limit {
+            sql.push_str(" LIMIT ?");
+            params.push(Value::Integer(limit_value as i64));
+        }
+
+        let mut items = vec![];
+        {
+            let mut statement = inner.connection.prepare(&sql)?;
+            let mut rows = statement.query(params_from_iter(params))?;
+            while let Some(row) = rows.next()? {
+                let document = document_from_json(&row.get::<_, String>(0)?)?;
+                if matches(&document, &query)? {
+                    items.push(document);
+                }
+            }
+        }
+        if order.is_some() {
+            if let Some(limit_value) = limit {
+                items.truncate(limit_value as usize);
+            }
+        }
+
+        if items.is_empty() {
+            Err(DbError::NotFound("Item not found".to_string()))
+        } else {
+            Ok(items)
+        }
+    }
+
+    async fn create_indexes(
+        &self,
+        collection: &str,
+        indexes: Vec<IndexSpec>,
+    ) -> Result<(), DbError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.ensure_table(collection)?;
+
+        for index in indexes {
+            let mut columns = vec![];
+            for (path, direction) in &index.keys {
+                let Some(column) = column_for(path) else {
+                    log::debug!(
+                        "Index `{}` is not backed by a column, skipping.",
+                        index.name
+                    );
+                    columns.clear();
+                    break;
+                };
+                let direction = if direction.as_i32() == Some(-1) {
+                    "DESC"
+                } else {
+                    "ASC"
+                };
+                columns.push(format!("{} {}", quote(column), direction));
+            }
+            if columns.is_empty() {
+                continue;
+            }
+            let partial = match &index.partial_filter {
+                Some(filter) => match partial_index_clause(filter) {
+                    Some(clause) => clause,
+                    None => {
+                        log::debug!(
+                            "Filter of index `{}` is not backed by columns, skipping.",
+                            index.name
+                        );
+                        continue;
+                    }
+                },
+                None => String::new(),
+            };
+
+            let index_name = quote(&format!("{}_{}", collection, index.name));
+            let exists: Option<String> = inner
+                .connection
+                .query_row(
+                    "SELECT name FROM sqlite_master WHERE type = 'index' AND name = ?",
+                    [format!("{}_{}", collection, index.name)],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            if exists.is_some() {
+                log::debug!("Index `{}` already exists, skipping.", index.name);
+                continue;
+            }
+
+            log::info!("Creating index `{}`...", index.name);
+            inner.connection.execute(
+                &format!(
+                    "CREATE {}INDEX {} ON {} ({}){}",
+                    if index.unique { "UNIQUE " } else { "" },
+                    index_name,
+                    quote(collection),
+                    columns.join(", "),
+                    partial
+                ),
+                [],
+            )?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+// export_dataset.rs
+//
+// Exports closed positions as a training dataset.
+//
+//   export_dataset --db <name> [--uri <mongodb uri>] [--from <ts>] [--to <ts>]
+//       [--time open|close] [--fund <name>] [--token <name>]
+//       [--schema <name>@<version>] [--format csv|bincode] [--output <path>]
+//
+// The URI defaults to $MONGODB_URI. CSV goes to stdout unless `--output` is
+// given; bincode writes a `Dataset` and needs `--output`.
+
+use debot_db::{
+    build_dataset, write_dataset_csv, DbError, FeatureRegistry, FeatureSchema, PositionFilter,
+    PositionTime, TransactionLog,
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: export_dataset --db <name> [--uri <mongodb uri>] [--from <ts>] [--to <ts>] \
+         [--time open|close] [--fund <name>] [--token <name>] [--schema <name>@<version>] \
+         [--format csv|bincode] [--output <path>]"
+    );
+    std::process::exit(2);
+}
+
+fn parse_args() -> HashMap<String, String> {
+    let mut args = HashMap::new();
+    let mut iter = std::env::args().skip(1);
+    while let Some(key) = iter.next() {
+        let Some(key) = key.strip_prefix("--") else {
+            usage();
+        };
+        let Some(value) = iter.next() else {
+            usage();
+        };
+        args.insert(key.to_owned(), value);
+    }
+    args
+}
+
+fn parse_timestamp(args: &HashMap<String, String>, key: &str) -> Option<i64> {
+    args.get(key)
+        .map(|value| value.parse().unwrap_or_else(|_| usage()))
+}
+
+async fn schema(
+    db: &dyn debot_db::StorageBackend,
+    name: Option<&String>,
+) -> Result<FeatureSchema, DbError> {
+    let Some(name) = name else {
+        return Ok(FeatureSchema::debug_log());
+    };
+    let (name, version) = name.split_once('@').unwrap_or_else(|| usage());
+    let version = version.parse().unwrap_or_else(|_| usage());
+    FeatureRegistry::load(db)
+        .await?
+        .get(name, version)
+        .cloned()
+        .ok_or_else(|| DbError::NotFound(format!("Feature schema {}@{}", name, version)))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), DbError> {
+    env_logger::init();
+
+    let args = parse_args();
+    let db_name = args.get("db").unwrap_or_else(|| usage());
+    let uri = match args.get("uri") {
+        Some(uri) => uri.clone(),
+        None => std::env::var("MONGODB_URI").unwrap_or_else(|_| usage()),
+    };
+
+    let (from, to) = (parse_timestamp(&args, "from"), parse_timestamp(&args, "to"));
+    let mut filter = PositionFilter {
+        fund_name: args.get("fund").cloned(),
+        token_name: args.get("token").cloned(),
+        ..Default::default()
+    };
+    let time = match args.get("time").map(String::as_str) {
+        None | Some("open") => PositionTime::Open,
+        Some("close") => PositionTime::Close,
+        Some(_) => usage(),
+    };
+    match time {
+        PositionTime::Open => (filter.open_from, filter.open_to) = (from, to),
+        PositionTime::Close => (filter.close_from, filter.close_to) = (from, to),
+    }
+    log::info!("export_dataset: {} by {}", db_name, time.key());
+
+    // Only reads, so the database is not initialized like a TransactionLog.
+    let db = TransactionLog::open_db(&uri, db_name)
+        .await
+        .ok_or_else(|| DbError::Connection("no db".to_string()))?;
+    let schema = schema(db.as_ref(), args.get("schema")).await?;
+
+    let num = match args.get("format").map(String::as_str) {
+        None | Some("csv") => match args.get("output") {
+            Some(path) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                let num = write_dataset_csv(db.as_ref(), &schema, &filter, &mut writer).await?;
+                writer.flush()?;
+                num
+            }
+            None => {
+                let mut writer = std::io::stdout().lock();
+                write_dataset_csv(db.as_ref(), &schema, &filter, &mut writer).await?
+            }
+        },
+        Some("bincode") => {
+            let path = args.get("output").unwrap_or_else(|| usage());
+            let dataset = build_dataset(db.as_ref(), &schema, &filter).await?;
+            std::fs::write(path, dataset.to_bytes()?)?;
+            dataset.len()
+        }
+        Some(_) => usage(),
+    };
+    eprintln!("exported {} rows", num);
+    Ok(())
+}
@@ -0,0 +1,303 @@
+// candle.rs
+
+use bson::{doc, Bson, Document};
+use futures::stream::TryStreamExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::search_stream;
+use crate::DbError;
+use crate::Entity;
+use crate::PriceLog;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const CANDLE_PAGE_SIZE: u32 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CandleInterval {
+    OneMinute,
+    FiveMinutes,
+    OneHour,
+    OneDay,
+}
+
+impl CandleInterval {
+    pub fn seconds(&self) -> i64 {
+        match self {
+            CandleInterval::OneMinute => 60,
+            CandleInterval::FiveMinutes => 5 * 60,
+            CandleInterval::OneHour => 60 * 60,
+            CandleInterval::OneDay => 24 * 60 * 60,
+        }
+    }
+
+    /// Start of the candle that contains `timestamp`.
+    pub fn start_of(&self, timestamp: i64) -> i64 {
+        timestamp - timestamp.rem_euclid(self.seconds())
+    }
+}
+
+impl FromStr for CandleInterval {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(CandleInterval::OneMinute),
+            "5m" => Ok(CandleInterval::FiveMinutes),
+            "1h" => Ok(CandleInterval::OneHour),
+            "1d" => Ok(CandleInterval::OneDay),
+            _ => Err(DbError::InvalidQuery(format!(
+                "Invalid candle interval: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// OHLCV candle of one price source (`name`) and token.
+///
+/// `volume` and `num_trades` are sums over the ticks; ticks without them
+/// count as zero. `funding_rate`, `open_interest` and `oracle_price` are the
+/// last values reported within the candle.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Candle {
+    pub name: String,
+    pub token_name: String,
+    pub start: i64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub num_trades: u64,
+    pub vwap: Option<Decimal>,
+    pub funding_rate: Option<Decimal>,
+    pub open_interest: Option<Decimal>,
+    pub oracle_price: Option<Decimal>,
+}
+
+impl Candle {
+    fn new(price_log: &PriceLog, interval: CandleInterval) -> Self {
+        let price = price_log.price_point.price;
+        Self {
+            name: price_log.name.clone(),
+            token_name: price_log.token_name.clone(),
+            start: interval.start_of(price_log.price_point.timestamp),
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct CandleBuilder {
+    candle: Candle,
+    price_volume: Decimal,
+}
+
+impl CandleBuilder {
+    fn push(&mut self, price_log: &PriceLog) {
+        let point = &price_log.price_point;
+        let candle = &mut self.candle;
+        candle.high = candle.high.max(point.price);
+        candle.low = candle.low.min(point.price);
+        candle.close = point.price;
+        if let Some(volume) = point.volume {
+            candle.volume += volume;
+            self.price_volume += point.price * volume;
+        }
+        candle.num_trades += point.num_trades.unwrap_or(0);
+        candle.funding_rate = point.funding_rate.or(candle.funding_rate);
+        candle.open_interest = point.open_interest.or(candle.open_interest);
+        candle.oracle_price = point.oracle_price.or(candle.oracle_price);
+    }
+
+    fn build(mut self) -> Candle {
+        if !self.candle.volume.is_zero() {
+            self.candle.vwap = Some(self.price_volume / self.candle.volume);
+        }
+        self.candle
+    }
+}
+
+type CandleBuilders = BTreeMap<(String, String, i64), CandleBuilder>;
+
+fn add_price(builders: &mut CandleBuilders, price_log: &PriceLog, interval: CandleInterval) {
+    let key = (
+        price_log.name.clone(),
+        price_log.token_name.clone(),
+        interval.start_of(price_log.price_point.timestamp),
+    );
+    builders
+        .entry(key)
+        .or_insert_with(|| CandleBuilder {
+            candle: Candle::new(price_log, interval),
+            ..Default::default()
+        })
+        .push(price_log);
+}
+
+/// Builds candles from prices in ascending order of timestamp. The result is
+/// ordered by name, token and start.
+pub fn aggregate_candles<I>(prices: I, interval: CandleInterval) -> Vec<Candle>
+where
+    I: IntoIterator<Item = PriceLog>,
+{
+    let mut builders = CandleBuilders::new();
+    for price_log in prices {
+        add_price(&mut builders, &price_log, interval);
+    }
+    builders.into_values().map(CandleBuilder::build).collect()
+}
+
+fn price_filter(from: i64, to: i64, name: Option<&str>, token_name: Option<&str>) -> Document {
+    let mut filter = doc! { "price_point.timestamp": { "$gte": from, "$lt": to } };
+    if let Some(name) = name {
+        filter.insert("name", name);
+    }
+    if let Some(token_name) = token_name {
+        filter.insert("token_name", token_name);
+    }
+    filter
+}
+
+fn candle_pipeline(interval: CandleInterval, filter: Document) -> Vec<Document> {
+    // Decimals are stored as strings, so they are converted before use.
+    let decimal = |path: &str| doc! { "$toDecimal": format!("$price_point.{}", path) };
+    // The latest non-null value: documents compare field by field, and
+    // `$max` ignores nulls.
+    let latest = |path: &str| {
+        let field = format!("$price_point.{}", path);
+        doc! { "$max": { "$cond": [
+            { "$eq": [{ "$ifNull": [&field, Bson::Null] }, Bson::Null] },
+            Bson::Null,
+            { "t": "$price_point.timestamp", "id": "$id", "v": &field },
+        ]}}
+    };
+    let interval = interval.seconds();
+
+    vec![
+        doc! { "$match": filter },
+        doc! { "$sort": { "price_point.timestamp": 1, "id": 1 } },
+        doc! { "$addFields": {
+            "start": { "$subtract": [
+                "$price_point.timestamp",
+                { "$mod": ["$price_point.timestamp", interval] },
+            ]},
+            "price": decimal("price"),
+            "volume": { "$ifNull": [decimal("volume"), 0] },
+        }},
+        doc! { "$group": {
+            "_id": { "name": "$name", "token_name": "$token_name", "start": "$start" },
+            "open": { "$first": "$price" },
+            "high": { "$max": "$price" },
+            "low": { "$min": "$price" },
+            "close": { "$last": "$price" },
+            "volume": { "$sum": "$volume" },
+            "price_volume": { "$sum": { "$multiply": ["$price", "$volume"] } },
+            "num_trades": { "$sum": { "$ifNull": ["$price_point.num_trades", 0] } },
+            "funding_rate": latest("funding_rate"),
+            "open_interest": latest("open_interest"),
+            "oracle_price": latest("oracle_price"),
+        }},
+        doc! { "$sort": { "_id.name": 1, "_id.token_name": 1, "_id.start": 1 } },
+    ]
+}
+
+fn to_decimal(value: Option<&Bson>) -> Option<Decimal> {
+    crate::decimal::from_bson(value?)
+}
+
+fn candle_from_group(document: &Document) -> Result<Candle, DbError> {
+    let invalid = || DbError::Serialization("Unexpected candle document".into());
+    let id = document.get_document("_id").map_err(|_| invalid())?;
+    let required = |key: &str| to_decimal(document.get(key)).ok_or_else(invalid);
+    let latest = |key: &str| {
+        document
+            .get_document(key)
+            .ok()
+            .and_then(|latest| to_decimal(latest.get("v")))
+    };
+
+    let volume = required("volume")?;
+    let vwap = if volume.is_zero() {
+        None
+    } else {
+        Some(required("price_volume")? / volume)
+    };
+    let num_trades = match document.get("num_trades") {
+        Some(Bson::Int32(v)) => *v as u64,
+        Some(Bson::Int64(v)) => *v as u64,
+        _ => to_decimal(document.get("num_trades"))
+            .and_then(|v| u64::try_from(v).ok())
+            .unwrap_or(0),
+    };
+
+    Ok(Candle {
+        name: id.get_str("name").unwrap_or_default().to_owned(),
+        token_name: id.get_str("token_name").unwrap_or_default().to_owned(),
+        start: match id.get("start") {
+            Some(Bson::Int32(v)) => *v as i64,
+            Some(Bson::Int64(v)) => *v,
+            _ => return Err(invalid()),
+        },
+        open: required("open")?,
+        high: required("high")?,
+        low: required("low")?,
+        close: required("close")?,
+        volume,
+        num_trades,
+        vwap,
+        funding_rate: latest("funding_rate"),
+        open_interest: latest("open_interest"),
+        oracle_price: latest("oracle_price"),
+    })
+}
+
+/// Builds the candles of the prices with `from <= timestamp < to`. The
+/// aggregation runs on the server when the store supports it; otherwise the
+/// prices are streamed and aggregated here.
+pub async fn build_candles(
+    db: &dyn StorageBackend,
+    interval: CandleInterval,
+    from: i64,
+    to: i64,
+    name: Option<&str>,
+    token_name: Option<&str>,
+) -> Result<Vec<Candle>, DbError> {
+    let item = PriceLog::default();
+    let filter = price_filter(from, to, name, token_name);
+
+    match db
+        .aggregate(
+            item.get_collection_name(),
+            candle_pipeline(interval, filter.clone()),
+        )
+        .await
+    {
+        Ok(documents) => return documents.iter().map(candle_from_group).collect(),
+        Err(DbError::Unsupported(_)) => {}
+        Err(e) => return Err(e),
+    }
+
+    let prices = search_stream(
+        db,
+        &item,
+        SearchMode::Ascending,
+        CANDLE_PAGE_SIZE,
+        Some("price_point.timestamp"),
+        Some(filter),
+    );
+    futures::pin_mut!(prices);
+    let mut builders = CandleBuilders::new();
+    while let Some(price_log) = prices.try_next().await? {
+        add_price(&mut builders, &price_log, interval);
+    }
+    Ok(builders.into_values().map(CandleBuilder::build).collect())
+}
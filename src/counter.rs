@@ -1,22 +1,102 @@
 // counter.rs
 
+use bson::{doc, Bson, Document};
+
+use crate::DbError;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const COUNTER_COLLECTION: &str = "counters";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CounterType {
     Position,
     Price,
     Pnl,
 }
 
+impl CounterType {
+    pub fn key(&self) -> &'static str {
+        match self {
+            CounterType::Position => "position",
+            CounterType::Price => "price",
+            CounterType::Pnl => "pnl",
+        }
+    }
+}
+
+/// Where ids are allocated.
+///
+/// `Local` keeps the counters in this process and is only safe with a single
+/// writer. `Distributed` increments a per-`CounterType` document in the
+/// "counters" collection atomically on the server, so several processes can
+/// share one database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CounterMode {
+    #[default]
+    Local,
+    Distributed,
+}
+
+/// `sequence` only ever grows; the id handed out is derived from it, so with
+/// a `max` the generation of the ring buffer is `(sequence - 1) / (max - 1)`.
 pub struct CounterData {
     max: Option<u32>,
-    counter: std::sync::Mutex<u32>,
+    sequence: std::sync::Mutex<u64>,
 }
 
 pub struct Counter {
+    mode: CounterMode,
     position: CounterData,
     price: CounterData,
     pnl: CounterData,
 }
 
+fn sequence_of(document: &Document) -> Result<u64, DbError> {
+    match document.get("seq") {
+        Some(Bson::Int32(v)) => Ok(*v as u64),
+        Some(Bson::Int64(v)) => Ok(*v as u64),
+        Some(Bson::Double(v)) => Ok(*v as u64),
+        _ => Err(DbError::Serialization(
+            "Counter sequence is not a number".into(),
+        )),
+    }
+}
+
+/// Maps a monotonically increasing sequence number to an id. With a `max`,
+/// ids cycle through 1..max-1 like a ring buffer.
+fn wrap_sequence(seq: u64, max: Option<u32>) -> u32 {
+    match max {
+        Some(max) if max > 1 => (((seq.max(1) - 1) % (max as u64 - 1)) + 1) as u32,
+        Some(_) => 1,
+        None => seq as u32,
+    }
+}
+
+/// Increments the sequence saved under `key` in the "counters" collection
+/// atomically and returns the new value.
+pub(crate) async fn next_shared_sequence(
+    db: &dyn StorageBackend,
+    key: &str,
+) -> Result<u64, DbError> {
+    add_shared_sequence(db, key, 1).await
+}
+
+/// Adds `num` to the sequence saved under `key` atomically and returns the
+/// new value, reserving the `num` sequence numbers up to it.
+async fn add_shared_sequence(db: &dyn StorageBackend, key: &str, num: u64) -> Result<u64, DbError> {
+    let document = db
+        .find_one_and_update(
+            COUNTER_COLLECTION,
+            doc! { "_id": key },
+            doc! { "$inc": { "seq": num as i64 } },
+            true,
+        )
+        .await?
+        .ok_or_else(|| DbError::NotFound("Counter not found".to_string()))?;
+    sequence_of(&document)
+}
+
 impl Counter {
     pub fn new(
         max_position_counter: Option<u32>,
@@ -27,40 +107,197 @@ impl Counter {
         pnl_counter: u32,
     ) -> Self {
         Self {
+            mode: CounterMode::Local,
             position: CounterData {
                 max: max_position_counter,
-                counter: std::sync::Mutex::new(position_counter),
+                sequence: std::sync::Mutex::new(position_counter as u64),
             },
             price: CounterData {
                 max: max_price_counter,
-                counter: std::sync::Mutex::new(price_counter),
+                sequence: std::sync::Mutex::new(price_counter as u64),
             },
             pnl: CounterData {
                 max: max_pnl_counter,
-                counter: std::sync::Mutex::new(pnl_counter),
+                sequence: std::sync::Mutex::new(pnl_counter as u64),
             },
         }
     }
 
-    pub fn increment(&self, counter_type: CounterType) -> u32 {
-        let counter_data = match counter_type {
+    pub fn new_distributed(
+        max_position_counter: Option<u32>,
+        max_price_counter: Option<u32>,
+        max_pnl_counter: Option<u32>,
+    ) -> Self {
+        let mut counter = Self::new(
+            max_position_counter,
+            max_price_counter,
+            max_pnl_counter,
+            0,
+            0,
+            0,
+        );
+        counter.mode = CounterMode::Distributed;
+        counter
+    }
+
+    pub fn mode(&self) -> CounterMode {
+        self.mode
+    }
+
+    fn data(&self, counter_type: CounterType) -> &CounterData {
+        match counter_type {
             CounterType::Position => &self.position,
             CounterType::Price => &self.price,
             CounterType::Pnl => &self.pnl,
-        };
+        }
+    }
+
+    /// Allocates the next id from the in-process counter, whatever the mode.
+    pub fn increment(&self, counter_type: CounterType) -> u32 {
+        let counter_data = self.data(counter_type);
+
+        let mut sequence = counter_data.sequence.lock().unwrap();
+        *sequence += 1;
+        wrap_sequence(*sequence, counter_data.max)
+    }
+
+    /// The last sequence number handed out by the in-process counter.
+    pub fn sequence(&self, counter_type: CounterType) -> u64 {
+        *self.data(counter_type).sequence.lock().unwrap()
+    }
+
+    /// How many times the in-process counter has wrapped around.
+    pub fn generation(&self, counter_type: CounterType) -> u64 {
+        let counter_data = self.data(counter_type);
+        match counter_data.max {
+            Some(max) if max > 1 => {
+                self.sequence(counter_type).saturating_sub(1) / (max as u64 - 1)
+            }
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn restore(&self, counter_type: CounterType, sequence: u64) {
+        *self.data(counter_type).sequence.lock().unwrap() = sequence;
+    }
+
+    /// Allocates the next id according to the counter mode. A capped local
+    /// counter also saves its sequence, so that a restart resumes at the
+    /// right slot of the ring buffer rather than after the highest id.
+    pub async fn allocate(
+        &self,
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+    ) -> Result<u32, DbError> {
+        match self.mode {
+            CounterMode::Local => {
+                let id = self.increment(counter_type);
+                if self.data(counter_type).max.is_some() {
+                    Self::save_sequence(db, counter_type, self.sequence(counter_type)).await?;
+                }
+                Ok(id)
+            }
+            CounterMode::Distributed => {
+                let seq = next_shared_sequence(db, counter_type.key()).await?;
+                Ok(wrap_sequence(seq, self.data(counter_type).max))
+            }
+        }
+    }
 
-        let mut counter = counter_data.counter.lock().unwrap();
-        *counter += 1;
-        let mut id = *counter;
-        if let Some(max_counter) = counter_data.max {
-            id = *counter % max_counter;
-            if id == 0 {
-                id = 1;
+    /// Allocates `num` consecutive ids in one step, e.g. for a batch of
+    /// inserts, instead of one round trip per id.
+    pub async fn allocate_many(
+        &self,
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+        num: usize,
+    ) -> Result<Vec<u32>, DbError> {
+        if num == 0 {
+            return Ok(vec![]);
+        }
+        let counter_data = self.data(counter_type);
+        let last = match self.mode {
+            CounterMode::Local => {
+                let last = {
+                    let mut sequence = counter_data.sequence.lock().unwrap();
+                    *sequence += num as u64;
+                    *sequence
+                };
+                if counter_data.max.is_some() {
+                    Self::save_sequence(db, counter_type, last).await?;
+                }
+                last
             }
-            *counter = id;
+            CounterMode::Distributed => {
+                add_shared_sequence(db, counter_type.key(), num as u64).await?
+            }
+        };
+        Ok((last + 1 - num as u64..=last)
+            .map(|seq| wrap_sequence(seq, counter_data.max))
+            .collect())
+    }
+
+    /// Makes sure the shared sequence is not behind `sequence`, e.g. when a
+    /// database that was written with the local counter is shared later.
+    pub async fn seed(
+        &self,
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+        sequence: u64,
+    ) -> Result<(), DbError> {
+        if self.mode == CounterMode::Local {
+            return Ok(());
         }
+        Self::save_sequence(db, counter_type, sequence).await
+    }
+
+    async fn save_sequence(
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+        sequence: u64,
+    ) -> Result<(), DbError> {
+        db.update_one(
+            COUNTER_COLLECTION,
+            doc! { "_id": counter_type.key() },
+            doc! { "$max": { "seq": sequence as i64 } },
+            true,
+        )
+        .await
+    }
 
-        drop(counter);
-        id
+    /// Reads the saved sequence of `counter_type`, if any.
+    pub async fn load_sequence(
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+    ) -> Result<Option<u64>, DbError> {
+        match db
+            .search(
+                COUNTER_COLLECTION,
+                doc! { "_id": counter_type.key() },
+                SearchMode::Ascending,
+                Some(1),
+                None,
+                "id",
+            )
+            .await
+        {
+            Ok(documents) => documents.first().map(sequence_of).transpose(),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Forgets the saved sequence of `counter_type`.
+    pub async fn reset_sequence(
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+    ) -> Result<(), DbError> {
+        match db
+            .delete_one(COUNTER_COLLECTION, doc! { "_id": counter_type.key() })
+            .await
+        {
+            Err(e) if !e.is_not_found() => Err(e),
+            _ => Ok(()),
+        }
     }
 }
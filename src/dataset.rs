@@ -0,0 +1,225 @@
+// dataset.rs
+
+use futures::stream::TryStreamExt;
+use rust_decimal::prelude::{Signed, ToPrimitive};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+use crate::search_stream;
+use crate::DbError;
+use crate::FeatureSchema;
+use crate::PositionFilter;
+use crate::PositionLog;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const DATASET_PAGE_SIZE: u32 = 1000;
+
+/// Names of the label columns, in the order of `DatasetLabels::to_vec`.
+pub const LABEL_COLUMNS: [&str; 5] = [
+    "pnl",
+    "pnl_sign",
+    "return_ratio",
+    "holding_time",
+    "close_price",
+];
+
+/// Outcome of a closed position.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DatasetLabels {
+    pub pnl: Decimal,
+    /// -1, 0 or 1.
+    pub pnl_sign: Decimal,
+    /// `pnl / asset_in_usd`, or zero when the position has no size.
+    pub return_ratio: Decimal,
+    /// Seconds from open to close.
+    pub holding_time: i64,
+    pub close_price: Decimal,
+}
+
+impl DatasetLabels {
+    /// The labels of `position`, or `None` if it has not been closed. A
+    /// canceled position was never filled, so it has no labels either.
+    pub fn from_position(position: &PositionLog) -> Option<Self> {
+        if !position.state.is_closed() {
+            return None;
+        }
+        let close_timestamp = position.close_timestamp?;
+        let return_ratio = if position.asset_in_usd.is_zero() {
+            Decimal::ZERO
+        } else {
+            (position.pnl / position.asset_in_usd).normalize()
+        };
+        Some(Self {
+            pnl: position.pnl,
+            pnl_sign: position.pnl.signum(),
+            return_ratio,
+            holding_time: close_timestamp - position.open_timestamp,
+            close_price: position.close_price,
+        })
+    }
+
+    pub fn to_vec(&self) -> Vec<Decimal> {
+        vec![
+            self.pnl,
+            self.pnl_sign,
+            self.return_ratio,
+            Decimal::from(self.holding_time),
+            self.close_price,
+        ]
+    }
+}
+
+/// Features and labels of one position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatasetRow {
+    pub position_id: u32,
+    pub features: Vec<Decimal>,
+    pub labels: DatasetLabels,
+}
+
+impl DatasetRow {
+    /// The row of `position`, or `None` if it is still open or its features
+    /// follow another schema.
+    pub fn from_position(position: &PositionLog, schema: &FeatureSchema) -> Option<Self> {
+        let labels = DatasetLabels::from_position(position)?;
+        let features = schema.to_inputs(&position.feature_vector()).ok()?;
+        Some(Self {
+            position_id: position.id.unwrap_or_default(),
+            features,
+            labels,
+        })
+    }
+}
+
+/// A numeric matrix with one row per position, e.g. for training a model
+/// that is then kept with `ModelParams`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Dataset {
+    pub schema: String,
+    pub version: u32,
+    pub feature_columns: Vec<String>,
+    pub label_columns: Vec<String>,
+    pub position_ids: Vec<u32>,
+    pub features: Vec<Vec<f64>>,
+    pub labels: Vec<Vec<f64>>,
+}
+
+fn to_f64(values: &[Decimal]) -> Vec<f64> {
+    values
+        .iter()
+        .map(|v| v.to_f64().unwrap_or(f64::NAN))
+        .collect()
+}
+
+impl Dataset {
+    pub fn new(schema: &FeatureSchema) -> Self {
+        Self {
+            schema: schema.name.clone(),
+            version: schema.version,
+            feature_columns: schema.input_columns(),
+            label_columns: LABEL_COLUMNS.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, row: &DatasetRow) {
+        self.position_ids.push(row.position_id);
+        self.features.push(to_f64(&row.features));
+        self.labels.push(to_f64(&row.labels.to_vec()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.position_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position_ids.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DbError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DbError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Streams the positions matching `filter` in order of open time and calls
+/// `f` with the row of each closed one whose features follow `schema`.
+/// Returns the number of rows.
+pub async fn for_each_dataset_row<F>(
+    db: &dyn StorageBackend,
+    schema: &FeatureSchema,
+    filter: &PositionFilter,
+    mut f: F,
+) -> Result<usize, DbError>
+where
+    F: FnMut(DatasetRow) -> Result<(), DbError>,
+{
+    let item = PositionLog::default();
+    let positions = search_stream(
+        db,
+        &item,
+        SearchMode::Ascending,
+        DATASET_PAGE_SIZE,
+        Some("open_timestamp"),
+        Some(filter.to_document()),
+    );
+    futures::pin_mut!(positions);
+
+    let (mut num, mut skipped) = (0, 0);
+    while let Some(position) = positions.try_next().await? {
+        match DatasetRow::from_position(&position, schema) {
+            Some(row) => {
+                f(row)?;
+                num += 1;
+            }
+            None => skipped += 1,
+        }
+    }
+    log::debug!("dataset: rows = {}, skipped = {}", num, skipped);
+    Ok(num)
+}
+
+pub async fn build_dataset(
+    db: &dyn StorageBackend,
+    schema: &FeatureSchema,
+    filter: &PositionFilter,
+) -> Result<Dataset, DbError> {
+    let mut dataset = Dataset::new(schema);
+    for_each_dataset_row(db, schema, filter, |row| {
+        dataset.push(&row);
+        Ok(())
+    })
+    .await?;
+    Ok(dataset)
+}
+
+/// Writes the dataset as CSV without keeping it in memory: a header, then
+/// the position id, features and labels of each row. Values are written
+/// exactly as stored.
+pub async fn write_dataset_csv<W: Write>(
+    db: &dyn StorageBackend,
+    schema: &FeatureSchema,
+    filter: &PositionFilter,
+    writer: &mut W,
+) -> Result<usize, DbError> {
+    let header = std::iter::once("position_id".to_string())
+        .chain(schema.input_columns())
+        .chain(LABEL_COLUMNS.iter().map(|c| c.to_string()))
+        .collect::<Vec<_>>();
+    writeln!(writer, "{}", header.join(","))?;
+
+    for_each_dataset_row(db, schema, filter, |row| {
+        let values = std::iter::once(row.position_id.to_string())
+            .chain(row.features.iter().map(Decimal::to_string))
+            .chain(row.labels.to_vec().iter().map(Decimal::to_string))
+            .collect::<Vec<_>>();
+        writeln!(writer, "{}", values.join(","))?;
+        Ok(())
+    })
+    .await
+}
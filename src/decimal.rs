@@ -0,0 +1,97 @@
+// decimal.rs
+//
+// Decimals stored as BSON Decimal128, which keeps their precision and can be
+// added to and compared by the database.
+
+use bson::{Bson, Decimal128};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::DbError;
+
+pub(crate) fn to_bson(value: Decimal) -> Result<Bson, DbError> {
+    Decimal128::from_str(&value.to_string())
+        .map(Bson::Decimal128)
+        .map_err(|e| DbError::Serialization(format!("{}: {}", value, e).into()))
+}
+
+fn parse(s: &str) -> Option<Decimal> {
+    Decimal::from_str(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .ok()
+}
+
+/// The value of a numeric BSON value, if it fits in a `Decimal`.
+pub(crate) fn from_number(value: &Bson) -> Option<Decimal> {
+    match value {
+        Bson::Decimal128(v) => parse(&v.to_string()),
+        Bson::Int32(v) => Some(Decimal::from(*v)),
+        Bson::Int64(v) => Some(Decimal::from(*v)),
+        Bson::Double(v) => Decimal::try_from(*v).ok(),
+        _ => None,
+    }
+}
+
+/// Same as `from_number`, also accepting the decimal strings that
+/// `Decimal` is serialized to by default.
+pub(crate) fn from_bson(value: &Bson) -> Option<Decimal> {
+    match value {
+        Bson::String(s) => parse(s),
+        value => from_number(value),
+    }
+}
+
+/// Serde helpers for `Decimal` fields stored as Decimal128. Values stored as
+/// strings or doubles are still read.
+pub(crate) mod decimal128 {
+    use bson::Bson;
+    use rust_decimal::Decimal;
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Decimal,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        super::to_bson(*value)
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Decimal, D::Error> {
+        let value = Bson::deserialize(deserializer)?;
+        super::from_bson(&value)
+            .ok_or_else(|| D::Error::custom(format!("invalid decimal: {}", value)))
+    }
+}
+
+/// Same as `decimal128`, for optional fields.
+pub(crate) mod decimal128_option {
+    use bson::Bson;
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<Decimal>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::decimal128::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Decimal>, D::Error> {
+        match Bson::deserialize(deserializer)? {
+            Bson::Null | Bson::Undefined => Ok(None),
+            value => super::from_bson(&value)
+                .map(Some)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid decimal: {}", value))),
+        }
+    }
+}
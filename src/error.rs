@@ -0,0 +1,161 @@
+// error.rs
+
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum DbError {
+    NotFound(String),
+    Ambiguous(String),
+    InvalidQuery(String),
+    Serialization(Box<dyn error::Error + Send + Sync>),
+    Connection(String),
+    DuplicateKey(String),
+    Mongo(mongodb::error::Error),
+    Backend(Box<dyn error::Error + Send + Sync>),
+    Io(std::io::Error),
+    Unsupported(String),
+    InvalidTransition(String),
+    /// The stored item changed since it was read.
+    Conflict(String),
+}
+
+impl DbError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, DbError::NotFound(_))
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, DbError::Conflict(_))
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::NotFound(msg) => write!(f, "not found: {}", msg),
+            DbError::Ambiguous(msg) => write!(f, "ambiguous result: {}", msg),
+            DbError::InvalidQuery(msg) => write!(f, "invalid query: {}", msg),
+            DbError::Serialization(e) => write!(f, "serialization error: {}", e),
+            DbError::Connection(msg) => write!(f, "connection error: {}", msg),
+            DbError::DuplicateKey(msg) => write!(f, "duplicate key: {}", msg),
+            DbError::Mongo(e) => write!(f, "mongodb error: {}", e),
+            DbError::Backend(e) => write!(f, "backend error: {}", e),
+            DbError::Io(e) => write!(f, "io error: {}", e),
+            DbError::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            DbError::InvalidTransition(msg) => write!(f, "invalid transition: {}", msg),
+            DbError::Conflict(msg) => write!(f, "conflict: {}", msg),
+        }
+    }
+}
+
+impl error::Error for DbError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DbError::Serialization(e) => Some(e.as_ref()),
+            DbError::Mongo(e) => Some(e),
+            DbError::Backend(e) => Some(e.as_ref()),
+            DbError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Runs `f` until it succeeds, fails with something other than
+/// `DbError::Conflict`, or has been tried `max_attempts` times. `f` should
+/// read the item again each time, so that its change is made to the
+/// latest version.
+pub async fn retry_on_conflict<T, F, Fut>(max_attempts: usize, mut f: F) -> Result<T, DbError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DbError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if e.is_conflict() && attempt < max_attempts => {
+                log::debug!("retry_on_conflict: attempt = {}, {}", attempt, e);
+                tokio::time::sleep(Duration::from_millis(10 * attempt as u64)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    use mongodb::error::{ErrorKind, WriteFailure};
+
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => write_error.code == 11000,
+        ErrorKind::BulkWrite(bulk) => bulk
+            .write_errors
+            .as_ref()
+            .is_some_and(|errors| errors.iter().any(|e| e.code == 11000)),
+        ErrorKind::Command(command_error) => command_error.code == 11000,
+        _ => false,
+    }
+}
+
+impl From<mongodb::error::Error> for DbError {
+    fn from(e: mongodb::error::Error) -> Self {
+        if is_duplicate_key(&e) {
+            DbError::DuplicateKey(e.to_string())
+        } else {
+            DbError::Mongo(e)
+        }
+    }
+}
+
+impl From<bson::ser::Error> for DbError {
+    fn from(e: bson::ser::Error) -> Self {
+        DbError::Serialization(Box::new(e))
+    }
+}
+
+impl From<bson::de::Error> for DbError {
+    fn from(e: bson::de::Error) -> Self {
+        DbError::Serialization(Box::new(e))
+    }
+}
+
+impl From<bincode::Error> for DbError {
+    fn from(e: bincode::Error) -> Self {
+        DbError::Serialization(e)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Serialization(Box::new(e))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        match &e {
+            rusqlite::Error::SqliteFailure(failure, _)
+                if failure.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                DbError::DuplicateKey(e.to_string())
+            }
+            _ => DbError::Backend(Box::new(e)),
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for DbError {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        DbError::Backend(Box::new(e))
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
@@ -0,0 +1,505 @@
+// export.rs
+
+use bson::{doc, Bson, Document};
+use debot_utils::HasId;
+use futures::stream::{Stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::search_stream;
+use crate::DbError;
+use crate::Entity;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const EXPORT_PAGE_SIZE: u32 = 1000;
+
+/// Which items to export, as with `search_items`. `from` and `to` bound the
+/// sort key, `from <= value < to`, so a time range is given by sorting on a
+/// timestamp such as "price_point.timestamp".
+#[derive(Clone, Debug)]
+pub struct ExportOptions {
+    pub mode: SearchMode,
+    pub limit: Option<u32>,
+    pub sort_key: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub filter: Option<Document>,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            mode: SearchMode::Ascending,
+            limit: None,
+            sort_key: None,
+            from: None,
+            to: None,
+            filter: None,
+        }
+    }
+}
+
+impl ExportOptions {
+    fn to_filter(&self) -> Result<Option<Document>, DbError> {
+        let mut range = Document::new();
+        if let Some(from) = self.from {
+            range.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            range.insert("$lt", to);
+        }
+        if range.is_empty() {
+            return Ok(self.filter.clone());
+        }
+        let Some(sort_key) = &self.sort_key else {
+            return Err(DbError::InvalidQuery(
+                "A range needs a sort key".to_string(),
+            ));
+        };
+        let range = doc! { sort_key: range };
+        Ok(Some(match &self.filter {
+            Some(filter) => doc! { "$and": [filter.clone(), range] },
+            None => range,
+        }))
+    }
+}
+
+/// Rows of flattened documents. Embedded documents become dotted columns,
+/// e.g. "price_point.timestamp", and arrays one column per element, e.g.
+/// "fund_configs.0.token"; columns are in order of first appearance.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Bson>>,
+}
+
+fn flatten(prefix: &str, document: &Document, out: &mut Vec<(String, Bson)>) {
+    for (key, value) in document {
+        let key = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        flatten_value(key, value, out);
+    }
+}
+
+fn flatten_value(key: String, value: &Bson, out: &mut Vec<(String, Bson)>) {
+    match value {
+        Bson::Document(document) => flatten(&key, document, out),
+        Bson::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                flatten_value(format!("{}.{}", key, i), value, out);
+            }
+        }
+        value => out.push((key, value.clone())),
+    }
+}
+
+/// Column names in order of first appearance.
+#[derive(Clone, Debug, Default)]
+struct Columns {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl Columns {
+    fn add(&mut self, fields: &[(String, Bson)]) {
+        for (key, _) in fields {
+            if !self.index.contains_key(key) {
+                self.index.insert(key.clone(), self.names.len());
+                self.names.push(key.clone());
+            }
+        }
+    }
+
+    /// The cells of `fields` in column order. Fields without a column are
+    /// left out.
+    fn row(&self, fields: Vec<(String, Bson)>) -> Vec<Bson> {
+        let mut row = vec![Bson::Null; self.names.len()];
+        for (key, value) in fields {
+            match self.index.get(&key) {
+                Some(&i) => row[i] = value,
+                None => log::debug!("export: no column for {}", key),
+            }
+        }
+        row
+    }
+}
+
+fn flattened(document: &Document) -> Vec<(String, Bson)> {
+    let mut fields = vec![];
+    flatten("", document, &mut fields);
+    fields
+}
+
+impl Table {
+    pub fn from_documents(documents: &[Document]) -> Self {
+        let flattened: Vec<_> = documents.iter().map(flattened).collect();
+        let mut columns = Columns::default();
+        for fields in &flattened {
+            columns.add(fields);
+        }
+        let rows = flattened
+            .into_iter()
+            .map(|fields| columns.row(fields))
+            .collect();
+        Table {
+            columns: columns.names,
+            rows,
+        }
+    }
+
+    pub fn from_items<T: Serialize>(items: &[T]) -> Result<Self, DbError> {
+        let documents = items
+            .iter()
+            .map(bson::to_document)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_documents(&documents))
+    }
+}
+
+/// Text of a cell. Decimals are stored as strings, so they keep their exact
+/// value; other values that are not scalars are written as JSON.
+pub fn cell_to_string(value: &Bson) -> String {
+    match value {
+        Bson::Null | Bson::Undefined => String::new(),
+        Bson::String(s) => s.clone(),
+        Bson::Int32(v) => v.to_string(),
+        Bson::Int64(v) => v.to_string(),
+        Bson::Double(v) => v.to_string(),
+        Bson::Boolean(v) => v.to_string(),
+        Bson::Decimal128(v) => v.to_string(),
+        value => value.clone().into_relaxed_extjson().to_string(),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// The items of `item`'s collection selected by `options`, page by page,
+/// as flattened documents.
+fn export_rows<'a, T: Entity + Serialize + HasId + 'a>(
+    db: &'a dyn StorageBackend,
+    item: &'a T,
+    options: &'a ExportOptions,
+) -> Result<impl Stream<Item = Result<Vec<(String, Bson)>, DbError>> + 'a, DbError> {
+    let limit = options.limit.map_or(usize::MAX, |limit| limit as usize);
+    let page_size = options
+        .limit
+        .unwrap_or(EXPORT_PAGE_SIZE)
+        .min(EXPORT_PAGE_SIZE);
+    let rows = search_stream(
+        db,
+        item,
+        options.mode,
+        page_size.max(1),
+        options.sort_key.as_deref(),
+        options.to_filter()?,
+    )
+    .take(limit)
+    .and_then(|item| async move { Ok(flattened(&bson::to_document(&item)?)) });
+    Ok(rows)
+}
+
+/// The columns of the items selected by `options`, read in a first pass so
+/// that rows can then be written one page at a time.
+async fn export_columns<T: Entity + Serialize + HasId>(
+    db: &dyn StorageBackend,
+    item: &T,
+    options: &ExportOptions,
+) -> Result<Columns, DbError> {
+    export_rows(db, item, options)?
+        .try_fold(Columns::default(), |mut columns, fields| async move {
+            columns.add(&fields);
+            Ok(columns)
+        })
+        .await
+}
+
+/// Reads the items of `item`'s collection selected by `options` into
+/// memory. `export_csv` and `export_parquet` write large collections
+/// without holding them.
+pub async fn export_table<T: Entity + Serialize + HasId>(
+    db: &dyn StorageBackend,
+    item: &T,
+    options: &ExportOptions,
+) -> Result<Table, DbError> {
+    let rows: Vec<_> = export_rows(db, item, options)?.try_collect().await?;
+    let mut columns = Columns::default();
+    for fields in &rows {
+        columns.add(fields);
+    }
+    let rows = rows.into_iter().map(|fields| columns.row(fields)).collect();
+    Ok(Table {
+        columns: columns.names,
+        rows,
+    })
+}
+
+fn write_csv_header<W: Write>(columns: &[String], writer: &mut W) -> Result<(), DbError> {
+    let header: Vec<_> = columns.iter().map(|c| csv_field(c)).collect();
+    writeln!(writer, "{}", header.join(","))?;
+    Ok(())
+}
+
+fn write_csv_row<W: Write>(row: &[Bson], writer: &mut W) -> Result<(), DbError> {
+    let fields: Vec<_> = row
+        .iter()
+        .map(|value| csv_field(&cell_to_string(value)))
+        .collect();
+    writeln!(writer, "{}", fields.join(","))?;
+    Ok(())
+}
+
+pub fn write_csv<W: Write>(table: &Table, writer: &mut W) -> Result<(), DbError> {
+    write_csv_header(&table.columns, writer)?;
+    for row in &table.rows {
+        write_csv_row(row, writer)?;
+    }
+    Ok(())
+}
+
+/// Writes the items of `item`'s collection selected by `options` as CSV and
+/// returns the number of rows. The items are read twice, once for the
+/// columns and once for the rows, so that only a page is held at a time;
+/// fields of items added in between that have no column are left out.
+pub async fn export_csv<T: Entity + Serialize + HasId, W: Write>(
+    db: &dyn StorageBackend,
+    item: &T,
+    options: &ExportOptions,
+    writer: &mut W,
+) -> Result<usize, DbError> {
+    let columns = export_columns(db, item, options).await?;
+    write_csv_header(&columns.names, writer)?;
+    let mut rows = std::pin::pin!(export_rows(db, item, options)?);
+    let mut num = 0;
+    while let Some(fields) = rows.try_next().await? {
+        write_csv_row(&columns.row(fields), writer)?;
+        num += 1;
+    }
+    Ok(num)
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use bson::Bson;
+    use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+    use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::types::Type;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use super::{cell_to_string, Table};
+    use crate::DbError;
+
+    #[derive(Clone, Copy, PartialEq)]
+    pub(super) enum ColumnType {
+        Int64,
+        Double,
+        Boolean,
+        Utf8,
+    }
+
+    /// Widens `column_types` to the narrowest types that also hold the
+    /// cells of `row`. Columns with only nulls so far stay `None`.
+    pub(super) fn merge_types(column_types: &mut Vec<Option<ColumnType>>, row: &[Bson]) {
+        column_types.resize(row.len().max(column_types.len()), None);
+        for (column_type, value) in column_types.iter_mut().zip(row) {
+            let value_type = match value {
+                Bson::Null | Bson::Undefined => continue,
+                Bson::Int32(_) | Bson::Int64(_) => ColumnType::Int64,
+                Bson::Double(_) => ColumnType::Double,
+                Bson::Boolean(_) => ColumnType::Boolean,
+                _ => ColumnType::Utf8,
+            };
+            *column_type = Some(match (*column_type, value_type) {
+                (None, t) => t,
+                (Some(a), b) if a == b => a,
+                (Some(ColumnType::Int64), ColumnType::Double)
+                | (Some(ColumnType::Double), ColumnType::Int64) => ColumnType::Double,
+                _ => ColumnType::Utf8,
+            });
+        }
+    }
+
+    fn field(name: &str, column_type: ColumnType) -> Result<Type, DbError> {
+        let builder = match column_type {
+            ColumnType::Int64 => Type::primitive_type_builder(name, PhysicalType::INT64),
+            ColumnType::Double => Type::primitive_type_builder(name, PhysicalType::DOUBLE),
+            ColumnType::Boolean => Type::primitive_type_builder(name, PhysicalType::BOOLEAN),
+            ColumnType::Utf8 => Type::primitive_type_builder(name, PhysicalType::BYTE_ARRAY)
+                .with_converted_type(ConvertedType::UTF8),
+        };
+        Ok(builder.with_repetition(Repetition::OPTIONAL).build()?)
+    }
+
+    /// Values and definition levels of the non-null cells of column `i`.
+    fn values<T>(rows: &[Vec<Bson>], i: usize, f: impl Fn(&Bson) -> T) -> (Vec<T>, Vec<i16>) {
+        let mut values = vec![];
+        let mut levels = vec![];
+        for row in rows {
+            match &row[i] {
+                Bson::Null | Bson::Undefined => levels.push(0),
+                value => {
+                    values.push(f(value));
+                    levels.push(1);
+                }
+            }
+        }
+        (values, levels)
+    }
+
+    /// Writes rows one row group at a time. Columns with only nulls are
+    /// strings.
+    pub(super) struct RowGroupWriter<W: Write + Send> {
+        writer: SerializedFileWriter<W>,
+        column_types: Vec<ColumnType>,
+    }
+
+    impl<W: Write + Send> RowGroupWriter<W> {
+        pub(super) fn new(
+            columns: &[String],
+            column_types: &[Option<ColumnType>],
+            writer: W,
+        ) -> Result<Self, DbError> {
+            let column_types: Vec<_> = (0..columns.len())
+                .map(|i| {
+                    column_types
+                        .get(i)
+                        .copied()
+                        .flatten()
+                        .unwrap_or(ColumnType::Utf8)
+                })
+                .collect();
+            let fields = columns
+                .iter()
+                .zip(&column_types)
+                .map(|(name, column_type)| field(name, *column_type).map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()?;
+            let schema = Type::group_type_builder("schema")
+                .with_fields(fields)
+                .build()?;
+            let properties = Arc::new(WriterProperties::builder().build());
+            let writer = SerializedFileWriter::new(writer, Arc::new(schema), properties)?;
+            Ok(Self {
+                writer,
+                column_types,
+            })
+        }
+
+        pub(super) fn write(&mut self, rows: &[Vec<Bson>]) -> Result<(), DbError> {
+            let mut row_group = self.writer.next_row_group()?;
+            let mut i = 0;
+            while let Some(mut column) = row_group.next_column()? {
+                match self.column_types[i] {
+                    ColumnType::Int64 => {
+                        let (values, levels) = values(rows, i, |v| match v {
+                            Bson::Int32(v) => *v as i64,
+                            Bson::Int64(v) => *v,
+                            _ => 0,
+                        });
+                        column
+                            .typed::<Int64Type>()
+                            .write_batch(&values, Some(&levels), None)?;
+                    }
+                    ColumnType::Double => {
+                        let (values, levels) = values(rows, i, |v| match v {
+                            Bson::Int32(v) => *v as f64,
+                            Bson::Int64(v) => *v as f64,
+                            Bson::Double(v) => *v,
+                            _ => 0.0,
+                        });
+                        column
+                            .typed::<DoubleType>()
+                            .write_batch(&values, Some(&levels), None)?;
+                    }
+                    ColumnType::Boolean => {
+                        let (values, levels) = values(rows, i, |v| v.as_bool().unwrap_or_default());
+                        column
+                            .typed::<BoolType>()
+                            .write_batch(&values, Some(&levels), None)?;
+                    }
+                    ColumnType::Utf8 => {
+                        let (values, levels) =
+                            values(rows, i, |v| ByteArray::from(cell_to_string(v).as_str()));
+                        column.typed::<ByteArrayType>().write_batch(
+                            &values,
+                            Some(&levels),
+                            None,
+                        )?;
+                    }
+                }
+                column.close()?;
+                i += 1;
+            }
+            row_group.close()?;
+            Ok(())
+        }
+
+        pub(super) fn close(self) -> Result<(), DbError> {
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+
+    pub fn write_parquet<W: Write + Send>(table: &Table, writer: W) -> Result<(), DbError> {
+        let mut column_types = vec![];
+        for row in &table.rows {
+            merge_types(&mut column_types, row);
+        }
+        let mut writer = RowGroupWriter::new(&table.columns, &column_types, writer)?;
+        writer.write(&table.rows)?;
+        writer.close()
+    }
+}
+
+#[cfg(feature = "parquet")]
+pub use parquet_writer::write_parquet;
+
+/// Writes the items of `item`'s collection selected by `options` as Parquet
+/// and returns the number of rows. Integers, doubles and booleans get typed
+/// columns; everything else, including Decimals, is stored as UTF-8 text so
+/// that no precision is lost. As with `export_csv`, the items are read once
+/// for the schema and once more for the rows, which are written a page per
+/// row group.
+#[cfg(feature = "parquet")]
+pub async fn export_parquet<T: Entity + Serialize + HasId, W: Write + Send>(
+    db: &dyn StorageBackend,
+    item: &T,
+    options: &ExportOptions,
+    writer: W,
+) -> Result<usize, DbError> {
+    use parquet_writer::{merge_types, RowGroupWriter};
+
+    let (columns, column_types) = export_rows(db, item, options)?
+        .try_fold(
+            (Columns::default(), vec![]),
+            |(mut columns, mut column_types), fields| async move {
+                columns.add(&fields);
+                merge_types(&mut column_types, &columns.row(fields));
+                Ok((columns, column_types))
+            },
+        )
+        .await?;
+    let mut writer = RowGroupWriter::new(&columns.names, &column_types, writer)?;
+    let mut pages = std::pin::pin!(export_rows(db, item, options)?
+        .map_ok(|fields| columns.row(fields))
+        .try_chunks(EXPORT_PAGE_SIZE as usize));
+    let mut num = 0;
+    while let Some(rows) = pages.try_next().await.map_err(|e| e.1)? {
+        writer.write(&rows)?;
+        num += rows.len();
+    }
+    writer.close()?;
+    Ok(num)
+}
@@ -0,0 +1,407 @@
+// feature.rs
+
+use bson::doc;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::CandlePattern;
+use crate::DbError;
+use crate::DebugLog;
+use crate::IndexSpec;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+const FEATURE_SCHEMA_COLLECTION: &str = "feature_schemas";
+
+/// Schema of the vectors adapted from `DebugLog`.
+pub const DEBUG_LOG_SCHEMA: &str = "debug_log";
+
+const DEBUG_LOG_NUMERIC_INPUTS: usize = 29;
+const DEBUG_LOG_PATTERN_INPUTS: usize = 10;
+const DEBUG_LOG_OUTPUTS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureKind {
+    Numeric,
+    /// A `CandlePattern`, expanded to its one-hot encoding in vectors.
+    Pattern,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeatureSpec {
+    pub name: String,
+    pub kind: FeatureKind,
+}
+
+impl FeatureSpec {
+    pub fn numeric(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind: FeatureKind::Numeric,
+        }
+    }
+
+    pub fn pattern(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            kind: FeatureKind::Pattern,
+        }
+    }
+}
+
+/// Names and order of the features of a model. A schema is identified by
+/// `name` and `version`; a changed feature set gets a new version rather
+/// than a new crate release.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeatureSchema {
+    pub name: String,
+    pub version: u32,
+    pub inputs: Vec<FeatureSpec>,
+    pub outputs: Vec<String>,
+}
+
+impl FeatureSchema {
+    /// The fixed layout of `DebugLog`: 29 numeric inputs, 10 pattern inputs
+    /// and 5 outputs, named after its fields.
+    pub fn debug_log() -> Self {
+        let numeric =
+            (1..=DEBUG_LOG_NUMERIC_INPUTS).map(|i| FeatureSpec::numeric(&format!("input_{}", i)));
+        let patterns = (1..=DEBUG_LOG_PATTERN_INPUTS)
+            .map(|i| FeatureSpec::pattern(&format!("input_{}", DEBUG_LOG_NUMERIC_INPUTS + i)));
+        Self {
+            name: DEBUG_LOG_SCHEMA.to_owned(),
+            version: 1,
+            inputs: numeric.chain(patterns).collect(),
+            outputs: (1..=DEBUG_LOG_OUTPUTS)
+                .map(|i| format!("output_{}", i))
+                .collect(),
+        }
+    }
+
+    fn is_schema_of(&self, vector: &FeatureVector) -> bool {
+        self.name == vector.schema && self.version == vector.version
+    }
+
+    /// Checks that `vector` belongs to this schema and has every feature
+    /// with the right kind. Unknown features are an error too.
+    pub fn validate(&self, vector: &FeatureVector) -> Result<(), DbError> {
+        if !self.is_schema_of(vector) {
+            return Err(DbError::InvalidQuery(format!(
+                "Feature vector is for {}@{}, not {}@{}",
+                vector.schema, vector.version, self.name, self.version
+            )));
+        }
+        for spec in &self.inputs {
+            match (spec.kind, vector.inputs.get(&spec.name)) {
+                (FeatureKind::Numeric, Some(FeatureValue::Numeric(_)))
+                | (FeatureKind::Pattern, Some(FeatureValue::Pattern(_))) => {}
+                (_, value) => {
+                    return Err(DbError::InvalidQuery(format!(
+                        "Feature {} should be {:?}, found {:?}",
+                        spec.name, spec.kind, value
+                    )))
+                }
+            }
+        }
+        for name in &self.outputs {
+            if !vector.outputs.contains_key(name) {
+                return Err(DbError::InvalidQuery(format!("Output {} is missing", name)));
+            }
+        }
+        let known =
+            vector.inputs.len() == self.inputs.len() && vector.outputs.len() == self.outputs.len();
+        if !known {
+            return Err(DbError::InvalidQuery(format!(
+                "Feature vector has features that are not in {}@{}",
+                self.name, self.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Column names of `to_inputs`. A pattern feature `p` becomes one column
+    /// per pattern, such as `p.Doji`.
+    pub fn input_columns(&self) -> Vec<String> {
+        let mut columns = vec![];
+        for spec in &self.inputs {
+            match spec.kind {
+                FeatureKind::Numeric => columns.push(spec.name.clone()),
+                FeatureKind::Pattern => columns.extend(
+                    CandlePattern::ALL
+                        .iter()
+                        .map(|pattern| format!("{}.{:?}", spec.name, pattern)),
+                ),
+            }
+        }
+        columns
+    }
+
+    /// The inputs of `vector` in schema order, with patterns one-hot encoded.
+    pub fn to_inputs(&self, vector: &FeatureVector) -> Result<Vec<Decimal>, DbError> {
+        self.validate(vector)?;
+        let mut inputs = vec![];
+        for spec in &self.inputs {
+            match &vector.inputs[&spec.name] {
+                FeatureValue::Numeric(value) => inputs.push(*value),
+                FeatureValue::Pattern(pattern) => inputs.extend(pattern.to_one_hot()),
+            }
+        }
+        Ok(inputs)
+    }
+
+    /// The outputs of `vector` in schema order.
+    pub fn to_outputs(&self, vector: &FeatureVector) -> Result<Vec<Decimal>, DbError> {
+        self.validate(vector)?;
+        Ok(self
+            .outputs
+            .iter()
+            .map(|name| vector.outputs[name])
+            .collect())
+    }
+}
+
+/// Decimals are stored as strings and patterns by name, so the variant is
+/// told apart by whether the string parses as a number.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum FeatureValue {
+    Numeric(Decimal),
+    Pattern(CandlePattern),
+}
+
+impl From<Decimal> for FeatureValue {
+    fn from(value: Decimal) -> Self {
+        FeatureValue::Numeric(value)
+    }
+}
+
+impl From<CandlePattern> for FeatureValue {
+    fn from(pattern: CandlePattern) -> Self {
+        FeatureValue::Pattern(pattern)
+    }
+}
+
+/// Named model inputs and outputs, tagged with the schema they follow.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FeatureVector {
+    pub schema: String,
+    pub version: u32,
+    pub inputs: BTreeMap<String, FeatureValue>,
+    pub outputs: BTreeMap<String, Decimal>,
+}
+
+impl FeatureVector {
+    pub fn new(schema: &FeatureSchema) -> Self {
+        Self {
+            schema: schema.name.clone(),
+            version: schema.version,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_input(mut self, name: &str, value: impl Into<FeatureValue>) -> Self {
+        self.inputs.insert(name.to_owned(), value.into());
+        self
+    }
+
+    pub fn with_output(mut self, name: &str, value: Decimal) -> Self {
+        self.outputs.insert(name.to_owned(), value);
+        self
+    }
+}
+
+impl From<&DebugLog> for FeatureVector {
+    fn from(debug: &DebugLog) -> Self {
+        let numeric = [
+            debug.input_1,
+            debug.input_2,
+            debug.input_3,
+            debug.input_4,
+            debug.input_5,
+            debug.input_6,
+            debug.input_7,
+            debug.input_8,
+            debug.input_9,
+            debug.input_10,
+            debug.input_11,
+            debug.input_12,
+            debug.input_13,
+            debug.input_14,
+            debug.input_15,
+            debug.input_16,
+            debug.input_17,
+            debug.input_18,
+            debug.input_19,
+            debug.input_20,
+            debug.input_21,
+            debug.input_22,
+            debug.input_23,
+            debug.input_24,
+            debug.input_25,
+            debug.input_26,
+            debug.input_27,
+            debug.input_28,
+            debug.input_29,
+        ];
+        let patterns = [
+            debug.input_30,
+            debug.input_31,
+            debug.input_32,
+            debug.input_33,
+            debug.input_34,
+            debug.input_35,
+            debug.input_36,
+            debug.input_37,
+            debug.input_38,
+            debug.input_39,
+        ];
+        let outputs = [
+            debug.output_1,
+            debug.output_2,
+            debug.output_3,
+            debug.output_4,
+            debug.output_5,
+        ];
+
+        let schema = FeatureSchema::debug_log();
+        let values = numeric
+            .into_iter()
+            .map(FeatureValue::from)
+            .chain(patterns.into_iter().map(FeatureValue::from));
+        Self {
+            schema: schema.name,
+            version: schema.version,
+            inputs: schema
+                .inputs
+                .into_iter()
+                .map(|spec| spec.name)
+                .zip(values)
+                .collect(),
+            outputs: schema.outputs.into_iter().zip(outputs).collect(),
+        }
+    }
+}
+
+impl From<DebugLog> for FeatureVector {
+    fn from(debug: DebugLog) -> Self {
+        FeatureVector::from(&debug)
+    }
+}
+
+/// Known feature schemas by name and version. Schemas are kept in the
+/// "feature_schemas" collection so that readers can look up the layout of
+/// stored vectors; `DebugLog`'s layout is always known.
+#[derive(Clone, Debug)]
+pub struct FeatureRegistry {
+    schemas: BTreeMap<(String, u32), FeatureSchema>,
+}
+
+impl Default for FeatureRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            schemas: BTreeMap::new(),
+        };
+        let schema = FeatureSchema::debug_log();
+        registry
+            .schemas
+            .insert((schema.name.clone(), schema.version), schema);
+        registry
+    }
+}
+
+impl FeatureRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `schema`. A schema that is already known must not change.
+    pub fn register(&mut self, schema: FeatureSchema) -> Result<(), DbError> {
+        let key = (schema.name.clone(), schema.version);
+        match self.schemas.get(&key) {
+            Some(known) if *known != schema => Err(DbError::DuplicateKey(format!(
+                "Feature schema {}@{} is already registered with other features",
+                schema.name, schema.version
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                self.schemas.insert(key, schema);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str, version: u32) -> Option<&FeatureSchema> {
+        self.schemas.get(&(name.to_owned(), version))
+    }
+
+    /// The highest version of schema `name`.
+    pub fn latest(&self, name: &str) -> Option<&FeatureSchema> {
+        self.schemas
+            .range((name.to_owned(), 0)..=(name.to_owned(), u32::MAX))
+            .next_back()
+            .map(|(_, schema)| schema)
+    }
+
+    /// The schema `vector` follows.
+    pub fn schema_of(&self, vector: &FeatureVector) -> Option<&FeatureSchema> {
+        self.get(&vector.schema, vector.version)
+    }
+
+    pub fn schemas(&self) -> impl Iterator<Item = &FeatureSchema> {
+        self.schemas.values()
+    }
+
+    pub async fn create_indexes(db: &dyn StorageBackend) -> Result<(), DbError> {
+        let indexes = vec![IndexSpec::new(
+            "name_1_version_1",
+            doc! {"name": 1, "version": 1},
+            true,
+        )];
+        db.create_indexes(FEATURE_SCHEMA_COLLECTION, indexes).await
+    }
+
+    /// Reads the saved schemas.
+    pub async fn load(db: &dyn StorageBackend) -> Result<Self, DbError> {
+        let mut registry = Self::default();
+        // Skips the placeholder document MongoDB creates with the indexes.
+        let documents = match db
+            .search(
+                FEATURE_SCHEMA_COLLECTION,
+                doc! { "name": { "$exists": true }},
+                SearchMode::Ascending,
+                None,
+                None,
+                "id",
+            )
+            .await
+        {
+            Ok(documents) => documents,
+            Err(e) if e.is_not_found() => vec![],
+            Err(e) => return Err(e),
+        };
+        for document in documents {
+            let mut document = document;
+            document.remove("_id");
+            registry.register(bson::from_document(document)?)?;
+        }
+        Ok(registry)
+    }
+
+    /// Adds `schema` and saves it.
+    pub async fn save(
+        &mut self,
+        db: &dyn StorageBackend,
+        schema: FeatureSchema,
+    ) -> Result<(), DbError> {
+        self.register(schema.clone())?;
+        let query = doc! { "name": &schema.name, "version": schema.version };
+        db.replace_one(
+            FEATURE_SCHEMA_COLLECTION,
+            query,
+            bson::to_document(&schema)?,
+            true,
+        )
+        .await
+    }
+}
@@ -0,0 +1,361 @@
+// import.rs
+
+use bson::{doc, Document};
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::str::FromStr;
+
+use crate::Counter;
+use crate::CounterType;
+use crate::DbError;
+use crate::Entity;
+use crate::PriceLog;
+use crate::PricePoint;
+use crate::SearchMode;
+use crate::StorageBackend;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Comma-separated values with a header line.
+    Csv,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+/// Source column of each `PriceLog` field. Optional fields that are not
+/// mapped, or empty in a row, are left unset.
+#[derive(Clone, Debug)]
+pub struct PriceColumns {
+    /// When set, ids are taken from this column; otherwise they are
+    /// allocated from a `Counter`.
+    pub id: Option<String>,
+    pub name: String,
+    pub token_name: String,
+    pub timestamp: String,
+    pub price: String,
+    pub volume: Option<String>,
+    pub num_trades: Option<String>,
+    pub funding_rate: Option<String>,
+    pub open_interest: Option<String>,
+    pub oracle_price: Option<String>,
+}
+
+impl Default for PriceColumns {
+    fn default() -> Self {
+        Self {
+            id: None,
+            name: "name".to_string(),
+            token_name: "token_name".to_string(),
+            timestamp: "timestamp".to_string(),
+            price: "price".to_string(),
+            volume: Some("volume".to_string()),
+            num_trades: Some("num_trades".to_string()),
+            funding_rate: Some("funding_rate".to_string()),
+            open_interest: Some("open_interest".to_string()),
+            oracle_price: Some("oracle_price".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PriceImportOptions {
+    pub format: ImportFormat,
+    pub columns: PriceColumns,
+    /// Used instead of the name column, e.g. for a dump of one exchange.
+    pub name: Option<String>,
+    /// Used instead of the token column, e.g. for a dump of one token.
+    pub token_name: Option<String>,
+    /// Timestamps in the source are in milliseconds.
+    pub timestamp_in_millis: bool,
+    pub batch_size: usize,
+}
+
+impl Default for PriceImportOptions {
+    fn default() -> Self {
+        Self {
+            format: ImportFormat::Csv,
+            columns: PriceColumns::default(),
+            name: None,
+            token_name: None,
+            timestamp_in_millis: false,
+            batch_size: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    pub read: usize,
+    pub inserted: usize,
+    /// Rows whose (name, token_name, timestamp) was already stored or
+    /// appeared earlier in the source, or whose id was taken.
+    pub skipped: usize,
+}
+
+type Row = HashMap<String, String>;
+
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+fn parse_json_line(line: &str) -> Result<Row, DbError> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    let serde_json::Value::Object(object) = value else {
+        return Err(DbError::Serialization("line is not an object".into()));
+    };
+    Ok(object
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::Null => return None,
+                serde_json::Value::String(s) => s,
+                value => value.to_string(),
+            };
+            Some((key, value))
+        })
+        .collect())
+}
+
+fn parse_decimal(s: &str) -> Option<Decimal> {
+    Decimal::from_str(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .ok()
+}
+
+fn timestamp_str(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%dT%H:%M:%S%z").to_string())
+        .unwrap_or_default()
+}
+
+impl PriceImportOptions {
+    fn to_price(&self, row: &Row) -> Result<PriceLog, String> {
+        let columns = &self.columns;
+        let get = |column: &str| row.get(column).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let required = |column: &str| get(column).ok_or(format!("{} is missing", column));
+        let decimal = |column: &Option<String>| -> Result<Option<Decimal>, String> {
+            match column.as_deref().and_then(get) {
+                Some(v) => parse_decimal(v)
+                    .map(Some)
+                    .ok_or(format!("invalid number: {}", v)),
+                None => Ok(None),
+            }
+        };
+
+        let id = match &columns.id {
+            Some(column) => {
+                let id = required(column)?;
+                Some(id.parse().map_err(|_| format!("invalid id: {}", id))?)
+            }
+            None => None,
+        };
+        let timestamp = required(&columns.timestamp)?;
+        let timestamp = parse_decimal(timestamp)
+            .and_then(|v| i64::try_from(v.trunc()).ok())
+            .ok_or(format!("invalid timestamp: {}", timestamp))?;
+        let timestamp = if self.timestamp_in_millis {
+            timestamp.div_euclid(1000)
+        } else {
+            timestamp
+        };
+        let price = required(&columns.price)?;
+        let price = parse_decimal(price).ok_or(format!("invalid price: {}", price))?;
+        let num_trades = match columns.num_trades.as_deref().and_then(get) {
+            Some(v) => Some(
+                v.parse()
+                    .map_err(|_| format!("invalid number of trades: {}", v))?,
+            ),
+            None => None,
+        };
+
+        Ok(PriceLog {
+            id,
+            name: match &self.name {
+                Some(name) => name.clone(),
+                None => required(&columns.name)?.to_owned(),
+            },
+            token_name: match &self.token_name {
+                Some(token_name) => token_name.clone(),
+                None => required(&columns.token_name)?.to_owned(),
+            },
+            price_point: PricePoint {
+                timestamp,
+                timestamp_str: timestamp_str(timestamp),
+                price,
+                volume: decimal(&columns.volume)?,
+                num_trades,
+                funding_rate: decimal(&columns.funding_rate)?,
+                open_interest: decimal(&columns.open_interest)?,
+                oracle_price: decimal(&columns.oracle_price)?,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+type PriceKey = (String, String, i64);
+
+fn price_key(price: &PriceLog) -> PriceKey {
+    (
+        price.name.clone(),
+        price.token_name.clone(),
+        price.price_point.timestamp,
+    )
+}
+
+/// The keys of `prices` that are already stored, from one query over the
+/// time range of the batch.
+async fn stored_keys(
+    db: &dyn StorageBackend,
+    prices: &[PriceLog],
+) -> Result<HashSet<PriceKey>, DbError> {
+    let (Some(from), Some(to)) = (
+        prices.iter().map(|p| p.price_point.timestamp).min(),
+        prices.iter().map(|p| p.price_point.timestamp).max(),
+    ) else {
+        return Ok(HashSet::new());
+    };
+    let names: HashSet<_> = prices.iter().map(|p| p.name.as_str()).collect();
+    let token_names: HashSet<_> = prices.iter().map(|p| p.token_name.as_str()).collect();
+    let query = doc! {
+        "name": { "$in": names.into_iter().collect::<Vec<_>>() },
+        "token_name": { "$in": token_names.into_iter().collect::<Vec<_>>() },
+        "price_point.timestamp": { "$gte": from, "$lte": to },
+    };
+    let documents = match db
+        .search(
+            PriceLog::default().get_collection_name(),
+            query,
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+    {
+        Ok(documents) => documents,
+        Err(e) if e.is_not_found() => vec![],
+        Err(e) => return Err(e),
+    };
+    documents
+        .into_iter()
+        .map(|document| Ok(price_key(&bson::from_document(document)?)))
+        .collect()
+}
+
+struct Importer<'a> {
+    db: &'a dyn StorageBackend,
+    counter: Option<&'a Counter>,
+    summary: ImportSummary,
+}
+
+impl Importer<'_> {
+    async fn flush(&mut self, batch: Vec<PriceLog>) -> Result<(), DbError> {
+        // Earlier batches are stored by now, so only repeats within this
+        // batch need to be remembered.
+        let mut known = stored_keys(self.db, &batch).await?;
+        let read = batch.len();
+        let mut batch: Vec<_> = batch
+            .into_iter()
+            .filter(|price| known.insert(price_key(price)))
+            .collect();
+        let num = batch.len();
+        self.summary.skipped += read - num;
+        let missing = batch.iter().filter(|price| price.id.is_none()).count();
+        if missing > 0 {
+            let counter = self
+                .counter
+                .ok_or_else(|| DbError::InvalidQuery("No id column and no counter".to_string()))?;
+            let mut ids = counter
+                .allocate_many(self.db, CounterType::Price, missing)
+                .await?
+                .into_iter();
+            for price in batch.iter_mut().filter(|price| price.id.is_none()) {
+                price.id = ids.next();
+            }
+        }
+        let documents = batch
+            .iter()
+            .map(bson::to_document)
+            .collect::<Result<Vec<Document>, _>>()?;
+        // Only id collisions are left to skip.
+        let inserted = self
+            .db
+            .insert_many(PriceLog::default().get_collection_name(), documents)
+            .await?;
+        self.summary.inserted += inserted;
+        self.summary.skipped += num - inserted;
+        Ok(())
+    }
+}
+
+/// Imports prices from `reader` in batches. Rows already stored with the
+/// same (name, token_name, timestamp) are skipped, as are repeats within the
+/// source, so an interrupted import can simply be run again. Ids come from
+/// `columns.id` if it is set and from `counter` otherwise.
+///
+/// A row that cannot be read stops the import with an error; the batches
+/// before it stay stored.
+pub async fn import_prices<R: BufRead>(
+    db: &dyn StorageBackend,
+    reader: R,
+    options: &PriceImportOptions,
+    counter: Option<&Counter>,
+) -> Result<ImportSummary, DbError> {
+    let mut importer = Importer {
+        db,
+        counter,
+        summary: ImportSummary::default(),
+    };
+    let mut header: Option<Vec<String>> = None;
+    let mut batch = vec![];
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let row = match options.format {
+            ImportFormat::Csv => {
+                let fields = parse_csv_line(&line);
+                let Some(header) = &header else {
+                    header = Some(fields);
+                    continue;
+                };
+                header.iter().cloned().zip(fields).collect()
+            }
+            ImportFormat::JsonLines => parse_json_line(&line)?,
+        };
+        let price = options
+            .to_price(&row)
+            .map_err(|e| DbError::Serialization(format!("line {}: {}", number + 1, e).into()))?;
+        importer.summary.read += 1;
+        batch.push(price);
+        if batch.len() >= options.batch_size.max(1) {
+            importer.flush(std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        importer.flush(batch).await?;
+    }
+
+    log::debug!("import_prices: {:?}", importer.summary);
+    Ok(importer.summary)
+}
@@ -1,23 +1,24 @@
 use async_trait::async_trait;
-use bson::to_document;
-use bson::Document;
+use bson::{Bson, Document};
 use debot_utils::HasId;
-use futures::stream::TryStreamExt;
+use futures::stream::{self, Stream, TryStreamExt};
 use mongodb::bson::doc;
-use mongodb::options::*;
-use mongodb::Database;
-use mongodb::{Collection, IndexModel};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::error;
-use std::io::{Error, ErrorKind};
 
+use crate::backend::query::get_path;
+use crate::backend::{document_from_json, document_to_json};
+use crate::DbError;
+use crate::FeatureRegistry;
+use crate::PositionEvent;
 use crate::PositionLog;
+use crate::{IndexSpec, StorageBackend};
 
 use super::AppState;
 use super::PnlLog;
 use super::PriceLog;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SearchMode {
     Ascending,
     Descending,
@@ -26,239 +27,372 @@ pub enum SearchMode {
 
 #[async_trait]
 pub trait Entity {
-    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>>;
-    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>>;
-    async fn delete(&self, db: &Database) -> Result<(), Box<dyn error::Error>>;
-    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>>;
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError>;
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError>;
+    /// Overwrites the stored document with the same id as a whole, or inserts
+    /// it. Used when a capped counter hands out a recycled id.
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError>;
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError>;
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError>;
 
     async fn search(
         &self,
-        db: &Database,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
         sort_key: Option<&str>,
-    ) -> Result<Vec<Self>, Box<dyn error::Error>>
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError>
     where
         Self: std::marker::Sized;
 
     fn get_collection_name(&self) -> &str;
 
-    fn get_collection(&self, db: &Database) -> Collection<Self>
-    where
-        Self: std::marker::Sized,
-    {
-        db.collection::<Self>(self.get_collection_name())
-    }
-
-    async fn create_indexes(&self, db: &Database) -> Result<(), Box<dyn error::Error>>
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError>
     where
         Self: std::marker::Sized,
         Self: std::marker::Send;
 }
 
-pub async fn insert_item<T: Entity>(db: &Database, item: &T) -> Result<(), Box<dyn error::Error>> {
+pub async fn insert_item<T: Entity>(db: &dyn StorageBackend, item: &T) -> Result<(), DbError> {
     item.insert(db).await
 }
 
-pub async fn update_item<T: Entity>(db: &Database, item: &T) -> Result<(), Box<dyn error::Error>> {
+pub async fn update_item<T: Entity>(db: &dyn StorageBackend, item: &T) -> Result<(), DbError> {
     item.update(db).await
 }
 
+pub async fn replace_item<T: Entity>(db: &dyn StorageBackend, item: &T) -> Result<(), DbError> {
+    item.replace(db).await
+}
+
 #[allow(dead_code)]
-pub async fn delete_item<T: Entity>(db: &Database, item: &T) -> Result<(), Box<dyn error::Error>> {
+pub async fn delete_item<T: Entity>(db: &dyn StorageBackend, item: &T) -> Result<(), DbError> {
     item.delete(db).await
 }
 
 #[allow(dead_code)]
-pub async fn delete_item_all<T: Entity>(
-    db: &Database,
-    item: &T,
-) -> Result<(), Box<dyn error::Error>> {
+pub async fn delete_item_all<T: Entity>(db: &dyn StorageBackend, item: &T) -> Result<(), DbError> {
     item.delete_all(db).await
 }
 
 pub async fn search_items<T: Entity>(
-    db: &Database,
+    db: &dyn StorageBackend,
     item: &T,
     mode: SearchMode,
     limit: Option<u32>,
     id: Option<u32>,
     sort_key: Option<&str>,
-) -> Result<Vec<T>, Box<dyn error::Error>> {
-    item.search(db, mode, limit, id, sort_key).await
+    filter: Option<Document>,
+) -> Result<Vec<T>, DbError> {
+    item.search(db, mode, limit, id, sort_key, filter).await
 }
 
 pub async fn search_item<T: Entity>(
-    db: &Database,
+    db: &dyn StorageBackend,
     item: &T,
     id: Option<u32>,
     sort_key: Option<&str>,
-) -> Result<T, Box<dyn error::Error>> {
+) -> Result<T, DbError> {
     let mut items = item
-        .search(db, SearchMode::ById, None, id, sort_key)
+        .search(db, SearchMode::ById, None, id, sort_key, None)
         .await?;
     if items.len() == 1 {
         Ok(items.pop().unwrap())
     } else {
-        Err(Box::new(Error::new(
-            ErrorKind::Other,
-            "Multiple items are found".to_string(),
-        )))
+        Err(DbError::Ambiguous("Multiple items are found".to_string()))
     }
 }
 
-async fn ensure_collection_exists(collection: &Collection<Document>) {
-    let doc = doc! { "_id": bson::oid::ObjectId::new() };
-    let _ = collection.insert_one(doc, None).await.ok();
+/// Position after the last item of a page: the value of the sort key and the
+/// id, which breaks ties between items with the same sort value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageToken {
+    pub sort_key: String,
+    pub value: Bson,
+    pub id: u32,
 }
 
-async fn get_existing_indexes<T>(
-    collection: &Collection<T>,
-) -> Result<Vec<String>, Box<dyn error::Error>> {
-    let mut indexes = collection.list_indexes(None).await?;
-    let mut index_names = Vec::new();
+impl PageToken {
+    /// Encodes the token as a string that can be handed to a client and
+    /// passed back later to resume.
+    pub fn encode(&self) -> String {
+        document_to_json(&doc! {
+            "sort_key": &self.sort_key,
+            "value": self.value.clone(),
+            "id": self.id,
+        })
+    }
+
+    pub fn decode(token: &str) -> Result<Self, DbError> {
+        let document = document_from_json(token)?;
+        let invalid = || DbError::InvalidQuery(format!("Invalid page token: {}", token));
+        let sort_key = document.get_str("sort_key").map_err(|_| invalid())?;
+        let value = document.get("value").ok_or_else(invalid)?.clone();
+        let id = match document.get("id") {
+            Some(Bson::Int32(id)) => *id as u32,
+            Some(Bson::Int64(id)) => *id as u32,
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            sort_key: sort_key.to_owned(),
+            value,
+            id,
+        })
+    }
 
-    while let Some(index) = indexes.try_next().await? {
-        let document: Document = to_document(&index)?;
-        if let Some(name) = document.get_str("name").ok() {
-            index_names.push(name.to_string());
+    fn to_filter(&self, mode: SearchMode) -> Document {
+        let operator = match mode {
+            SearchMode::Descending => "$lt",
+            _ => "$gt",
+        };
+        if self.sort_key == "id" {
+            return doc! { "id": { operator: self.id } };
+        }
+        let ties = doc! { &self.sort_key: self.value.clone(), "id": { operator: self.id } };
+        // Ranges never match null, and items without a sort value come before
+        // every other item, so they are added or left out explicitly.
+        let branches = match (self.value == Bson::Null, mode) {
+            (true, SearchMode::Descending) => vec![ties],
+            (true, _) => vec![doc! { &self.sort_key: { "$ne": Bson::Null } }, ties],
+            (false, SearchMode::Descending) => vec![
+                doc! { &self.sort_key: { operator: self.value.clone() } },
+                ties,
+                doc! { &self.sort_key: Bson::Null },
+            ],
+            (false, _) => vec![
+                doc! { &self.sort_key: { operator: self.value.clone() } },
+                ties,
+            ],
+        };
+        doc! { "$or": branches }
+    }
+}
+
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, or `None` when this is the last page.
+    pub next: Option<PageToken>,
+}
+
+/// Returns up to `page_size` items after `after`, ordered by `sort_key` and
+/// then by id. Unlike an offset, the token stays valid when items are
+/// inserted or removed in between.
+#[allow(clippy::too_many_arguments)]
+pub async fn search_page<T: Entity + Serialize + HasId>(
+    db: &dyn StorageBackend,
+    item: &T,
+    mode: SearchMode,
+    page_size: u32,
+    sort_key: Option<&str>,
+    filter: Option<Document>,
+    after: Option<&PageToken>,
+) -> Result<Page<T>, DbError> {
+    if mode == SearchMode::ById {
+        return Err(DbError::InvalidQuery(
+            "Pagination needs a sort order".to_string(),
+        ));
+    }
+    let sort_key = sort_key.unwrap_or("id");
+
+    let mut conditions = vec![];
+    if let Some(filter) = filter {
+        conditions.push(filter);
+    }
+    if let Some(after) = after {
+        if after.sort_key != sort_key {
+            return Err(DbError::InvalidQuery(format!(
+                "Page token is for sort key {}",
+                after.sort_key
+            )));
+        }
+        conditions.push(after.to_filter(mode));
+    }
+    let filter = (!conditions.is_empty()).then(|| doc! { "$and": conditions });
+
+    let items = match search_items(
+        db,
+        item,
+        mode,
+        Some(page_size),
+        None,
+        Some(sort_key),
+        filter,
+    )
+    .await
+    {
+        Ok(items) => items,
+        Err(e) if e.is_not_found() => vec![],
+        Err(e) => return Err(e),
+    };
+
+    let next = match items.last() {
+        Some(last) if items.len() == page_size as usize => {
+            let document = bson::to_document(last)?;
+            Some(PageToken {
+                sort_key: sort_key.to_owned(),
+                value: get_path(&document, sort_key).cloned().unwrap_or(Bson::Null),
+                id: last.id().unwrap_or_default(),
+            })
+        }
+        _ => None,
+    };
+    Ok(Page { items, next })
+}
+
+/// Streams every matching item, fetching `page_size` items at a time so that
+/// memory use does not grow with the size of the collection.
+///
+/// Each page resumes after the `(sort_key, id)` of the last item returned, not
+/// from a snapshot. An item is therefore returned at most once, and every
+/// item present for the whole scan is returned exactly once as long as its
+/// sort value does not change. Items inserted, removed or re-sorted while the
+/// stream runs may or may not be seen.
+pub fn search_stream<'a, T: Entity + Serialize + HasId + 'a>(
+    db: &'a dyn StorageBackend,
+    item: &'a T,
+    mode: SearchMode,
+    page_size: u32,
+    sort_key: Option<&'a str>,
+    filter: Option<Document>,
+) -> impl Stream<Item = Result<T, DbError>> + 'a {
+    // `None` once the last page has been fetched.
+    let start: Option<Option<PageToken>> = Some(None);
+    stream::try_unfold(start, move |state| {
+        let filter = filter.clone();
+        async move {
+            let Some(after) = state else {
+                return Ok(None);
+            };
+            search_page(db, item, mode, page_size, sort_key, filter, after.as_ref())
+                .await
+                .map(|page| Some((page.items, page.next.map(Some))))
         }
+    })
+    .map_ok(|items| stream::iter(items.into_iter().map(Ok)))
+    .try_flatten()
+}
+
+fn id_query(id: Option<u32>) -> Result<Document, DbError> {
+    match id {
+        Some(id) => Ok(doc! { "id": id }),
+        None => Err(DbError::InvalidQuery("ID not provided".to_string())),
     }
+}
 
-    log::debug!("Existing indexes: {:?}", index_names);
-    Ok(index_names)
+fn from_documents<T: DeserializeOwned>(documents: Vec<Document>) -> Result<Vec<T>, DbError> {
+    documents
+        .into_iter()
+        .map(|document| bson::from_document(document).map_err(DbError::from))
+        .collect()
 }
-pub async fn create_unique_index(db: &Database) -> Result<(), Box<dyn error::Error>> {
-    async fn create_index<T: Entity>(
-        db: &Database,
-        entity: &T,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let collection: Collection<Document> =
-            db.collection::<Document>(entity.get_collection_name());
-        ensure_collection_exists(&collection).await;
-        let existing_indexes = get_existing_indexes(&collection).await?;
 
+pub async fn create_unique_index(db: &dyn StorageBackend) -> Result<(), DbError> {
+    async fn create_index<T: Entity>(db: &dyn StorageBackend, entity: &T) -> Result<(), DbError> {
         let indexes = vec![
-            (
-                "id_1",
-                IndexModel::builder()
-                    .keys(doc! {"id": 1})
-                    .options(IndexOptions::builder().unique(true).build())
-                    .build(),
-            ),
-            (
-                "open_timestamp_1",
-                IndexModel::builder()
-                    .keys(doc! {"open_timestamp": 1})
-                    .build(),
-            ),
-            (
-                "open_timestamp_-1",
-                IndexModel::builder()
-                    .keys(doc! {"open_timestamp": -1})
-                    .build(),
-            ),
-            (
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new("open_timestamp_1", doc! {"open_timestamp": 1}, false),
+            IndexSpec::new("open_timestamp_-1", doc! {"open_timestamp": -1}, false),
+            IndexSpec::new("close_timestamp_1", doc! {"close_timestamp": 1}, false),
+            IndexSpec::new(
                 "price_point.timestamp_1",
-                IndexModel::builder()
-                    .keys(doc! {"price_point.timestamp": 1})
-                    .build(),
+                doc! {"price_point.timestamp": 1},
+                false,
             ),
-            (
+            IndexSpec::new(
                 "price_point.timestamp_-1",
-                IndexModel::builder()
-                    .keys(doc! {"price_point.timestamp": -1})
-                    .build(),
+                doc! {"price_point.timestamp": -1},
+                false,
             ),
         ];
 
-        for (index_name, index_model) in indexes {
-            if !existing_indexes.contains(&index_name.to_string()) {
-                log::info!("Creating index `{}`...", index_name);
-                collection.create_index(index_model, None).await?;
-                log::info!("Index `{}` has been created successfully!", index_name);
-            } else {
-                log::debug!("Index `{}` already exists, skipping.", index_name);
-            }
-        }
-
-        Ok(())
+        db.create_indexes(entity.get_collection_name(), indexes)
+            .await
     }
 
     create_index(db, &PositionLog::default()).await?;
+    // Indexes for the fields of `PositionFilter`.
+    PositionLog::default().create_indexes(db).await?;
     create_index(db, &AppState::default()).await?;
+    AppState::default().create_indexes(db).await?;
     create_index(db, &PriceLog::default()).await?;
+    PriceLog::default().create_indexes(db).await?;
     create_index(db, &PnlLog::default()).await?;
+    PnlLog::default().create_indexes(db).await?;
+    PositionEvent::default().create_indexes(db).await?;
+    FeatureRegistry::create_indexes(db).await?;
 
     Ok(())
 }
 
 #[async_trait]
 impl Entity for PositionLog {
-    async fn create_indexes(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-
-        let id_index = IndexModel::builder()
-            .keys(doc! {"id": 1})
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-
-        let open_timestamp_index = IndexModel::builder()
-            .keys(doc! {"open_timestamp": 1})
-            .build();
-
-        let open_timestamp_index_2 = IndexModel::builder()
-            .keys(doc! {"open_timestamp": -1})
-            .build();
-
-        collection.create_index(id_index, None).await?;
-        collection.create_index(open_timestamp_index, None).await?;
-        collection
-            .create_index(open_timestamp_index_2, None)
-            .await?;
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let indexes = vec![
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new("open_timestamp_1", doc! {"open_timestamp": 1}, false),
+            IndexSpec::new("open_timestamp_-1", doc! {"open_timestamp": -1}, false),
+            IndexSpec::new("close_timestamp_1", doc! {"close_timestamp": 1}, false),
+            IndexSpec::new("fund_name_1", doc! {"fund_name": 1}, false),
+            IndexSpec::new("token_name_1", doc! {"token_name": 1}, false),
+            IndexSpec::new("state_1", doc! {"state": 1}, false),
+            IndexSpec::new("position_type_1", doc! {"position_type": 1}, false),
+        ];
 
-        Ok(())
+        db.create_indexes(self.get_collection_name(), indexes).await
     }
 
-    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-        collection.insert_one(self, None).await?;
-        Ok(())
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let document = bson::to_document(self)?;
+        db.insert_one(self.get_collection_name(), document).await
     }
 
-    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let query = doc! { "id": self.id() };
-        let update = bson::to_bson(self).unwrap();
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id())?;
+        let update = bson::to_bson(self)?;
         let update = doc! { "$set" : update };
-        let collection = self.get_collection(db);
-        collection.update(query, update, true).await
+        db.update_one(self.get_collection_name(), query, update, true)
+            .await
     }
 
-    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id())?;
+        let document = bson::to_document(self)?;
+        db.replace_one(self.get_collection_name(), query, document, true)
+            .await
     }
 
-    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-        collection.delete_all().await
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id())?;
+        db.delete_one(self.get_collection_name(), query).await
+    }
+
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        db.delete_all(self.get_collection_name()).await
     }
 
     async fn search(
         &self,
-        db: &Database,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
         sort_key: Option<&str>,
-    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError> {
         let mut query = doc! { "id": { "$gt": 0 }};
-        if self.id() != None {
-            query = doc! { "id": self.id().unwrap() };
+        if let Some(id) = self.id() {
+            query = doc! { "id": id };
+        }
+        if let Some(filter) = filter {
+            query.extend(filter);
         }
-        let collection = self.get_collection(db);
         let sort_key = sort_key.unwrap_or("id");
-        collection.search(query, mode, limit, id, &sort_key).await
+        let documents = db
+            .search(self.get_collection_name(), query, mode, limit, id, sort_key)
+            .await?;
+        from_documents(documents)
     }
 
     fn get_collection_name(&self) -> &str {
@@ -268,52 +402,65 @@ impl Entity for PositionLog {
 
 #[async_trait]
 impl Entity for PnlLog {
-    async fn create_indexes(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-
-        let id_index = IndexModel::builder()
-            .keys(doc! {"id": 1})
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let indexes = vec![
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new("timestamp_1", doc! {"timestamp": 1}, false),
+        ];
 
-        collection.create_index(id_index, None).await?;
+        db.create_indexes(self.get_collection_name(), indexes).await
+    }
 
-        Ok(())
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let document = bson::to_document(self)?;
+        db.insert_one(self.get_collection_name(), document).await
     }
 
-    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-        collection.insert_one(self, None).await?;
-        Ok(())
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let update = bson::to_bson(self)?;
+        let update = doc! { "$set" : update };
+        db.update_one(self.get_collection_name(), query, update, true)
+            .await
     }
 
-    async fn update(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let document = bson::to_document(self)?;
+        db.replace_one(self.get_collection_name(), query, document, true)
+            .await
     }
 
-    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        db.delete_one(self.get_collection_name(), query).await
     }
 
-    async fn delete_all(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        db.delete_all(self.get_collection_name()).await
     }
 
     async fn search(
         &self,
-        db: &Database,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
         sort_key: Option<&str>,
-    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError> {
         let mut query = doc! { "id": { "$gt": 0 }};
-        if self.id != None {
-            query = doc! { "id": self.id.unwrap() };
+        if let Some(id) = self.id {
+            query = doc! { "id": id };
+        }
+        if let Some(filter) = filter {
+            query.extend(filter);
         }
-        let collection = self.get_collection(db);
         let sort_key = sort_key.unwrap_or("id");
-        collection.search(query, mode, limit, id, &sort_key).await
+        let documents = db
+            .search(self.get_collection_name(), query, mode, limit, id, sort_key)
+            .await?;
+        from_documents(documents)
     }
 
     fn get_collection_name(&self) -> &str {
@@ -323,52 +470,65 @@ impl Entity for PnlLog {
 
 #[async_trait]
 impl Entity for AppState {
-    async fn create_indexes(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-
-        let id_index = IndexModel::builder()
-            .keys(doc! {"id": 1})
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-
-        collection.create_index(id_index, None).await?;
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        // The default state has no key, and neither do states stored before
+        // keys existed.
+        let indexes = vec![
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new("instance_key_1", doc! {"instance_key": 1}, true)
+                .partial_filter(doc! {"instance_key": {"$exists": true}}),
+        ];
 
-        Ok(())
+        db.create_indexes(self.get_collection_name(), indexes).await
     }
 
-    async fn insert(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let document = bson::to_document(self)?;
+        db.insert_one(self.get_collection_name(), document).await
     }
 
-    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let query = doc! { "id": 1 };
-        let update = bson::to_bson(self).unwrap();
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = self.query();
+        let update = bson::to_bson(self)?;
         let update = doc! { "$set" : update };
-        let collection = self.get_collection(db);
-        collection.update(query, update, true).await
+        db.update_one(self.get_collection_name(), query, update, true)
+            .await
+    }
+
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = self.query();
+        let document = bson::to_document(self)?;
+        db.replace_one(self.get_collection_name(), query, document, true)
+            .await
     }
 
-    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = self.query();
+        db.delete_one(self.get_collection_name(), query).await
     }
 
-    async fn delete_all(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-        collection.delete_all().await
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        db.delete_all(self.get_collection_name()).await
     }
 
     async fn search(
         &self,
-        db: &Database,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
         sort_key: Option<&str>,
-    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
-        let query = doc! { "id": 1 };
-        let collection = self.get_collection(db);
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError> {
+        let mut query = doc! { "id": { "$gt": 0 }};
+        if let Some(filter) = filter {
+            query.extend(filter);
+        }
         let sort_key = sort_key.unwrap_or("id");
-        collection.search(query, mode, limit, id, &sort_key).await
+        let documents = db
+            .search(self.get_collection_name(), query, mode, limit, id, sort_key)
+            .await?;
+        from_documents(documents)
     }
 
     fn get_collection_name(&self) -> &str {
@@ -378,70 +538,80 @@ impl Entity for AppState {
 
 #[async_trait]
 impl Entity for PriceLog {
-    async fn create_indexes(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-
-        let id_index = IndexModel::builder()
-            .keys(doc! {"id": 1})
-            .options(IndexOptions::builder().unique(true).build())
-            .build();
-
-        let price_point_timestamp_index = IndexModel::builder()
-            .keys(doc! {"price_point.timestamp": 1})
-            .build();
-
-        let price_point_timestamp_index_2 = IndexModel::builder()
-            .keys(doc! {"price_point.timestamp": -1})
-            .build();
-
-        collection.create_index(id_index, None).await?;
-        collection
-            .create_index(price_point_timestamp_index, None)
-            .await?;
-        collection
-            .create_index(price_point_timestamp_index_2, None)
-            .await?;
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let indexes = vec![
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new(
+                "price_point.timestamp_1",
+                doc! {"price_point.timestamp": 1},
+                false,
+            ),
+            IndexSpec::new(
+                "price_point.timestamp_-1",
+                doc! {"price_point.timestamp": -1},
+                false,
+            ),
+            // Not unique: live ticks may share a second.
+            IndexSpec::new(
+                "name_1_token_name_1_price_point.timestamp_1",
+                doc! {"name": 1, "token_name": 1, "price_point.timestamp": 1},
+                false,
+            ),
+        ];
 
-        Ok(())
+        db.create_indexes(self.get_collection_name(), indexes).await
     }
 
-    async fn insert(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let collection = self.get_collection(db);
-        collection.insert_one(self, None).await?;
-        Ok(())
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let document = bson::to_document(self)?;
+        db.insert_one(self.get_collection_name(), document).await
     }
 
-    async fn update(&self, db: &Database) -> Result<(), Box<dyn error::Error>> {
-        let query = doc! { "id": self.id };
-        let update = bson::to_bson(self).unwrap();
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let update = bson::to_bson(self)?;
         let update = doc! { "$set" : update };
-        let collection = self.get_collection(db);
-        collection.update(query, update, true).await
+        db.update_one(self.get_collection_name(), query, update, true)
+            .await
     }
 
-    async fn delete(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let document = bson::to_document(self)?;
+        db.replace_one(self.get_collection_name(), query, document, true)
+            .await
     }
 
-    async fn delete_all(&self, _db: &Database) -> Result<(), Box<dyn error::Error>> {
-        panic!("Not implemented")
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        db.delete_one(self.get_collection_name(), query).await
+    }
+
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        db.delete_all(self.get_collection_name()).await
     }
 
     async fn search(
         &self,
-        db: &Database,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
         sort_key: Option<&str>,
-    ) -> Result<Vec<Self>, Box<dyn error::Error>> {
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError> {
         let mut query = doc! { "id": { "$gt": 0 }};
-        if self.id != None {
-            query = doc! { "id": self.id.unwrap() };
+        if let Some(id) = self.id {
+            query = doc! { "id": id };
+        }
+        if let Some(filter) = filter {
+            query.extend(filter);
         }
-        let collection = self.get_collection(db);
         let sort_key = sort_key.unwrap_or("id");
-        collection.search(query, mode, limit, id, &sort_key).await
+        let documents = db
+            .search(self.get_collection_name(), query, mode, limit, id, sort_key)
+            .await?;
+        from_documents(documents)
     }
 
     fn get_collection_name(&self) -> &str {
@@ -450,127 +620,70 @@ impl Entity for PriceLog {
 }
 
 #[async_trait]
-pub trait HelperCollection<T> {
-    async fn update(
-        &self,
-        query: Document,
-        update: Document,
-        upsert: bool,
-    ) -> Result<(), Box<dyn error::Error>>;
-    async fn delete(&self, query: Document) -> Result<(), Box<dyn error::Error>>;
-    async fn delete_all(&self) -> Result<(), Box<dyn error::Error>>;
-    async fn search(
-        &self,
-        query: Document,
-        mode: SearchMode,
-        limit: Option<u32>,
-        id: Option<u32>,
-        sort_key: &str,
-    ) -> Result<Vec<T>, Box<dyn error::Error>>;
-}
+impl Entity for PositionEvent {
+    async fn create_indexes(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let indexes = vec![
+            IndexSpec::new("id_1", doc! {"id": 1}, true),
+            IndexSpec::new("position_id_1", doc! {"position_id": 1}, false),
+            IndexSpec::new("timestamp_1", doc! {"timestamp": 1}, false),
+        ];
 
-#[async_trait]
-impl<T> HelperCollection<T> for Collection<T>
-where
-    T: DeserializeOwned + Unpin + Send + Sync + Serialize + std::fmt::Debug,
-{
-    async fn update(
-        &self,
-        query: Document,
-        update: Document,
-        upsert: bool,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let options = FindOneAndUpdateOptions::builder()
-            .upsert(upsert)
-            .return_document(ReturnDocument::After)
-            .build();
-        let _ = self.find_one_and_update(query, update, options).await?;
-        Ok(())
-    }
-
-    async fn delete(&self, query: Document) -> Result<(), Box<dyn error::Error>> {
-        let result = self.delete_one(query, None).await?;
-        if result.deleted_count == 1 {
-            return Ok(());
-        } else {
-            panic!("Not implemented")
-        }
+        db.create_indexes(self.get_collection_name(), indexes).await
+    }
+
+    async fn insert(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let document = bson::to_document(self)?;
+        db.insert_one(self.get_collection_name(), document).await
+    }
+
+    async fn update(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let update = bson::to_bson(self)?;
+        let update = doc! { "$set" : update };
+        db.update_one(self.get_collection_name(), query, update, true)
+            .await
     }
 
-    async fn delete_all(&self) -> Result<(), Box<dyn error::Error>> {
-        let options = DropCollectionOptions::builder().build();
-        self.drop(options).await?;
-        Ok(())
+    async fn replace(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        let document = bson::to_document(self)?;
+        db.replace_one(self.get_collection_name(), query, document, true)
+            .await
+    }
+
+    async fn delete(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        let query = id_query(self.id)?;
+        db.delete_one(self.get_collection_name(), query).await
+    }
+
+    async fn delete_all(&self, db: &dyn StorageBackend) -> Result<(), DbError> {
+        db.delete_all(self.get_collection_name()).await
     }
 
     async fn search(
         &self,
-        mut query: Document,
+        db: &dyn StorageBackend,
         mode: SearchMode,
         limit: Option<u32>,
         id: Option<u32>,
-        sort_key: &str,
-    ) -> Result<Vec<T>, Box<dyn error::Error>> {
-        let mut items: Vec<T> = vec![];
-
-        match sort_key {
-            "id" | "open_timestamp" | "price_point.timestamp" => {}
-            _ => {
-                return Err(Box::new(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Invalid sort key",
-                )))
-            }
-        };
-
-        let find_options = match mode {
-            SearchMode::Ascending => {
-                let builder = FindOptions::builder()
-                    .allow_disk_use(Some(true))
-                    .sort(doc! { sort_key: 1 });
-
-                if let Some(limit_value) = limit {
-                    builder.limit(limit_value as i64).build()
-                } else {
-                    builder.build()
-                }
-            }
-            SearchMode::Descending => {
-                let builder = FindOptions::builder()
-                    .allow_disk_use(Some(true))
-                    .sort(doc! { sort_key: -1 });
-
-                if let Some(limit_value) = limit {
-                    builder.limit(limit_value as i64).build()
-                } else {
-                    builder.build()
-                }
-            }
-            SearchMode::ById => {
-                if let Some(id_value) = id {
-                    query.insert("id", id_value);
-                } else {
-                    return Err(Box::new(Error::new(
-                        ErrorKind::InvalidInput,
-                        "ID not provided".to_string(),
-                    )));
-                }
-                FindOptions::builder().allow_disk_use(Some(true)).build()
-            }
-        };
-
-        let mut cursor = self.find(query, find_options).await?;
-        while let Some(item) = cursor.try_next().await? {
-            items.push(item);
+        sort_key: Option<&str>,
+        filter: Option<Document>,
+    ) -> Result<Vec<Self>, DbError> {
+        let mut query = doc! { "id": { "$gt": 0 }};
+        if let Some(id) = self.id {
+            query = doc! { "id": id };
         }
-
-        if items.is_empty() {
-            Err(Box::new(Error::new(
-                ErrorKind::Other,
-                "Item not found".to_string(),
-            )))
-        } else {
-            Ok(items)
+        if let Some(filter) = filter {
+            query.extend(filter);
         }
+        let sort_key = sort_key.unwrap_or("id");
+        let documents = db
+            .search(self.get_collection_name(), query, mode, limit, id, sort_key)
+            .await?;
+        from_documents(documents)
+    }
+
+    fn get_collection_name(&self) -> &str {
+        "position_events"
     }
 }
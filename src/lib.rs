@@ -1,10 +1,31 @@
+mod analytics;
+mod backend;
+mod candle;
 mod counter;
+mod dataset;
+mod decimal;
+mod error;
+mod export;
+mod feature;
+mod import;
 mod item;
+mod pattern;
 mod trading_strategy;
 mod transaction_log;
 
+pub use analytics::*;
+pub use backend::*;
+pub use candle::*;
 pub use counter::Counter;
+pub use counter::CounterMode;
 pub use counter::CounterType;
+pub use dataset::*;
+pub use error::retry_on_conflict;
+pub use error::DbError;
+pub use export::*;
+pub use feature::*;
+pub use import::*;
 pub use item::*;
+pub use pattern::*;
 pub use trading_strategy::*;
 pub use transaction_log::*;
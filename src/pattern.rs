@@ -0,0 +1,214 @@
+// pattern.rs
+
+use rust_decimal::Decimal;
+
+use crate::Candle;
+use crate::CandlePattern;
+
+/// Ratios used to classify candles. "Range" is high - low and "body" is
+/// |close - open|; shadow ratios are relative to the body unless noted.
+#[derive(Clone, Debug)]
+pub struct PatternThresholds {
+    /// A doji's body is at most this share of its range.
+    pub doji_body: Decimal,
+    /// Small bodies (hammers, spinning tops, stars) are at most this share
+    /// of the range.
+    pub small_body: Decimal,
+    /// Long bodies (engulfed candles, soldiers, crows) are at least this
+    /// share of the range.
+    pub long_body: Decimal,
+    /// A hammer's long shadow is at least this many times its body.
+    pub long_shadow: Decimal,
+    /// A shadow is negligible when it is at most this share of the range.
+    pub short_shadow: Decimal,
+    /// A marubozu has both shadows at most this share of the range.
+    pub marubozu_shadow: Decimal,
+}
+
+impl Default for PatternThresholds {
+    fn default() -> Self {
+        Self {
+            doji_body: Decimal::new(1, 1),
+            small_body: Decimal::new(3, 1),
+            long_body: Decimal::new(6, 1),
+            long_shadow: Decimal::TWO,
+            short_shadow: Decimal::new(1, 1),
+            marubozu_shadow: Decimal::new(5, 2),
+        }
+    }
+}
+
+fn body(c: &Candle) -> Decimal {
+    (c.close - c.open).abs()
+}
+
+fn range(c: &Candle) -> Decimal {
+    c.high - c.low
+}
+
+fn upper_shadow(c: &Candle) -> Decimal {
+    c.high - c.open.max(c.close)
+}
+
+fn lower_shadow(c: &Candle) -> Decimal {
+    c.open.min(c.close) - c.low
+}
+
+fn body_top(c: &Candle) -> Decimal {
+    c.open.max(c.close)
+}
+
+fn body_bottom(c: &Candle) -> Decimal {
+    c.open.min(c.close)
+}
+
+fn midpoint(c: &Candle) -> Decimal {
+    (c.open + c.close) / Decimal::TWO
+}
+
+fn is_bullish(c: &Candle) -> bool {
+    c.close > c.open
+}
+
+fn is_bearish(c: &Candle) -> bool {
+    c.close < c.open
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PatternDetector {
+    pub thresholds: PatternThresholds,
+}
+
+impl PatternDetector {
+    pub fn new(thresholds: PatternThresholds) -> Self {
+        Self { thresholds }
+    }
+
+    /// Returns every pattern that ends with the last of `candles` (oldest
+    /// first), three-candle patterns before two- and single-candle ones.
+    pub fn detect(&self, candles: &[Candle]) -> Vec<CandlePattern> {
+        let mut patterns = vec![];
+        if let [.., a, b, c] = candles {
+            self.detect_three(a, b, c, &mut patterns);
+        }
+        if let [.., a, b] = candles {
+            self.detect_two(a, b, &mut patterns);
+        }
+        if let [.., c] = candles {
+            self.detect_one(c, &mut patterns);
+        }
+        patterns
+    }
+
+    /// The most specific pattern ending with the last candle, or
+    /// `CandlePattern::None`. Suitable for the pattern inputs of `DebugLog`.
+    pub fn detect_primary(&self, candles: &[Candle]) -> CandlePattern {
+        self.detect(candles)
+            .into_iter()
+            .next()
+            .unwrap_or(CandlePattern::None)
+    }
+
+    fn is_doji(&self, c: &Candle) -> bool {
+        range(c) > Decimal::ZERO && body(c) <= self.thresholds.doji_body * range(c)
+    }
+
+    fn is_small(&self, c: &Candle) -> bool {
+        body(c) <= self.thresholds.small_body * range(c)
+    }
+
+    fn is_long(&self, c: &Candle) -> bool {
+        range(c) > Decimal::ZERO && body(c) >= self.thresholds.long_body * range(c)
+    }
+
+    fn detect_one(&self, c: &Candle, patterns: &mut Vec<CandlePattern>) {
+        let t = &self.thresholds;
+        let range = range(c);
+        if range <= Decimal::ZERO {
+            return;
+        }
+        let (body, upper, lower) = (body(c), upper_shadow(c), lower_shadow(c));
+        let short = t.short_shadow * range;
+
+        if self.is_doji(c) {
+            patterns.push(CandlePattern::Doji);
+        } else if self.is_small(c) {
+            if lower >= t.long_shadow * body && upper <= short {
+                patterns.push(CandlePattern::Hammer);
+            } else if upper >= t.long_shadow * body && lower <= short {
+                patterns.push(CandlePattern::InvertedHammer);
+            } else if upper >= body && lower >= body {
+                patterns.push(CandlePattern::SpinningTop);
+            }
+        }
+        if upper <= t.marubozu_shadow * range && lower <= t.marubozu_shadow * range {
+            patterns.push(CandlePattern::Marubozu);
+        }
+    }
+
+    fn detect_two(&self, a: &Candle, b: &Candle, patterns: &mut Vec<CandlePattern>) {
+        // The second body covers the first one and is larger.
+        let engulfs =
+            body(b) > body(a) && body_top(b) >= body_top(a) && body_bottom(b) <= body_bottom(a);
+        if engulfs && is_bearish(a) && is_bullish(b) {
+            patterns.push(CandlePattern::BullishEngulfing);
+        }
+        if engulfs && is_bullish(a) && is_bearish(b) {
+            patterns.push(CandlePattern::BearishEngulfing);
+        }
+
+        if self.is_long(a) {
+            if is_bearish(a)
+                && is_bullish(b)
+                && b.open < a.close
+                && b.close > midpoint(a)
+                && b.close < a.open
+            {
+                patterns.push(CandlePattern::PiercingPattern);
+            }
+            if is_bullish(a)
+                && is_bearish(b)
+                && b.open > a.close
+                && b.close < midpoint(a)
+                && b.close > a.open
+            {
+                patterns.push(CandlePattern::DarkCloudCover);
+            }
+
+            let inside = body_top(b) < body_top(a) && body_bottom(b) > body_bottom(a);
+            if inside {
+                if self.is_doji(b) {
+                    patterns.push(CandlePattern::HaramiCross);
+                } else if is_bullish(a) != is_bullish(b) {
+                    patterns.push(CandlePattern::Harami);
+                }
+            }
+        }
+    }
+
+    fn detect_three(&self, a: &Candle, b: &Candle, c: &Candle, patterns: &mut Vec<CandlePattern>) {
+        if self.is_long(a) && self.is_small(b) {
+            if is_bearish(a) && body_top(b) < a.close && is_bullish(c) && c.close > midpoint(a) {
+                patterns.push(CandlePattern::MorningStar);
+            }
+            if is_bullish(a) && body_bottom(b) > a.close && is_bearish(c) && c.close < midpoint(a) {
+                patterns.push(CandlePattern::EveningStar);
+            }
+        }
+
+        let candles = [a, b, c];
+        if candles.iter().all(|x| self.is_long(x)) {
+            let steps = || candles.windows(2).map(|w| (w[0], w[1]));
+            if candles.iter().all(|x| is_bullish(x))
+                && steps().all(|(p, n)| n.close > p.close && n.open >= p.open && n.open <= p.close)
+            {
+                patterns.push(CandlePattern::ThreeWhiteSoldiers);
+            }
+            if candles.iter().all(|x| is_bearish(x))
+                && steps().all(|(p, n)| n.close < p.close && n.open <= p.open && n.open >= p.close)
+            {
+                patterns.push(CandlePattern::ThreeBlackCrows);
+            }
+        }
+    }
+}
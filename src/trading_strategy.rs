@@ -7,6 +7,7 @@ pub enum TrendType {
     Any,
 }
 
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Serialize, Deserialize)]
 pub enum TradingStrategy {
     MarketMake,
@@ -20,8 +21,11 @@ pub enum TradingStrategy {
 }
 
 impl TradingStrategy {
-    pub fn is_market_make(&self) -> bool  {
-        matches!(self, TradingStrategy::MarketMake | TradingStrategy::RandomMarketMake)
+    pub fn is_market_make(&self) -> bool {
+        matches!(
+            self,
+            TradingStrategy::MarketMake | TradingStrategy::RandomMarketMake
+        )
     }
 
     pub fn trend_type(&self) -> &TrendType {
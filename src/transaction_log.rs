@@ -5,32 +5,39 @@ use bson::Bson;
 use bson::Document;
 use debot_utils::get_local_time;
 use debot_utils::HasId;
-use mongodb::Collection;
-use mongodb::{
-    options::{ClientOptions, Tls, TlsOptions},
-    Database,
-};
+use futures::stream::{StreamExt, TryStreamExt};
+use mongodb::options::{ClientOptions, Tls, TlsOptions};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use shared_mongodb::{database, ClientHolder};
 use std::collections::HashMap;
-use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::Mutex;
 
+use crate::counter::next_shared_sequence;
+use crate::delete_item;
 use crate::delete_item_all;
+use crate::retry_on_conflict;
+use crate::DbError;
+use crate::FeatureVector;
+use crate::MemoryBackend;
 use crate::SearchMode;
+use crate::StorageBackend;
 use crate::TradingStrategy;
+use crate::{build_candles, Candle, CandleInterval};
 use crate::{
-    create_unique_index, insert_item, search_item, search_items, update_item, Counter, CounterType,
-    Entity,
+    create_unique_index, insert_item, replace_item, search_item, search_items, search_stream,
+    update_item, Counter, CounterMode, CounterType, Entity,
 };
+use crate::{import_prices, ImportSummary, PriceImportOptions};
 
-async fn get_last_id<T: Default + Entity + HasId>(db: &Database) -> u32 {
+async fn get_last_id<T: Default + Entity + HasId>(db: &dyn StorageBackend) -> u32 {
     let item = T::default();
     match search_items(
         db,
@@ -39,6 +46,7 @@ async fn get_last_id<T: Default + Entity + HasId>(db: &Database) -> u32 {
         Some(1),
         None,
         Some("id"),
+        None,
     )
     .await
     {
@@ -50,6 +58,61 @@ async fn get_last_id<T: Default + Entity + HasId>(db: &Database) -> u32 {
     }
 }
 
+const COPY_PAGE_SIZE: u32 = 1000;
+const POSITION_EVENT_COUNTER: &str = "position_event";
+
+/// Adds the condition that the stored `version` is `version`. Documents
+/// written before versions were kept have none, which counts as zero.
+fn with_version(mut query: Document, version: u64) -> Document {
+    if version == 0 {
+        query.insert("version", doc! { "$in": [0_i64, Bson::Null] });
+    } else {
+        query.insert("version", version as i64);
+    }
+    query
+}
+
+/// Copies the items of `item`'s collection in id order, one page at a time.
+async fn copy_items<T: Entity + Serialize + HasId>(
+    db_r: &dyn StorageBackend,
+    db_w: &dyn StorageBackend,
+    item: &T,
+    limit: Option<u32>,
+    name: &str,
+) {
+    let page_size = limit.map_or(COPY_PAGE_SIZE, |limit| limit.min(COPY_PAGE_SIZE));
+    let items = search_stream(
+        db_r,
+        item,
+        SearchMode::Ascending,
+        page_size,
+        Some("id"),
+        None,
+    );
+    let items = match limit {
+        Some(limit) => items.take(limit as usize).left_stream(),
+        None => items.right_stream(),
+    };
+    futures::pin_mut!(items);
+
+    let mut num = 0;
+    while let Some(item) = items.next().await {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                log::error!("get {}: {:?}", name, e);
+                return;
+            }
+        };
+        if let Err(e) = insert_item(db_w, &item).await {
+            log::error!("write {}: {:?}", name, e);
+            return;
+        }
+        num += 1;
+    }
+    log::debug!("copy {}: num = {}", name, num);
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum SampleTerm {
     TradingTerm,
@@ -83,28 +146,57 @@ pub struct FundConfig {
     pub bias_ticks: i64,
 }
 
+/// The numbers are stored as Decimal128 so that `AppStatePatch` can add to
+/// them in the database without losing precision; states stored with
+/// decimal strings or doubles still load and are rewritten by
+/// `migrate_app_state`.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct AppState {
     pub id: u32,
+    /// Bot or fund the state belongs to. `None` for the default state,
+    /// which always has `id` 1, so that a database holding a single state
+    /// is used as it is. Keyed states get an id when they are first stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance_key: Option<String>,
+    /// Incremented by every update. See `update_app_state_if_unchanged`.
+    #[serde(default)]
+    pub version: u64,
     pub last_execution_time: Option<SystemTime>,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub last_equity: Option<Decimal>,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub ave_dd: Option<Decimal>,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub max_dd: Option<Decimal>,
+    #[serde(with = "crate::decimal::decimal128")]
     pub cumulative_return: Decimal,
+    #[serde(with = "crate::decimal::decimal128")]
     pub cumulative_dd: Decimal,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub score: Option<Decimal>,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub score_2: Option<Decimal>,
+    #[serde(default, with = "crate::decimal::decimal128_option")]
     pub score_3: Option<Decimal>,
     pub curcuit_break: bool,
     pub error_time: Vec<String>,
+    #[serde(with = "crate::decimal::decimal128")]
     pub max_invested_amount: Decimal,
     pub fund_configs: Option<Vec<FundConfig>>,
 }
 
+impl HasId for AppState {
+    fn id(&self) -> Option<u32> {
+        Some(self.id)
+    }
+}
+
 impl Default for AppState {
     fn default() -> Self {
         Self {
             id: 1,
+            instance_key: None,
+            version: 0,
             last_execution_time: None,
             last_equity: None,
             ave_dd: None,
@@ -122,11 +214,290 @@ impl Default for AppState {
     }
 }
 
+impl AppState {
+    /// Defaults for the state of `instance_key`.
+    pub fn for_instance(instance_key: &str) -> Self {
+        Self {
+            id: 0,
+            instance_key: Some(instance_key.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn instance_query(instance_key: Option<&str>) -> Document {
+        match instance_key {
+            Some(instance_key) => doc! { "instance_key": instance_key },
+            None => doc! { "id": 1 },
+        }
+    }
+
+    pub(crate) fn query(&self) -> Document {
+        Self::instance_query(self.instance_key.as_deref())
+    }
+}
+
+/// Numeric fields of `AppState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppStateField {
+    LastEquity,
+    AveDd,
+    MaxDd,
+    CumulativeReturn,
+    CumulativeDd,
+    Score,
+    Score2,
+    Score3,
+    MaxInvestedAmount,
+}
+
+impl AppStateField {
+    pub const ALL: [AppStateField; 9] = [
+        AppStateField::LastEquity,
+        AppStateField::AveDd,
+        AppStateField::MaxDd,
+        AppStateField::CumulativeReturn,
+        AppStateField::CumulativeDd,
+        AppStateField::Score,
+        AppStateField::Score2,
+        AppStateField::Score3,
+        AppStateField::MaxInvestedAmount,
+    ];
+
+    pub fn key(&self) -> &'static str {
+        match self {
+            AppStateField::LastEquity => "last_equity",
+            AppStateField::AveDd => "ave_dd",
+            AppStateField::MaxDd => "max_dd",
+            AppStateField::CumulativeReturn => "cumulative_return",
+            AppStateField::CumulativeDd => "cumulative_dd",
+            AppStateField::Score => "score",
+            AppStateField::Score2 => "score_2",
+            AppStateField::Score3 => "score_3",
+            AppStateField::MaxInvestedAmount => "max_invested_amount",
+        }
+    }
+}
+
+/// Changes to `AppState`, applied in one atomic update by
+/// `TransactionLog::apply_app_state_patch`. Each field takes at most one
+/// operation; a later call for the same field replaces the earlier one.
+/// Values are stored as given, without rounding. A value that cannot be
+/// stored makes the whole patch fail with `DbError::Serialization`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AppStatePatch {
+    set: Document,
+    inc: Document,
+    max: Document,
+    push: Document,
+    expected_version: Option<u64>,
+    error: Option<String>,
+}
+
+impl AppStatePatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with(mut self, key: &str, operator: &str, value: Result<Bson, DbError>) -> Self {
+        for document in [&mut self.set, &mut self.inc, &mut self.max, &mut self.push] {
+            document.remove(key);
+        }
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                self.error.get_or_insert_with(|| format!("{}: {}", key, e));
+                return self;
+            }
+        };
+        let document = match operator {
+            "$inc" => &mut self.inc,
+            "$max" => &mut self.max,
+            "$push" => &mut self.push,
+            _ => &mut self.set,
+        };
+        document.insert(key, value);
+        self
+    }
+
+    /// Replaces `field` with `value`.
+    pub fn set(self, field: AppStateField, value: Decimal) -> Self {
+        self.with(field.key(), "$set", crate::decimal::to_bson(value))
+    }
+
+    /// Clears an optional `field`.
+    pub fn unset(self, field: AppStateField) -> Self {
+        self.with(field.key(), "$set", Ok(Bson::Null))
+    }
+
+    /// Adds `value` to `field`, which must not be null.
+    pub fn add(self, field: AppStateField, value: Decimal) -> Self {
+        self.with(field.key(), "$inc", crate::decimal::to_bson(value))
+    }
+
+    /// Replaces `field` with `value` if it is null or smaller.
+    pub fn max(self, field: AppStateField, value: Decimal) -> Self {
+        self.with(field.key(), "$max", crate::decimal::to_bson(value))
+    }
+
+    pub fn last_execution_time(self, time: SystemTime) -> Self {
+        let value = bson::to_bson(&time).map_err(DbError::from);
+        self.with("last_execution_time", "$set", value)
+    }
+
+    pub fn curcuit_break(self, curcuit_break: bool) -> Self {
+        self.with("curcuit_break", "$set", Ok(Bson::Boolean(curcuit_break)))
+    }
+
+    /// Appends `error_time` to the list of error times.
+    pub fn push_error_time(self, error_time: String) -> Self {
+        self.with("error_time", "$push", Ok(Bson::String(error_time)))
+    }
+
+    pub fn fund_configs(self, fund_configs: Vec<FundConfig>) -> Self {
+        let value = bson::to_bson(&fund_configs).map_err(DbError::from);
+        self.with("fund_configs", "$set", value)
+    }
+
+    /// Applies the patch only if the stored state is still at `version`,
+    /// failing with `DbError::Conflict` otherwise.
+    pub fn if_version(mut self, version: u64) -> Self {
+        self.expected_version = Some(version);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.inc.is_empty() && self.max.is_empty() && self.push.is_empty()
+    }
+
+    /// The update document, which also increments `version`.
+    pub fn to_update(&self) -> Result<Document, DbError> {
+        if let Some(e) = &self.error {
+            return Err(DbError::Serialization(e.clone().into()));
+        }
+        let mut inc = self.inc.clone();
+        inc.insert("version", 1_i64);
+        let mut update = Document::new();
+        for (operator, fields) in [
+            ("$set", &self.set),
+            ("$inc", &inc),
+            ("$max", &self.max),
+            ("$push", &self.push),
+        ] {
+            if !fields.is_empty() {
+                update.insert(operator, fields.clone());
+            }
+        }
+        Ok(update)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct PnlLog {
     pub id: Option<u32>,
+    /// UTC seconds of the period the PnL is for. `None` for entries stored
+    /// before it was added, until `migrate_pnl_dates` has been run.
+    #[serde(default)]
+    pub timestamp: Option<i64>,
     pub date: String,
     pub pnl: Decimal,
+    #[serde(default)]
+    pub fund_name: Option<String>,
+    #[serde(default)]
+    pub trading_strategy: Option<TradingStrategy>,
+    #[serde(default)]
+    pub currency: Option<String>,
+}
+
+/// Accepted forms of the legacy `PnlLog::date` strings, besides RFC 3339.
+/// The first is the one of `get_local_time`.
+const PNL_OFFSET_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%z";
+const PNL_DATE_TIME_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"];
+const PNL_DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%Y/%m/%d", "%Y%m%d"];
+
+impl PnlLog {
+    /// A PnL for the period starting at `timestamp`, with `date` set to its
+    /// UTC day.
+    pub fn new(id: Option<u32>, timestamp: i64, pnl: Decimal) -> Self {
+        let date = chrono::DateTime::from_timestamp(timestamp, 0)
+            .map(|time| time.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        Self {
+            id,
+            timestamp: Some(timestamp),
+            date,
+            pnl,
+            ..Default::default()
+        }
+    }
+
+    /// Parses a legacy date string. Times without an offset are taken as
+    /// UTC, and dates without a time as UTC midnight.
+    pub fn parse_date(date: &str) -> Option<i64> {
+        let date = date.trim();
+        if let Ok(time) = chrono::DateTime::parse_from_rfc3339(date)
+            .or_else(|_| chrono::DateTime::parse_from_str(date, PNL_OFFSET_DATE_FORMAT))
+        {
+            return Some(time.timestamp());
+        }
+        PNL_DATE_TIME_FORMATS
+            .iter()
+            .find_map(|format| chrono::NaiveDateTime::parse_from_str(date, format).ok())
+            .or_else(|| {
+                PNL_DATE_FORMATS.iter().find_map(|format| {
+                    chrono::NaiveDate::parse_from_str(date, format)
+                        .ok()
+                        .and_then(|date| date.and_hms_opt(0, 0, 0))
+                })
+            })
+            .map(|time| time.and_utc().timestamp())
+    }
+
+    /// `timestamp`, or the parsed `date` of an entry that has not been
+    /// migrated.
+    pub fn effective_timestamp(&self) -> Option<i64> {
+        self.timestamp.or_else(|| Self::parse_date(&self.date))
+    }
+}
+
+/// Conditions for searching PnL entries, as with `PositionFilter`. The time
+/// bounds apply to `timestamp`, so they leave out entries that have not
+/// been migrated.
+#[derive(Clone, Debug, Default)]
+pub struct PnlFilter {
+    pub fund_name: Option<String>,
+    pub trading_strategy: Option<TradingStrategy>,
+    pub currency: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl PnlFilter {
+    /// The conditions the database can check. `trading_strategy` is checked
+    /// by `matches`, so that it compares like `TradingStrategy`'s `==`.
+    pub fn to_document(&self) -> Document {
+        let mut document = Document::new();
+        for (key, value) in [("fund_name", &self.fund_name), ("currency", &self.currency)] {
+            if let Some(value) = value {
+                document.insert(key, value);
+            }
+        }
+        let mut range = Document::new();
+        if let Some(from) = self.from {
+            range.insert("$gte", from);
+        }
+        if let Some(to) = self.to {
+            range.insert("$lt", to);
+        }
+        if !range.is_empty() {
+            document.insert("timestamp", range);
+        }
+        document
+    }
+
+    pub fn matches(&self, item: &PnlLog) -> bool {
+        self.trading_strategy
+            .is_none_or(|strategy| item.trading_strategy == Some(strategy))
+    }
 }
 
 impl HasId for PnlLog {
@@ -146,9 +517,12 @@ pub struct PricePoint {
     pub open_interest: Option<Decimal>,
     pub oracle_price: Option<Decimal>,
     pub debug: Option<DebugLog>,
+    #[serde(default)]
+    pub features: Option<FeatureVector>,
 }
 
 impl PricePoint {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         price: Decimal,
         timestamp: Option<i64>,
@@ -171,8 +545,17 @@ impl PricePoint {
             open_interest,
             oracle_price,
             debug,
+            features: None,
         }
     }
+
+    /// The features of this price, from `debug` if they were stored in the
+    /// old layout.
+    pub fn feature_vector(&self) -> Option<FeatureVector> {
+        self.features
+            .clone()
+            .or_else(|| self.debug.as_ref().map(FeatureVector::from))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -211,6 +594,26 @@ pub enum CandlePattern {
 }
 
 impl CandlePattern {
+    /// Every pattern, in the order of `to_one_hot`.
+    pub const ALL: [CandlePattern; 16] = [
+        CandlePattern::None,
+        CandlePattern::Hammer,
+        CandlePattern::InvertedHammer,
+        CandlePattern::BullishEngulfing,
+        CandlePattern::BearishEngulfing,
+        CandlePattern::Doji,
+        CandlePattern::Marubozu,
+        CandlePattern::MorningStar,
+        CandlePattern::EveningStar,
+        CandlePattern::ThreeWhiteSoldiers,
+        CandlePattern::ThreeBlackCrows,
+        CandlePattern::PiercingPattern,
+        CandlePattern::DarkCloudCover,
+        CandlePattern::Harami,
+        CandlePattern::HaramiCross,
+        CandlePattern::SpinningTop,
+    ];
+
     pub fn to_one_hot(&self) -> [Decimal; 16] {
         let mut one_hot = [Decimal::ZERO; 16];
 
@@ -285,223 +688,1027 @@ pub struct DebugLog {
     pub output_5: Decimal,
 }
 
+/// Takes the leading word of a stored value, lower-cased, so that values
+/// such as "open", "OPEN" or "Closed(TakeProfit)" written before the enums
+/// existed are still understood.
+fn normalized_name(s: &str) -> String {
+    s.trim()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+fn spellings_of(names: Vec<String>) -> Vec<String> {
+    names
+        .iter()
+        .flat_map(|name| [name.clone(), name.to_lowercase(), name.to_uppercase()])
+        .collect()
+}
+
+/// Lifecycle of a position. Stored as the variant name.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PositionState {
+    #[default]
+    Opening,
+    Open,
+    Closing,
+    Closed,
+    Canceled,
+    Liquidated,
+}
+
+impl PositionState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            PositionState::Closed | PositionState::Canceled | PositionState::Liquidated
+        )
+    }
+
+    /// Whether the position was filled and has been exited since, unlike a
+    /// canceled one.
+    pub fn is_closed(&self) -> bool {
+        matches!(self, PositionState::Closed | PositionState::Liquidated)
+    }
+
+    /// Whether a position may move from `self` to `next`. Staying in the
+    /// same state is always allowed, so that other fields can be updated.
+    pub fn can_transition_to(&self, next: PositionState) -> bool {
+        use PositionState::*;
+
+        *self == next
+            || matches!(
+                (self, next),
+                (Opening, Open)
+                    | (Opening, Canceled)
+                    | (Open, Closing)
+                    | (Open, Closed)
+                    | (Open, Liquidated)
+                    | (Closing, Open)
+                    | (Closing, Closed)
+                    | (Closing, Liquidated)
+            )
+    }
+
+    pub fn transition(self, next: PositionState) -> Result<PositionState, DbError> {
+        if self.can_transition_to(next) {
+            Ok(next)
+        } else {
+            Err(DbError::InvalidTransition(format!("{} -> {}", self, next)))
+        }
+    }
+}
+
+impl PositionState {
+    /// Spellings of `self` written before the enum existed that a query
+    /// should still match. Other legacy values are rewritten by
+    /// `TransactionLog::migrate_position_names`.
+    fn spellings(&self) -> Vec<String> {
+        let mut names = vec![self.to_string()];
+        if *self == PositionState::Canceled {
+            names.push("Cancelled".to_string());
+        }
+        spellings_of(names)
+    }
+}
+
+impl fmt::Display for PositionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for PositionState {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match normalized_name(s).as_str() {
+            "opening" => Ok(PositionState::Opening),
+            "open" => Ok(PositionState::Open),
+            "closing" => Ok(PositionState::Closing),
+            "closed" => Ok(PositionState::Closed),
+            "canceled" | "cancelled" => Ok(PositionState::Canceled),
+            "liquidated" => Ok(PositionState::Liquidated),
+            _ => Err(DbError::Serialization(
+                format!("Unknown position state: {}", s).into(),
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PositionState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PositionType {
+    #[default]
+    Long,
+    Short,
+}
+
+impl PositionType {
+    /// See `PositionState::spellings`.
+    fn spellings(&self) -> Vec<String> {
+        spellings_of(vec![self.to_string()])
+    }
+}
+
+impl fmt::Display for PositionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl FromStr for PositionType {
+    type Err = DbError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match normalized_name(s).as_str() {
+            "long" => Ok(PositionType::Long),
+            "short" => Ok(PositionType::Short),
+            _ => Err(DbError::Serialization(
+                format!("Unknown position type: {}", s).into(),
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PositionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OrderSide {
+    #[default]
+    Buy,
+    Sell,
+}
+
+impl OrderSide {
+    pub fn opposite(&self) -> OrderSide {
+        match self {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        }
+    }
+}
+
+impl fmt::Display for OrderSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// One execution of an order. `size` is always positive; `side` tells
+/// whether it adds to the position or reduces it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Fill {
+    pub side: OrderSide,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub fee: Decimal,
+    pub timestamp: i64,
+    pub order_id: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct PositionLog {
     pub id: Option<u32>,
     pub fund_name: String,
+    #[serde(default)]
+    pub trading_strategy: Option<TradingStrategy>,
+    /// Incremented by every `update_transaction`. See
+    /// `update_transaction_if_unchanged`.
+    #[serde(default)]
+    pub version: u64,
     pub order_id: String,
     pub ordered_price: Decimal,
-    pub state: String,
+    pub state: PositionState,
     pub token_name: String,
     pub open_time_str: String,
     pub open_timestamp: i64,
     pub close_time_str: String,
+    #[serde(default)]
+    pub close_timestamp: Option<i64>,
     pub average_open_price: Decimal,
-    pub position_type: String,
+    pub position_type: PositionType,
     pub close_price: Decimal,
     pub asset_in_usd: Decimal,
     pub pnl: Decimal,
     pub fee: Decimal,
     pub debug: DebugLog,
+    /// Fills in execution order. When present, `ordered_price`,
+    /// `average_open_price`, `close_price`, `pnl` and `fee` are derived from
+    /// them by `add_fill`.
+    #[serde(default)]
+    pub fills: Vec<Fill>,
+    #[serde(default)]
+    pub features: Option<FeatureVector>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct SerializableModel {
-    pub model: Vec<u8>,
+/// Size-weighted average price of `fills`.
+fn average_price<'a>(fills: impl Iterator<Item = &'a Fill>) -> Option<Decimal> {
+    let (size, notional) = fills.fold((Decimal::ZERO, Decimal::ZERO), |(size, notional), f| {
+        (size + f.size, notional + f.price * f.size)
+    });
+    (!size.is_zero()).then(|| notional / size)
 }
 
-impl HasId for PositionLog {
-    fn id(&self) -> Option<u32> {
-        self.id
+impl PositionLog {
+    /// The features of this position, from `debug` if they were stored in
+    /// the old layout.
+    pub fn feature_vector(&self) -> FeatureVector {
+        self.features
+            .clone()
+            .unwrap_or_else(|| FeatureVector::from(&self.debug))
     }
-}
-
-pub struct TransactionLog {
-    counter: Counter,
-    db_r_name: String,
-    db_w_name: String,
-    client_holder: Arc<Mutex<ClientHolder>>,
-}
-
-impl TransactionLog {
-    pub async fn new(
-        max_position_counter: Option<u32>,
-        max_price_counter: Option<u32>,
-        max_pnl_counter: Option<u32>,
-        mongodb_uri: &str,
-        db_r_name: &str,
-        db_w_name: &str,
-        back_test: bool,
-    ) -> Self {
-        // Set up the DB client holder
-        let mut client_options = match ClientOptions::parse(mongodb_uri).await {
-            Ok(client_options) => client_options,
-            Err(e) => {
-                panic!("{:?}", e);
-            }
-        };
-        let tls_options = TlsOptions::builder().build();
-        client_options.tls = Some(Tls::Enabled(tls_options));
-        let client_holder = Arc::new(Mutex::new(ClientHolder::new(client_options)));
-
-        // Get database instances for read and write
-        let db_w = shared_mongodb::database::get(&client_holder, &db_w_name)
-            .await
-            .unwrap();
-        let db_r = shared_mongodb::database::get(&client_holder, &db_r_name)
-            .await
-            .unwrap();
-
-        // Ensure indexes exist in both read and write databases
-        create_unique_index(&db_w)
-            .await
-            .expect("Error creating unique index in db_w");
-        create_unique_index(&db_r)
-            .await
-            .expect("Error creating unique index in db_r");
 
-        if back_test {
-            if let Err(e) = Self::delete_all_positions(&db_w).await {
-                panic!("delete_all_positions failed: {:?}", e);
-            }
-            if let Err(e) = Self::delete_app_state(&db_w).await {
-                panic!("delete_app_state failed: {:?}", e);
-            }
+    /// The side of the fills that open the position.
+    pub fn opening_side(&self) -> OrderSide {
+        match self.position_type {
+            PositionType::Long => OrderSide::Buy,
+            PositionType::Short => OrderSide::Sell,
         }
+    }
 
-        let last_position_counter =
-            TransactionLog::get_last_transaction_id(&db_w, CounterType::Position).await;
-        let last_price_counter =
-            TransactionLog::get_last_transaction_id(&db_w, CounterType::Price).await;
-        let last_pnl_counter =
-            TransactionLog::get_last_transaction_id(&db_w, CounterType::Pnl).await;
-
-        let counter = Counter::new(
-            max_position_counter,
-            max_price_counter,
-            max_pnl_counter,
-            last_position_counter,
-            last_price_counter,
-            last_pnl_counter,
-        );
+    fn opening_fills(&self) -> impl Iterator<Item = &Fill> {
+        let side = self.opening_side();
+        self.fills.iter().filter(move |f| f.side == side)
+    }
 
-        log::warn!(
-            "position = {}/{:?}, price = {}/{:?}, pnl = {}/{:?}",
-            last_position_counter,
-            max_position_counter,
-            last_price_counter,
-            max_price_counter,
-            last_pnl_counter,
-            max_pnl_counter,
-        );
+    fn closing_fills(&self) -> impl Iterator<Item = &Fill> {
+        let side = self.opening_side().opposite();
+        self.fills.iter().filter(move |f| f.side == side)
+    }
 
-        TransactionLog {
-            counter,
-            db_r_name: db_r_name.to_owned(),
-            db_w_name: db_w_name.to_owned(),
-            client_holder,
-        }
+    pub fn opened_size(&self) -> Decimal {
+        self.opening_fills().map(|f| f.size).sum()
     }
 
-    pub fn increment_counter(&self, counter_type: CounterType) -> u32 {
-        self.counter.increment(counter_type)
+    pub fn closed_size(&self) -> Decimal {
+        self.closing_fills().map(|f| f.size).sum()
     }
 
-    pub async fn get_last_transaction_id(db: &Database, counter_type: CounterType) -> u32 {
-        match counter_type {
-            CounterType::Position => get_last_id::<PositionLog>(db).await,
-            CounterType::Price => get_last_id::<PriceLog>(db).await,
-            CounterType::Pnl => get_last_id::<PnlLog>(db).await,
-        }
+    /// The size still open.
+    pub fn open_size(&self) -> Decimal {
+        self.opened_size() - self.closed_size()
     }
 
-    pub async fn get_w_db(&self) -> Option<Database> {
-        self.get_db(false).await
+    pub fn average_open_price_from_fills(&self) -> Option<Decimal> {
+        average_price(self.opening_fills())
     }
 
-    pub async fn get_r_db(&self) -> Option<Database> {
-        self.get_db(true).await
+    pub fn average_close_price_from_fills(&self) -> Option<Decimal> {
+        average_price(self.closing_fills())
     }
 
-    async fn get_db(&self, read: bool) -> Option<Database> {
-        let db_name = if read {
-            &self.db_r_name
-        } else {
-            &self.db_w_name
-        };
-        let db = match database::get(&self.client_holder, db_name).await {
-            Ok(db) => Some(db),
-            Err(e) => {
-                log::error!("get_db: {:?}", e);
-                None
-            }
+    /// PnL of the closed size against the average open price, before fees.
+    pub fn realized_pnl(&self) -> Decimal {
+        let (Some(open), Some(close)) = (
+            self.average_open_price_from_fills(),
+            self.average_close_price_from_fills(),
+        ) else {
+            return Decimal::ZERO;
         };
-        db
+        let pnl = (close - open) * self.closed_size();
+        match self.position_type {
+            PositionType::Long => pnl,
+            PositionType::Short => -pnl,
+        }
     }
 
-    pub async fn update_transaction(
-        db: &Database,
-        item: &PositionLog,
-    ) -> Result<(), Box<dyn error::Error>> {
-        update_item(db, item).await?;
-        Ok(())
+    pub fn total_fees(&self) -> Decimal {
+        self.fills.iter().map(|f| f.fee).sum()
     }
 
-    pub async fn update_price(db: &Database, item: PriceLog) -> Result<(), Box<dyn error::Error>> {
-        update_item(db, &item).await?;
-        Ok(())
+    /// Appends `fill` and updates the derived fields.
+    pub fn add_fill(&mut self, fill: Fill) {
+        self.fills.push(fill);
+        self.update_from_fills();
     }
 
-    pub async fn copy_price(db_r: &Database, db_w: &Database, limit: Option<u32>) {
-        let item = PriceLog::default();
-        let items = {
-            match search_items(db_r, &item, SearchMode::Ascending, limit, None, Some("id")).await {
-                Ok(items) => items,
-                Err(e) => {
-                    log::error!("get price: {:?}", e);
-                    return;
-                }
-            }
-        };
-        log::debug!("get prices: num = {}", items.len());
-
-        for item in &items {
-            match insert_item(db_w, item).await {
-                Ok(_) => {}
-                Err(e) => {
-                    log::error!("write price: {:?}", e);
-                    return;
-                }
-            }
+    /// Sets the flat fields from the fills. Positions without fills, such as
+    /// those stored before fills were recorded, are left as they are.
+    pub fn update_from_fills(&mut self) {
+        if self.fills.is_empty() {
+            return;
         }
+        let first_price = self.opening_fills().next().map(|f| f.price);
+        self.ordered_price = first_price.unwrap_or(self.ordered_price);
+        self.average_open_price = self.average_open_price_from_fills().unwrap_or_default();
+        self.close_price = self.average_close_price_from_fills().unwrap_or_default();
+        self.pnl = self.realized_pnl();
+        self.fee = self.total_fees();
     }
+}
 
-    pub async fn copy_position(db_r: &Database, db_w: &Database, limit: Option<u32>) {
-        let item = PositionLog::default();
-        let items = {
-            match search_items(db_r, &item, SearchMode::Ascending, limit, None, Some("id")).await {
-                Ok(items) => items,
-                Err(e) => {
-                    log::error!("get position: {:?}", e);
-                    return;
+/// Which timestamp of a position a time-range query looks at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PositionTime {
+    Open,
+    Close,
+}
+
+impl PositionTime {
+    pub fn key(&self) -> &'static str {
+        match self {
+            PositionTime::Open => "open_timestamp",
+            PositionTime::Close => "close_timestamp",
+        }
+    }
+}
+
+/// Conditions for searching positions. Unset fields do not restrict the
+/// result; time bounds are `from <= t < to`.
+#[derive(Clone, Debug, Default)]
+pub struct PositionFilter {
+    pub fund_name: Option<String>,
+    pub token_name: Option<String>,
+    pub state: Option<PositionState>,
+    pub position_type: Option<PositionType>,
+    pub open_from: Option<i64>,
+    pub open_to: Option<i64>,
+    pub close_from: Option<i64>,
+    pub close_to: Option<i64>,
+}
+
+impl PositionFilter {
+    pub fn to_document(&self) -> Document {
+        fn range(from: Option<i64>, to: Option<i64>) -> Option<Document> {
+            let mut range = Document::new();
+            if let Some(from) = from {
+                range.insert("$gte", from);
+            }
+            if let Some(to) = to {
+                range.insert("$lt", to);
+            }
+            (!range.is_empty()).then_some(range)
+        }
+
+        let mut document = Document::new();
+        for (key, value) in [
+            ("fund_name", &self.fund_name),
+            ("token_name", &self.token_name),
+        ] {
+            if let Some(value) = value {
+                document.insert(key, value);
+            }
+        }
+        let state = self.state.map(|state| state.spellings());
+        let position_type = self.position_type.map(|t| t.spellings());
+        for (key, spellings) in [("state", state), ("position_type", position_type)] {
+            if let Some(spellings) = spellings {
+                document.insert(key, doc! { "$in": spellings });
+            }
+        }
+        if let Some(range) = range(self.open_from, self.open_to) {
+            document.insert(PositionTime::Open.key(), range);
+        }
+        if let Some(range) = range(self.close_from, self.close_to) {
+            document.insert(PositionTime::Close.key(), range);
+        }
+        document
+    }
+}
+
+impl From<PositionFilter> for Document {
+    fn from(filter: PositionFilter) -> Self {
+        filter.to_document()
+    }
+}
+
+/// One change of a stored position. `changes` holds the new values of the
+/// fields that changed and `previous` their old values, or `None` when the
+/// event created the position, including when a capped counter reused its id.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PositionEvent {
+    pub id: Option<u32>,
+    pub position_id: u32,
+    pub timestamp: i64,
+    pub reason: String,
+    pub previous: Option<Document>,
+    pub changes: Document,
+}
+
+impl PositionEvent {
+    /// The event turning `stored` into `current`, or `None` when nothing
+    /// changed.
+    fn diff(
+        position_id: u32,
+        stored: Option<&Document>,
+        current: &Document,
+        reason: &str,
+    ) -> Option<Self> {
+        let (previous, changes) = match stored {
+            Some(stored) => {
+                let mut previous = Document::new();
+                let mut changes = Document::new();
+                for (key, value) in current {
+                    if key == "version" {
+                        continue;
+                    }
+                    let old = stored.get(key).cloned().unwrap_or(Bson::Null);
+                    if old != *value {
+                        previous.insert(key, old);
+                        changes.insert(key, value.clone());
+                    }
+                }
+                if changes.is_empty() {
+                    return None;
                 }
+                (Some(previous), changes)
             }
+            None => (None, current.clone()),
+        };
+        Some(Self {
+            id: None,
+            position_id,
+            timestamp: get_local_time().0,
+            reason: reason.to_owned(),
+            previous,
+            changes,
+        })
+    }
+}
+
+impl HasId for PositionEvent {
+    fn id(&self) -> Option<u32> {
+        self.id
+    }
+}
+
+/// Rebuilds the position after each of `events`, which must be in recording
+/// order. Events before the first creation, e.g. of positions stored before
+/// events were recorded, are skipped since the full position is not known.
+pub fn replay_position_events(events: &[PositionEvent]) -> Result<Vec<PositionLog>, DbError> {
+    let mut document: Option<Document> = None;
+    let mut positions = vec![];
+    for event in events {
+        let current = match (&event.previous, document.as_mut()) {
+            (None, _) => document.insert(Document::new()),
+            (Some(_), Some(current)) => current,
+            (Some(_), None) => continue,
         };
-        log::debug!("get position: num = {}", items.len());
+        current.extend(event.changes.clone());
+        positions.push(bson::from_document(current.clone())?);
+    }
+    Ok(positions)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SerializableModel {
+    pub model: Vec<u8>,
+}
 
-        for item in &items {
-            match insert_item(db_w, item).await {
-                Ok(_) => {}
+impl HasId for PositionLog {
+    fn id(&self) -> Option<u32> {
+        self.id
+    }
+}
+
+#[derive(Clone)]
+enum DbHandle {
+    Mongo {
+        client_holder: Arc<Mutex<ClientHolder>>,
+        db_name: String,
+    },
+    Backend(Arc<dyn StorageBackend>),
+}
+
+impl DbHandle {
+    async fn get(&self) -> Option<Arc<dyn StorageBackend>> {
+        match self {
+            DbHandle::Mongo {
+                client_holder,
+                db_name,
+            } => match database::get(client_holder, db_name).await {
+                Ok(db) => Some(Arc::new(db)),
                 Err(e) => {
-                    log::error!("write position: {:?}", e);
-                    return;
+                    log::error!("get_db: {:?}", e);
+                    None
+                }
+            },
+            DbHandle::Backend(backend) => Some(backend.clone()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            DbHandle::Mongo { db_name, .. } => db_name,
+            DbHandle::Backend(_) => "",
+        }
+    }
+}
+
+async fn mongo_client_holder(mongodb_uri: &str) -> Arc<Mutex<ClientHolder>> {
+    let mut client_options = match ClientOptions::parse(mongodb_uri).await {
+        Ok(client_options) => client_options,
+        Err(e) => {
+            panic!("{:?}", e);
+        }
+    };
+    let tls_options = TlsOptions::builder().build();
+    client_options.tls = Some(Tls::Enabled(tls_options));
+    Arc::new(Mutex::new(ClientHolder::new(client_options)))
+}
+
+pub struct TransactionLog {
+    counter: Counter,
+    db_r: DbHandle,
+    db_w: DbHandle,
+}
+
+impl TransactionLog {
+    /// When `in_memory` is set, MongoDB is not contacted at all and both the
+    /// read and write databases are a single `MemoryBackend`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        max_position_counter: Option<u32>,
+        max_price_counter: Option<u32>,
+        max_pnl_counter: Option<u32>,
+        mongodb_uri: &str,
+        db_r_name: &str,
+        db_w_name: &str,
+        back_test: bool,
+        in_memory: bool,
+        counter_mode: CounterMode,
+    ) -> Self {
+        let (db_r, db_w) = if in_memory {
+            let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+            (
+                DbHandle::Backend(backend.clone()),
+                DbHandle::Backend(backend),
+            )
+        } else {
+            // Set up the DB client holder
+            let client_holder = mongo_client_holder(mongodb_uri).await;
+
+            let db_r = DbHandle::Mongo {
+                client_holder: client_holder.clone(),
+                db_name: db_r_name.to_owned(),
+            };
+            let db_w = DbHandle::Mongo {
+                client_holder,
+                db_name: db_w_name.to_owned(),
+            };
+            (db_r, db_w)
+        };
+
+        Self::init(
+            max_position_counter,
+            max_price_counter,
+            max_pnl_counter,
+            db_r,
+            db_w,
+            back_test,
+            counter_mode,
+        )
+        .await
+    }
+
+    /// Opens `db_name` without creating indexes, resetting counters or
+    /// running migrations, for tools that only read from a database.
+    pub async fn open_db(mongodb_uri: &str, db_name: &str) -> Option<Arc<dyn StorageBackend>> {
+        let handle = DbHandle::Mongo {
+            client_holder: mongo_client_holder(mongodb_uri).await,
+            db_name: db_name.to_owned(),
+        };
+        handle.get().await
+    }
+
+    /// Creates a log on top of arbitrary storage backends instead of MongoDB.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn with_backend(
+        max_position_counter: Option<u32>,
+        max_price_counter: Option<u32>,
+        max_pnl_counter: Option<u32>,
+        db_r: Arc<dyn StorageBackend>,
+        db_w: Arc<dyn StorageBackend>,
+        back_test: bool,
+        counter_mode: CounterMode,
+    ) -> Self {
+        Self::init(
+            max_position_counter,
+            max_price_counter,
+            max_pnl_counter,
+            DbHandle::Backend(db_r),
+            DbHandle::Backend(db_w),
+            back_test,
+            counter_mode,
+        )
+        .await
+    }
+
+    async fn init(
+        max_position_counter: Option<u32>,
+        max_price_counter: Option<u32>,
+        max_pnl_counter: Option<u32>,
+        db_r_handle: DbHandle,
+        db_w_handle: DbHandle,
+        back_test: bool,
+        counter_mode: CounterMode,
+    ) -> Self {
+        // Get database instances for read and write
+        let db_w = db_w_handle.get().await.unwrap();
+        let db_r = db_r_handle.get().await.unwrap();
+        let db_w = db_w.as_ref();
+        let db_r = db_r.as_ref();
+
+        // Before the indexes, which legacy states could violate.
+        if let Err(e) = Self::migrate_app_state(db_w).await {
+            log::warn!("migrate_app_state: {:?}", e);
+        }
+
+        // Ensure indexes exist in both read and write databases
+        create_unique_index(db_w)
+            .await
+            .expect("Error creating unique index in db_w");
+        create_unique_index(db_r)
+            .await
+            .expect("Error creating unique index in db_r");
+
+        if back_test {
+            if let Err(e) = Self::delete_all_positions(db_w).await {
+                panic!("delete_all_positions failed: {:?}", e);
+            }
+            if let Err(e) = Counter::reset_sequence(db_w, CounterType::Position).await {
+                panic!("reset_sequence failed: {:?}", e);
+            }
+            if let Err(e) = Self::delete_app_state(db_w).await {
+                panic!("delete_app_state failed: {:?}", e);
+            }
+        }
+
+        if let Err(e) = Self::migrate_position_names(db_w).await {
+            log::warn!("migrate_position_names: {:?}", e);
+        }
+
+        let last_position_counter = Self::get_last_sequence(db_w, CounterType::Position).await;
+        let last_price_counter = Self::get_last_sequence(db_w, CounterType::Price).await;
+        let last_pnl_counter = Self::get_last_sequence(db_w, CounterType::Pnl).await;
+
+        let counter = match counter_mode {
+            CounterMode::Local => {
+                let counter = Counter::new(
+                    max_position_counter,
+                    max_price_counter,
+                    max_pnl_counter,
+                    0,
+                    0,
+                    0,
+                );
+                counter.restore(CounterType::Position, last_position_counter);
+                counter.restore(CounterType::Price, last_price_counter);
+                counter.restore(CounterType::Pnl, last_pnl_counter);
+                counter
+            }
+            CounterMode::Distributed => {
+                let counter = Counter::new_distributed(
+                    max_position_counter,
+                    max_price_counter,
+                    max_pnl_counter,
+                );
+                for (counter_type, sequence) in [
+                    (CounterType::Position, last_position_counter),
+                    (CounterType::Price, last_price_counter),
+                    (CounterType::Pnl, last_pnl_counter),
+                ] {
+                    if let Err(e) = counter.seed(db_w, counter_type, sequence).await {
+                        panic!("seeding {:?} counter failed: {:?}", counter_type, e);
+                    }
                 }
+                counter
             }
+        };
+
+        log::warn!(
+            "position = {}/{:?}, price = {}/{:?}, pnl = {}/{:?}",
+            last_position_counter,
+            max_position_counter,
+            last_price_counter,
+            max_price_counter,
+            last_pnl_counter,
+            max_pnl_counter,
+        );
+
+        TransactionLog {
+            counter,
+            db_r: db_r_handle,
+            db_w: db_w_handle,
         }
     }
 
+    /// Allocates the next id. A capped counter saves its sequence, so that
+    /// after a restart the ring buffer resumes at the right slot.
+    pub async fn increment_counter(&self, counter_type: CounterType) -> Result<u32, DbError> {
+        self.allocate_id(counter_type).await
+    }
+
+    /// Allocates an id with the configured `CounterMode`, through the write
+    /// database when the counter is distributed or capped.
+    pub async fn allocate_id(&self, counter_type: CounterType) -> Result<u32, DbError> {
+        let db = self
+            .get_w_db()
+            .await
+            .ok_or_else(|| DbError::Connection("no db".to_string()))?;
+        self.counter.allocate(db.as_ref(), counter_type).await
+    }
+
+    pub async fn get_last_transaction_id(
+        db: &dyn StorageBackend,
+        counter_type: CounterType,
+    ) -> u32 {
+        match counter_type {
+            CounterType::Position => get_last_id::<PositionLog>(db).await,
+            CounterType::Price => get_last_id::<PriceLog>(db).await,
+            CounterType::Pnl => get_last_id::<PnlLog>(db).await,
+        }
+    }
+
+    /// The sequence to resume from: the saved counter sequence when the
+    /// ids have wrapped around, the highest stored id otherwise.
+    async fn get_last_sequence(db: &dyn StorageBackend, counter_type: CounterType) -> u64 {
+        let last_id = Self::get_last_transaction_id(db, counter_type).await as u64;
+        match Counter::load_sequence(db, counter_type).await {
+            Ok(sequence) => sequence.unwrap_or(0).max(last_id),
+            Err(e) => {
+                log::warn!("load_sequence: {:?}", e);
+                last_id
+            }
+        }
+    }
+
+    pub async fn get_w_db(&self) -> Option<Arc<dyn StorageBackend>> {
+        self.db_w.get().await
+    }
+
+    pub async fn get_r_db(&self) -> Option<Arc<dyn StorageBackend>> {
+        self.db_r.get().await
+    }
+
+    /// Stores `item`, first checking that the stored position, if any, may
+    /// move to the new state. The change is recorded as a `PositionEvent`.
+    /// A new position under an id that a capped counter handed out again
+    /// must be written with `replace_transaction` first.
+    pub async fn update_transaction(
+        db: &dyn StorageBackend,
+        item: &PositionLog,
+    ) -> Result<(), DbError> {
+        Self::update_transaction_with_reason(db, item, "update").await
+    }
+
+    /// Same as `update_transaction`, recording `reason` (e.g. "partial
+    /// fill") with the event.
+    pub async fn update_transaction_with_reason(
+        db: &dyn StorageBackend,
+        item: &PositionLog,
+        reason: &str,
+    ) -> Result<(), DbError> {
+        Self::write_position(db, item, None, reason).await?;
+        Ok(())
+    }
+
+    /// Same as `update_transaction`, but only if the stored position is
+    /// still at `item.version`; a position that is not stored yet is at
+    /// version zero. Returns the position with its new version, or fails
+    /// with `DbError::Conflict` if another writer got there first.
+    pub async fn update_transaction_if_unchanged(
+        db: &dyn StorageBackend,
+        item: &PositionLog,
+        reason: &str,
+    ) -> Result<PositionLog, DbError> {
+        Self::write_position(db, item, Some(item.version), reason).await
+    }
+
+    /// Writes `item` with the version after the stored one. The write is
+    /// conditional on the version that was read, so that a concurrent
+    /// update is reported as a conflict instead of being overwritten.
+    async fn write_position(
+        db: &dyn StorageBackend,
+        item: &PositionLog,
+        expected_version: Option<u64>,
+        reason: &str,
+    ) -> Result<PositionLog, DbError> {
+        let id = item
+            .id
+            .ok_or_else(|| DbError::InvalidQuery("ID not provided".to_string()))?;
+        let stored = match search_item(db, &PositionLog::default(), Some(id), None).await {
+            Ok(stored) => Some(stored),
+            Err(e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        let stored_version = stored.as_ref().map_or(0, |stored| stored.version);
+        let conflict = || {
+            DbError::Conflict(format!(
+                "position {} is no longer at version {}",
+                id,
+                expected_version.unwrap_or(stored_version)
+            ))
+        };
+        if expected_version.is_some_and(|version| version != stored_version) {
+            return Err(conflict());
+        }
+        if let Some(stored) = &stored {
+            if !stored.state.can_transition_to(item.state) {
+                return Err(DbError::InvalidTransition(format!(
+                    "position {}: {} -> {}",
+                    id, stored.state, item.state
+                )));
+            }
+        }
+
+        let mut current = item.clone();
+        current.version = stored_version + 1;
+        let document = bson::to_document(&current)?;
+        let collection = current.get_collection_name();
+        match &stored {
+            Some(_) => {
+                let query = with_version(doc! { "id": id }, stored_version);
+                let update = doc! { "$set": document.clone() };
+                if db
+                    .find_one_and_update(collection, query, update, false)
+                    .await?
+                    .is_none()
+                {
+                    return Err(conflict());
+                }
+            }
+            None => match db.insert_one(collection, document.clone()).await {
+                Err(DbError::DuplicateKey(_)) => return Err(conflict()),
+                result => result?,
+            },
+        }
+
+        let stored = stored
+            .map(|stored| bson::to_document(&stored))
+            .transpose()?;
+        if let Some(event) = PositionEvent::diff(id, stored.as_ref(), &document, reason) {
+            Self::record_position_event(db, event).await?;
+        }
+        Ok(current)
+    }
+
+    /// Stores `item` as a new position, overwriting whatever was kept under
+    /// its id. Use this for the first write of a position whose id a capped
+    /// counter handed out again; later changes go through
+    /// `update_transaction`. The event history of the id starts over, and
+    /// the version still grows, so that writers holding the old position
+    /// get a conflict.
+    pub async fn replace_transaction(
+        db: &dyn StorageBackend,
+        item: &PositionLog,
+    ) -> Result<(), DbError> {
+        let id = item
+            .id
+            .ok_or_else(|| DbError::InvalidQuery("ID not provided".to_string()))?;
+        let stored_version = match search_item(db, &PositionLog::default(), Some(id), None).await {
+            Ok(stored) => stored.version,
+            Err(e) if e.is_not_found() => 0,
+            Err(e) => return Err(e),
+        };
+        let mut current = item.clone();
+        current.version = stored_version + 1;
+        replace_item(db, &current).await?;
+        Self::delete_position_history(db, id).await?;
+        let current = bson::to_document(&current)?;
+        if let Some(event) = PositionEvent::diff(id, None, &current, "replace") {
+            Self::record_position_event(db, event).await?;
+        }
+        Ok(())
+    }
+
+    /// Event ids come from a shared sequence in the "counters" collection,
+    /// whatever the `CounterMode`, since positions are updated without a
+    /// `TransactionLog` at hand.
+    async fn record_position_event(
+        db: &dyn StorageBackend,
+        mut event: PositionEvent,
+    ) -> Result<(), DbError> {
+        let sequence = next_shared_sequence(db, POSITION_EVENT_COUNTER).await?;
+        event.id = Some(sequence as u32);
+        insert_item(db, &event).await
+    }
+
+    /// Deletes the events of the position that was kept under `position_id`
+    /// before it was recycled, so that they do not mix with the history of
+    /// the new one.
+    async fn delete_position_history(
+        db: &dyn StorageBackend,
+        position_id: u32,
+    ) -> Result<(), DbError> {
+        let num = db
+            .delete_many(
+                PositionEvent::default().get_collection_name(),
+                doc! { "position_id": position_id },
+            )
+            .await?;
+        log::debug!(
+            "delete_position_history: id = {}, num = {}",
+            position_id,
+            num
+        );
+        Ok(())
+    }
+
+    /// The events of position `position_id` in recording order.
+    pub async fn get_position_history(
+        db: &dyn StorageBackend,
+        position_id: u32,
+    ) -> Result<Vec<PositionEvent>, DbError> {
+        Self::search_position_events(db, doc! { "position_id": position_id }).await
+    }
+
+    /// Position `position_id` as it was at `timestamp`, rebuilt from its
+    /// events, or `None` if it did not exist yet.
+    pub async fn get_position_at(
+        db: &dyn StorageBackend,
+        position_id: u32,
+        timestamp: i64,
+    ) -> Result<Option<PositionLog>, DbError> {
+        let filter = doc! {
+            "position_id": position_id,
+            "timestamp": { "$lte": timestamp },
+        };
+        let events = Self::search_position_events(db, filter).await?;
+        Ok(replay_position_events(&events)?.pop())
+    }
+
+    async fn search_position_events(
+        db: &dyn StorageBackend,
+        filter: Document,
+    ) -> Result<Vec<PositionEvent>, DbError> {
+        let item = PositionEvent::default();
+        match search_items(
+            db,
+            &item,
+            SearchMode::Ascending,
+            None,
+            None,
+            Some("id"),
+            Some(filter),
+        )
+        .await
+        {
+            Ok(events) => Ok(events),
+            Err(e) if e.is_not_found() => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stores `item` as a whole, so that a recycled id of a capped counter
+    /// replaces the old price.
+    pub async fn update_price(db: &dyn StorageBackend, item: PriceLog) -> Result<(), DbError> {
+        replace_item(db, &item).await?;
+        Ok(())
+    }
+
+    /// Stores `item`, overwriting whatever was kept under its id. Same as
+    /// `update_price`.
+    pub async fn replace_price(db: &dyn StorageBackend, item: PriceLog) -> Result<(), DbError> {
+        replace_item(db, &item).await?;
+        Ok(())
+    }
+
+    /// Imports historical prices into the write database, allocating ids
+    /// from this log's counter unless the source has an id column.
+    pub async fn import_prices<R: std::io::BufRead>(
+        &self,
+        reader: R,
+        options: &PriceImportOptions,
+    ) -> Result<ImportSummary, DbError> {
+        let db = self
+            .get_w_db()
+            .await
+            .ok_or_else(|| DbError::Connection("no db".to_string()))?;
+        import_prices(db.as_ref(), reader, options, Some(&self.counter)).await
+    }
+
+    pub async fn copy_price(
+        db_r: &dyn StorageBackend,
+        db_w: &dyn StorageBackend,
+        limit: Option<u32>,
+    ) {
+        copy_items(db_r, db_w, &PriceLog::default(), limit, "price").await;
+    }
+
+    pub async fn copy_position(
+        db_r: &dyn StorageBackend,
+        db_w: &dyn StorageBackend,
+        limit: Option<u32>,
+    ) {
+        copy_items(db_r, db_w, &PositionLog::default(), limit, "position").await;
+    }
+
     pub async fn get_price_market_data(
-        db: &Database,
+        db: &dyn StorageBackend,
         limit: Option<u32>,
         id: Option<u32>,
         is_ascend: bool,
@@ -518,7 +1725,7 @@ impl TransactionLog {
             Some(id) => search_item(db, &item, Some(id), sort_key)
                 .await
                 .map(|item| vec![item]),
-            None => search_items(db, &item, search_mode, limit, None, sort_key).await,
+            None => search_items(db, &item, search_mode, limit, None, sort_key, None).await,
         };
 
         let Ok(mut items) = items else {
@@ -542,7 +1749,7 @@ impl TransactionLog {
     }
 
     pub async fn get_all_positions(
-        db: &Database,
+        db: &dyn StorageBackend,
         limit: Option<u32>,
         id: Option<u32>,
         is_ascend: bool,
@@ -564,7 +1771,7 @@ impl TransactionLog {
                 }
             }
         } else {
-            match search_items(db, &item, search_mode, limit, None, sort_key).await {
+            match search_items(db, &item, search_mode, limit, None, sort_key, None).await {
                 Ok(positions) => positions,
                 Err(e) => {
                     log::warn!("get_all_positions: {:?}", e);
@@ -576,20 +1783,284 @@ impl TransactionLog {
         items
     }
 
-    async fn delete_all_positions(db: &Database) -> Result<(), Box<dyn error::Error>> {
+    /// Returns the prices with `from <= price_point.timestamp < to` in
+    /// ascending order, optionally restricted to one source `name` and token.
+    pub async fn get_prices_in_range(
+        db: &dyn StorageBackend,
+        from: i64,
+        to: i64,
+        name: Option<&str>,
+        token_name: Option<&str>,
+    ) -> Result<Vec<PriceLog>, DbError> {
+        let mut filter = doc! { "price_point.timestamp": { "$gte": from, "$lt": to } };
+        if let Some(name) = name {
+            filter.insert("name", name);
+        }
+        if let Some(token_name) = token_name {
+            filter.insert("token_name", token_name);
+        }
+
+        let item = PriceLog::default();
+        let sort_key = Some("price_point.timestamp");
+        match search_items(
+            db,
+            &item,
+            SearchMode::Ascending,
+            None,
+            None,
+            sort_key,
+            Some(filter),
+        )
+        .await
+        {
+            Err(e) if e.is_not_found() => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    /// Aggregates the prices with `from <= price_point.timestamp < to` into
+    /// candles of `interval`; see `build_candles`.
+    pub async fn get_candles(
+        db: &dyn StorageBackend,
+        interval: CandleInterval,
+        from: i64,
+        to: i64,
+        name: Option<&str>,
+        token_name: Option<&str>,
+    ) -> Result<Vec<Candle>, DbError> {
+        build_candles(db, interval, from, to, name, token_name).await
+    }
+
+    /// Returns the positions whose open or close timestamp is in
+    /// `from..to`, in ascending order of that timestamp. Positions that have
+    /// not been closed have no close timestamp and never match `Close`.
+    pub async fn get_positions_in_range(
+        db: &dyn StorageBackend,
+        time: PositionTime,
+        from: i64,
+        to: i64,
+        fund_name: Option<&str>,
+        token_name: Option<&str>,
+    ) -> Result<Vec<PositionLog>, DbError> {
+        let mut filter = PositionFilter {
+            fund_name: fund_name.map(str::to_owned),
+            token_name: token_name.map(str::to_owned),
+            ..Default::default()
+        };
+        match time {
+            PositionTime::Open => {
+                filter.open_from = Some(from);
+                filter.open_to = Some(to);
+            }
+            PositionTime::Close => {
+                filter.close_from = Some(from);
+                filter.close_to = Some(to);
+            }
+        }
+
+        let item = PositionLog::default();
+        let sort_key = Some(time.key());
+        let filter = filter.to_document();
+        match search_items(
+            db,
+            &item,
+            SearchMode::Ascending,
+            None,
+            None,
+            sort_key,
+            Some(filter),
+        )
+        .await
+        {
+            Err(e) if e.is_not_found() => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    /// Returns the positions matching `filter` in order of `open_timestamp`.
+    pub async fn search_positions(
+        db: &dyn StorageBackend,
+        filter: &PositionFilter,
+        limit: Option<u32>,
+        is_ascend: bool,
+    ) -> Result<Vec<PositionLog>, DbError> {
+        let search_mode = if is_ascend {
+            SearchMode::Ascending
+        } else {
+            SearchMode::Descending
+        };
+        let item = PositionLog::default();
+        match search_items(
+            db,
+            &item,
+            search_mode,
+            limit,
+            None,
+            Some("open_timestamp"),
+            Some(filter.to_document()),
+        )
+        .await
+        {
+            Err(e) if e.is_not_found() => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    async fn delete_all_positions(db: &dyn StorageBackend) -> Result<(), DbError> {
         let item = PositionLog::default();
-        delete_item_all(db, &item).await
+        delete_item_all(db, &item).await?;
+        delete_item_all(db, &PositionEvent::default()).await
+    }
+
+    /// Stores `item`. An entry already kept under its id, which with a
+    /// capped counter is from the previous lap of the ring buffer, is
+    /// replaced.
+    pub async fn insert_pnl(db: &dyn StorageBackend, item: PnlLog) -> Result<(), DbError> {
+        match insert_item(db, &item).await {
+            Err(DbError::DuplicateKey(_)) => {
+                log::debug!("insert_pnl: replacing id {:?}", item.id);
+                replace_item(db, &item).await
+            }
+            result => result,
+        }
     }
 
-    pub async fn insert_pnl(db: &Database, item: PnlLog) -> Result<(), Box<dyn error::Error>> {
-        insert_item(db, &item).await?;
+    /// Stores `item`, overwriting whatever was kept under its id. Same as
+    /// `insert_pnl`.
+    pub async fn replace_pnl(db: &dyn StorageBackend, item: PnlLog) -> Result<(), DbError> {
+        replace_item(db, &item).await?;
         Ok(())
     }
 
-    pub async fn get_app_state(db: &Database) -> AppState {
-        let item = AppState::default();
-        match search_item(db, &item, Some(1), Some("id")).await {
-            Ok(item) => item,
+    /// Rewrites `state` and `position_type` values stored before the enums
+    /// existed, such as "closed" or "Closed(TakeProfit)", to the variant
+    /// names that `PositionFilter` queries for. Returns how many positions
+    /// were rewritten. Values that are not understood, including empty
+    /// strings, are logged and left as they are.
+    pub async fn migrate_position_names(db: &dyn StorageBackend) -> Result<usize, DbError> {
+        fn canonical<T: FromStr + fmt::Display>(
+            value: Option<&Bson>,
+        ) -> Result<Option<String>, String> {
+            match value {
+                Some(Bson::String(stored)) => match stored.parse::<T>() {
+                    Ok(value) => Ok((value.to_string() != *stored).then(|| value.to_string())),
+                    Err(_) => Err(stored.clone()),
+                },
+                _ => Ok(None),
+            }
+        }
+
+        let item = PositionLog::default();
+        let documents = match db
+            .search(
+                item.get_collection_name(),
+                doc! { "id": { "$gt": 0 } },
+                SearchMode::Ascending,
+                None,
+                None,
+                "id",
+            )
+            .await
+        {
+            Ok(documents) => documents,
+            Err(e) if e.is_not_found() => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut num = 0;
+        for document in documents {
+            let Some(id) = document.get("id").cloned() else {
+                continue;
+            };
+            let mut set = Document::new();
+            for (key, value) in [
+                ("state", canonical::<PositionState>(document.get("state"))),
+                (
+                    "position_type",
+                    canonical::<PositionType>(document.get("position_type")),
+                ),
+            ] {
+                match value {
+                    Ok(Some(value)) => {
+                        set.insert(key, value);
+                    }
+                    Ok(None) => {}
+                    Err(stored) => {
+                        log::warn!(
+                            "migrate_position_names: id = {}, {} = {:?}",
+                            id,
+                            key,
+                            stored
+                        )
+                    }
+                }
+            }
+            if set.is_empty() {
+                continue;
+            }
+            db.update_one(
+                item.get_collection_name(),
+                doc! { "id": id },
+                doc! { "$set": set },
+                false,
+            )
+            .await?;
+            num += 1;
+        }
+        log::info!("migrate_position_names: num = {}", num);
+        Ok(num)
+    }
+
+    /// Sets `timestamp` on entries stored with only a date string and
+    /// returns how many were updated. Dates that cannot be parsed are
+    /// logged and left as they are.
+    pub async fn migrate_pnl_dates(db: &dyn StorageBackend) -> Result<usize, DbError> {
+        let item = PnlLog::default();
+        let pnl_logs: Vec<PnlLog> = search_stream(
+            db,
+            &item,
+            SearchMode::Ascending,
+            COPY_PAGE_SIZE,
+            Some("id"),
+            None,
+        )
+        .try_filter(|log| futures::future::ready(log.timestamp.is_none()))
+        .try_collect()
+        .await?;
+
+        let mut num = 0;
+        for mut log in pnl_logs {
+            let Some(timestamp) = PnlLog::parse_date(&log.date) else {
+                log::warn!("migrate_pnl_dates: id = {:?}, date = {}", log.id, log.date);
+                continue;
+            };
+            log.timestamp = Some(timestamp);
+            update_item(db, &log).await?;
+            num += 1;
+        }
+        log::info!("migrate_pnl_dates: num = {}", num);
+        Ok(num)
+    }
+
+    /// The default state, or its defaults if none is stored.
+    pub async fn get_app_state(db: &dyn StorageBackend) -> AppState {
+        Self::get_instance_app_state(db, None).await
+    }
+
+    /// The state of `instance_key`, or its defaults if none is stored.
+    pub async fn get_app_state_for(db: &dyn StorageBackend, instance_key: &str) -> AppState {
+        Self::get_instance_app_state(db, Some(instance_key)).await
+    }
+
+    async fn get_instance_app_state(
+        db: &dyn StorageBackend,
+        instance_key: Option<&str>,
+    ) -> AppState {
+        let item = match instance_key {
+            Some(instance_key) => AppState::for_instance(instance_key),
+            None => AppState::default(),
+        };
+        match Self::find_app_state(db, instance_key).await {
+            Ok(stored) => stored.unwrap_or(item),
             Err(e) => {
                 log::warn!("get_app_state: {:?}", e);
                 item
@@ -597,13 +2068,237 @@ impl TransactionLog {
         }
     }
 
-    async fn delete_app_state(db: &Database) -> Result<(), Box<dyn error::Error>> {
+    async fn find_app_state(
+        db: &dyn StorageBackend,
+        instance_key: Option<&str>,
+    ) -> Result<Option<AppState>, DbError> {
+        let filter = AppState::instance_query(instance_key);
+        match search_items(
+            db,
+            &AppState::default(),
+            SearchMode::Ascending,
+            Some(1),
+            None,
+            None,
+            Some(filter),
+        )
+        .await
+        {
+            Ok(items) => Ok(items.into_iter().next()),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Every stored state, the default one first.
+    pub async fn list_app_states(db: &dyn StorageBackend) -> Result<Vec<AppState>, DbError> {
+        match search_items(
+            db,
+            &AppState::default(),
+            SearchMode::Ascending,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+        {
+            Err(e) if e.is_not_found() => Ok(vec![]),
+            result => result,
+        }
+    }
+
+    /// Deletes the state of `instance_key`, if there is one.
+    pub async fn delete_app_state_for(
+        db: &dyn StorageBackend,
+        instance_key: &str,
+    ) -> Result<(), DbError> {
+        delete_item(db, &AppState::for_instance(instance_key)).await
+    }
+
+    /// Deletes the default state only; the states of other instances
+    /// sharing the database are kept.
+    async fn delete_app_state(db: &dyn StorageBackend) -> Result<(), DbError> {
+        match delete_item(db, &AppState::default()).await {
+            Err(e) if e.is_not_found() => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Stores the defaults for `instance_key` unless a state is stored. A
+    /// keyed state gets the id after the largest one in use.
+    async fn create_app_state(
+        db: &dyn StorageBackend,
+        instance_key: Option<&str>,
+    ) -> Result<(), DbError> {
+        retry_on_conflict(3, || async {
+            if Self::find_app_state(db, instance_key).await?.is_some() {
+                return Ok(());
+            }
+            let item = match instance_key {
+                Some(instance_key) => {
+                    let last_id = match search_items(
+                        db,
+                        &AppState::default(),
+                        SearchMode::Descending,
+                        Some(1),
+                        None,
+                        None,
+                        None,
+                    )
+                    .await
+                    {
+                        Ok(items) => items.first().map_or(0, |item| item.id),
+                        Err(e) if e.is_not_found() => 0,
+                        Err(e) => return Err(e),
+                    };
+                    AppState {
+                        id: last_id.max(1) + 1,
+                        ..AppState::for_instance(instance_key)
+                    }
+                }
+                None => AppState::default(),
+            };
+            // Someone else created this state or took the id.
+            match insert_item(db, &item).await {
+                Err(DbError::DuplicateKey(e)) => Err(DbError::Conflict(e)),
+                result => result,
+            }
+        })
+        .await
+    }
+
+    /// Applies `patch` to the default state in one atomic update, creating
+    /// the state if there is none, and returns the state as stored
+    /// afterwards. A patch made with `if_version` fails with
+    /// `DbError::Conflict` if the state has changed.
+    pub async fn apply_app_state_patch(
+        db: &dyn StorageBackend,
+        patch: &AppStatePatch,
+    ) -> Result<AppState, DbError> {
+        Self::patch_app_state(db, None, patch).await
+    }
+
+    /// Same as `apply_app_state_patch`, for the state of `instance_key`.
+    pub async fn apply_app_state_patch_for(
+        db: &dyn StorageBackend,
+        instance_key: &str,
+        patch: &AppStatePatch,
+    ) -> Result<AppState, DbError> {
+        Self::patch_app_state(db, Some(instance_key), patch).await
+    }
+
+    async fn patch_app_state(
+        db: &dyn StorageBackend,
+        instance_key: Option<&str>,
+        patch: &AppStatePatch,
+    ) -> Result<AppState, DbError> {
+        let collection = AppState::default().get_collection_name().to_owned();
+        let update = patch.to_update()?;
+        let mut query = AppState::instance_query(instance_key);
+        if let Some(version) = patch.expected_version {
+            query = with_version(query, version);
+        }
+        let conflict = || {
+            DbError::Conflict(format!(
+                "app state is no longer at version {}",
+                patch.expected_version.unwrap_or_default()
+            ))
+        };
+
+        let updated = db
+            .find_one_and_update(&collection, query.clone(), update.clone(), false)
+            .await?;
+        let document = match updated {
+            Some(document) => document,
+            // Nothing stored yet, which is version zero.
+            None if patch.expected_version.unwrap_or_default() == 0 => {
+                Self::create_app_state(db, instance_key).await?;
+                db.find_one_and_update(&collection, query, update, false)
+                    .await?
+                    .ok_or_else(conflict)?
+            }
+            None => return Err(conflict()),
+        };
+        Ok(bson::from_document(document)?)
+    }
+
+    /// Writes `state` as a whole if the stored state of its
+    /// `instance_key` is still at `state.version`, and returns it with the
+    /// new version. Otherwise fails with `DbError::Conflict`, so that the
+    /// caller can read the state again and redo its change, e.g. with
+    /// `retry_on_conflict`.
+    pub async fn update_app_state_if_unchanged(
+        db: &dyn StorageBackend,
+        state: &AppState,
+    ) -> Result<AppState, DbError> {
+        let mut document = bson::to_document(state)?;
+        // The id of a keyed state is assigned when it is created.
+        for key in ["id", "instance_key", "version"] {
+            document.remove(key);
+        }
+        let patch = AppStatePatch {
+            set: document,
+            expected_version: Some(state.version),
+            ..Default::default()
+        };
+        Self::patch_app_state(db, state.instance_key.as_deref(), &patch).await
+    }
+
+    /// Rewrites states stored with decimal strings or doubles so that their
+    /// numbers are stored as Decimal128, and drops null `instance_key`s,
+    /// which the unique index on it would take as the same key. Returns
+    /// whether anything was rewritten.
+    pub async fn migrate_app_state(db: &dyn StorageBackend) -> Result<bool, DbError> {
         let item = AppState::default();
-        delete_item_all(db, &item).await
+        let documents = match db
+            .search(
+                item.get_collection_name(),
+                doc! { "id": { "$gt": 0 }},
+                SearchMode::Ascending,
+                None,
+                None,
+                "id",
+            )
+            .await
+        {
+            Ok(documents) => documents,
+            Err(e) if e.is_not_found() => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let mut migrated = false;
+        for document in documents {
+            let legacy = AppStateField::ALL.iter().any(|field| {
+                matches!(
+                    document.get(field.key()),
+                    Some(Bson::String(_) | Bson::Double(_))
+                )
+            }) || matches!(document.get("instance_key"), Some(Bson::Null));
+            if !legacy {
+                continue;
+            }
+            let state: AppState = bson::from_document(document)?;
+            // By id, as a state with a null key need not be the default one.
+            db.replace_one(
+                item.get_collection_name(),
+                doc! { "id": state.id },
+                bson::to_document(&state)?,
+                false,
+            )
+            .await?;
+            log::info!("migrate_app_state: id = {}", state.id);
+            migrated = true;
+        }
+        Ok(migrated)
     }
 
+    /// Positional form of `apply_app_state_patch`. Values are rounded,
+    /// `max_dd` only grows, `cumulative_return` and `cumulative_dd` are
+    /// added and `error_time` is appended.
+    #[deprecated(note = "use apply_app_state_patch")]
+    #[allow(clippy::too_many_arguments)]
     pub async fn update_app_state(
-        db: &Database,
+        db: &dyn StorageBackend,
         last_execution_time: Option<SystemTime>,
         last_equity: Option<Decimal>,
         ave_dd: Option<Decimal>,
@@ -617,81 +2312,57 @@ impl TransactionLog {
         error_time: Option<String>,
         max_invested_amount: Option<Decimal>,
         fund_configs: Option<Vec<FundConfig>>,
-    ) -> Result<(), Box<dyn error::Error>> {
-        let item = AppState::default();
-        let mut item = match search_item(db, &item, Some(1), Some("id")).await {
-            Ok(prev_item) => prev_item,
-            Err(_) => item,
-        };
-
-        if last_execution_time.is_some() {
-            item.last_execution_time = last_execution_time;
-        }
-
-        if let Some(last_equity) = last_equity {
-            item.last_equity = Some(last_equity.round());
-        }
+    ) -> Result<(), DbError> {
+        use AppStateField::*;
 
-        if let Some(ave_dd) = ave_dd {
-            item.ave_dd = Some(ave_dd.round());
+        let mut patch = AppStatePatch::new().curcuit_break(curcuit_break);
+        if let Some(time) = last_execution_time {
+            patch = patch.last_execution_time(time);
         }
-
-        if let Some(max_dd_val) = max_dd {
-            if item
-                .max_dd
-                .map_or(true, |item_max_dd| max_dd_val > item_max_dd)
-            {
-                item.max_dd = Some(max_dd_val.round());
+        for (field, value) in [
+            (LastEquity, last_equity),
+            (AveDd, ave_dd),
+            (MaxInvestedAmount, max_invested_amount),
+        ] {
+            if let Some(value) = value {
+                patch = patch.set(field, value.round());
             }
         }
-
-        if let Some(cumulative_return) = cumulative_return {
-            item.cumulative_return += cumulative_return.round();
-        }
-
-        if let Some(cumulative_dd) = cumulative_dd {
-            item.cumulative_dd += cumulative_dd.round();
-        }
-
-        if score.is_some() {
-            item.score = score;
+        for (field, value) in [(Score, score), (Score2, score_2), (Score3, score_3)] {
+            if let Some(value) = value {
+                patch = patch.set(field, value);
+            }
         }
-
-        if score_2.is_some() {
-            item.score_2 = score_2;
+        if let Some(max_dd) = max_dd {
+            patch = patch.max(MaxDd, max_dd.round());
         }
-
-        if score_3.is_some() {
-            item.score_3 = score_3;
+        for (field, value) in [
+            (CumulativeReturn, cumulative_return),
+            (CumulativeDd, cumulative_dd),
+        ] {
+            if let Some(value) = value {
+                patch = patch.add(field, value.round());
+            }
         }
-
-        item.curcuit_break = curcuit_break;
-
         if let Some(error_time) = error_time {
-            item.error_time.push(error_time);
-        }
-
-        if let Some(max_invested_amount) = max_invested_amount {
-            item.max_invested_amount = max_invested_amount.round();
+            patch = patch.push_error_time(error_time);
         }
-
         if let Some(fund_configs) = fund_configs {
-            item.fund_configs = Some(fund_configs);
+            patch = patch.fund_configs(fund_configs);
         }
 
-        update_item(db, &item).await?;
+        Self::apply_app_state_patch(db, &patch).await?;
         Ok(())
     }
 
     pub fn db_w_name(&self) -> &str {
-        &self.db_w_name
+        self.db_w.name()
     }
 }
 
 #[derive(Clone)]
 pub struct ModelParams {
-    db_name: String,
-    client_holder: Arc<Mutex<ClientHolder>>,
+    db: DbHandle,
     collection_name: String,
     save_to_db: bool,
     file_path: Option<String>,
@@ -705,41 +2376,38 @@ impl ModelParams {
         file_path: Option<String>,
     ) -> Self {
         // Set up the DB client holder
-        let mut client_options = match ClientOptions::parse(mongodb_uri).await {
-            Ok(client_options) => client_options,
-            Err(e) => {
-                panic!("{:?}", e);
-            }
-        };
-        let tls_options = TlsOptions::builder().build();
-        client_options.tls = Some(Tls::Enabled(tls_options));
-        let client_holder = Arc::new(Mutex::new(ClientHolder::new(client_options)));
+        let client_holder = mongo_client_holder(mongodb_uri).await;
 
         ModelParams {
-            db_name: db_name.to_owned(),
-            client_holder,
+            db: DbHandle::Mongo {
+                client_holder,
+                db_name: db_name.to_owned(),
+            },
             collection_name: "model_params".to_owned(),
             save_to_db,
             file_path,
         }
     }
 
-    async fn get_db(&self) -> Option<Database> {
-        let db = match database::get(&self.client_holder, &self.db_name).await {
-            Ok(db) => Some(db),
-            Err(e) => {
-                log::error!("get_db: {:?}", e);
-                None
-            }
-        };
-        db
+    /// Creates model storage on top of an arbitrary storage backend.
+    pub fn with_backend(
+        backend: Arc<dyn StorageBackend>,
+        save_to_db: bool,
+        file_path: Option<String>,
+    ) -> Self {
+        ModelParams {
+            db: DbHandle::Backend(backend),
+            collection_name: "model_params".to_owned(),
+            save_to_db,
+            file_path,
+        }
     }
 
-    pub async fn save_model(
-        &self,
-        key: &str,
-        model: &SerializableModel,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    async fn get_db(&self) -> Option<Arc<dyn StorageBackend>> {
+        self.db.get().await
+    }
+
+    pub async fn save_model(&self, key: &str, model: &SerializableModel) -> Result<(), DbError> {
         if self.save_to_db {
             self.save_model_to_db(key, model).await
         } else {
@@ -747,10 +2415,7 @@ impl ModelParams {
         }
     }
 
-    pub async fn load_model(
-        &self,
-        key: &str,
-    ) -> Result<SerializableModel, Box<dyn std::error::Error>> {
+    pub async fn load_model(&self, key: &str) -> Result<SerializableModel, DbError> {
         if self.save_to_db {
             self.load_model_from_db(key).await
         } else {
@@ -758,13 +2423,11 @@ impl ModelParams {
         }
     }
 
-    async fn save_model_to_db(
-        &self,
-        key: &str,
-        model: &SerializableModel,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let db = self.get_db().await.ok_or("no db")?;
-        let collection: Collection<Document> = db.collection(&self.collection_name);
+    async fn save_model_to_db(&self, key: &str, model: &SerializableModel) -> Result<(), DbError> {
+        let db = self
+            .get_db()
+            .await
+            .ok_or_else(|| DbError::Connection("no db".to_string()))?;
         let serialized_model = bincode::serialize(model)?;
 
         let document = doc! {
@@ -775,36 +2438,45 @@ impl ModelParams {
             })
         };
 
-        collection
-            .update_one(
-                doc! { "key": key },
-                doc! { "$set": document },
-                mongodb::options::UpdateOptions::builder()
-                    .upsert(true)
-                    .build(),
-            )
-            .await?;
-        Ok(())
+        db.update_one(
+            &self.collection_name,
+            doc! { "key": key },
+            doc! { "$set": document },
+            true,
+        )
+        .await
     }
 
-    async fn load_model_from_db(
-        &self,
-        key: &str,
-    ) -> Result<SerializableModel, Box<dyn std::error::Error>> {
-        let db = self.get_db().await.ok_or("no db")?;
-        let collection: Collection<Document> = db.collection(&self.collection_name);
-
+    async fn load_model_from_db(&self, key: &str) -> Result<SerializableModel, DbError> {
+        let db = self
+            .get_db()
+            .await
+            .ok_or_else(|| DbError::Connection("no db".to_string()))?;
         let filter = doc! { "key": key };
-        let document = collection
-            .find_one(filter, None)
-            .await?
-            .ok_or("No model found in the collection")?;
+        let document = db
+            .search(
+                &self.collection_name,
+                filter,
+                SearchMode::Ascending,
+                Some(1),
+                None,
+                "id",
+            )
+            .await
+            .map_err(|e| match e {
+                DbError::NotFound(_) => {
+                    DbError::NotFound("No model found in the collection".to_string())
+                }
+                e => e,
+            })?
+            .pop()
+            .ok_or_else(|| DbError::NotFound("No model found in the collection".to_string()))?;
 
         if let Some(Bson::Binary(model_bytes)) = document.get("model") {
             let model: SerializableModel = bincode::deserialize(&model_bytes.bytes)?;
             Ok(model)
         } else {
-            Err("Invalid data format".into())
+            Err(DbError::Serialization("Invalid data format".into()))
         }
     }
 
@@ -812,7 +2484,7 @@ impl ModelParams {
         &self,
         key: &str,
         model: &SerializableModel,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), DbError> {
         let serialized_model = bincode::serialize(model)?;
         let file_name = format!("{}.bin", key);
 
@@ -827,10 +2499,7 @@ impl ModelParams {
         Ok(())
     }
 
-    async fn load_model_from_file(
-        &self,
-        key: &str,
-    ) -> Result<SerializableModel, Box<dyn std::error::Error>> {
+    async fn load_model_from_file(&self, key: &str) -> Result<SerializableModel, DbError> {
         let file_name = format!("{}.bin", key);
 
         let file_path = if let Some(ref dir) = self.file_path {
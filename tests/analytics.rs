@@ -0,0 +1,219 @@
+// Performance reports from stored positions and balances.
+
+use debot_db::{
+    balance_performance, best_pnl_days, pnl_logs, pnl_rollup, position_performance,
+    position_performance_by, worst_pnl_days, MemoryBackend, PerformanceGroup, PnlFilter, PnlLog,
+    PnlPeriod, PositionFilter, PositionLog, PositionState, TradingStrategy, TransactionLog,
+    TrendType,
+};
+use rust_decimal::Decimal;
+
+fn closed(id: u32, fund_name: &str, pnl: i64, strategy: Option<TradingStrategy>) -> PositionLog {
+    PositionLog {
+        id: Some(id),
+        fund_name: fund_name.to_string(),
+        trading_strategy: strategy,
+        state: PositionState::Closed,
+        open_timestamp: id as i64 * 100,
+        close_timestamp: Some(id as i64 * 100 + 60),
+        pnl: Decimal::new(pnl, 0),
+        ..Default::default()
+    }
+}
+
+async fn positions() -> MemoryBackend {
+    let db = MemoryBackend::new();
+    let inago = Some(TradingStrategy::Inago(TrendType::Up));
+    let open = PositionLog {
+        id: Some(5),
+        fund_name: "a".to_string(),
+        state: PositionState::Open,
+        open_timestamp: 50,
+        pnl: Decimal::new(1000, 0),
+        ..Default::default()
+    };
+    // Never filled, so it is not a trade.
+    let canceled = PositionLog {
+        state: PositionState::Canceled,
+        ..closed(6, "a", 0, inago)
+    };
+    for position in [
+        closed(1, "a", 10, inago),
+        closed(2, "a", -5, inago),
+        closed(3, "b", 10, Some(TradingStrategy::MarketMake)),
+        closed(4, "b", -3, None),
+        open,
+        canceled,
+    ] {
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+    db
+}
+
+#[tokio::test]
+async fn closed_positions_are_summarized() {
+    let db = positions().await;
+    let report = position_performance(&db, &PositionFilter::default())
+        .await
+        .unwrap();
+
+    let equity: Vec<_> = report.equity_curve.iter().map(|p| p.equity).collect();
+    assert_eq!(equity, [10, 5, 15, 12].map(Decimal::from));
+    assert_eq!(report.total_pnl, Decimal::from(12));
+    assert_eq!(report.max_drawdown, Decimal::from(5));
+    // Drawdowns of 5 and 3, the last one not recovered.
+    assert_eq!(report.average_drawdown, Decimal::from(4));
+    assert_eq!(report.calmar_ratio, Some(Decimal::new(24, 1)));
+    assert_eq!(report.num_trades, 4);
+    assert_eq!(report.win_rate, Some(Decimal::new(5, 1)));
+    assert_eq!(report.profit_factor, Some(Decimal::new(25, 1)));
+    assert_eq!(report.expectancy, Some(Decimal::from(3)));
+    assert_eq!(report.average_holding_time, Some(60));
+    // mean 3, sample deviation sqrt(66), downside deviation sqrt(8.5)
+    assert_eq!(
+        report.sharpe_ratio.unwrap().round_dp(3),
+        Decimal::new(369, 3)
+    );
+    assert_eq!(
+        report.sortino_ratio.unwrap().round_dp(3),
+        Decimal::new(1029, 3)
+    );
+}
+
+#[tokio::test]
+async fn reports_are_grouped() {
+    let db = positions().await;
+    let filter = PositionFilter::default();
+
+    let by_fund = position_performance_by(&db, &filter, PerformanceGroup::Fund)
+        .await
+        .unwrap();
+    assert_eq!(by_fund.len(), 2);
+    assert_eq!(by_fund["a"].total_pnl, Decimal::from(5));
+    assert_eq!(by_fund["b"].max_drawdown, Decimal::from(3));
+
+    let by_strategy = position_performance_by(&db, &filter, PerformanceGroup::Strategy)
+        .await
+        .unwrap();
+    assert_eq!(
+        by_strategy.keys().collect::<Vec<_>>(),
+        ["Inago(Up)", "MarketMake"]
+    );
+    assert_eq!(by_strategy["Inago(Up)"].num_trades, 2);
+    assert_eq!(by_strategy["MarketMake"].profit_factor, None);
+}
+
+#[tokio::test]
+async fn balance_entries_form_the_equity_curve() {
+    let db = MemoryBackend::new();
+    assert_eq!(
+        balance_performance(&db, &PnlFilter::default())
+            .await
+            .unwrap()
+            .equity_curve,
+        []
+    );
+
+    // Ids have wrapped around after the first entry.
+    for (id, day, pnl) in [(9, 1, 100), (1, 2, -50), (2, 3, 30)] {
+        let item = PnlLog {
+            id: Some(id),
+            date: format!("2024-01-0{}", day),
+            pnl: Decimal::from(pnl),
+            ..Default::default()
+        };
+        TransactionLog::insert_pnl(&db, item).await.unwrap();
+    }
+    let report = balance_performance(&db, &PnlFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(report.equity_curve[2].label, "2024-01-03");
+    assert_eq!(report.equity_curve[2].equity, Decimal::from(80));
+    assert_eq!(report.max_drawdown, Decimal::from(50));
+    assert_eq!(report.calmar_ratio, Some(Decimal::new(16, 1)));
+    assert_eq!(report.num_trades, 0);
+    assert_eq!(report.win_rate, None);
+}
+
+#[tokio::test]
+async fn pnl_is_rolled_up_by_period() {
+    let db = MemoryBackend::new();
+    // Entries stored before `timestamp` was added.
+    for (id, date, pnl) in [
+        (1, "2024-01-29T23:00:00+0000", 5),
+        (2, "2024-01-31", -20),
+        (3, "not a date", 1),
+    ] {
+        let item = PnlLog {
+            id: Some(id),
+            date: date.to_string(),
+            pnl: Decimal::from(pnl),
+            ..Default::default()
+        };
+        TransactionLog::insert_pnl(&db, item).await.unwrap();
+    }
+    let day = 86_400;
+    let feb_1 = 1_706_745_600;
+    for (id, timestamp, pnl, fund_name) in [
+        (4, feb_1, 10, "a"),
+        (5, feb_1 + 3600, 7, "b"),
+        (6, feb_1 + 2 * day, -4, "a"),
+    ] {
+        let item = PnlLog {
+            fund_name: Some(fund_name.to_string()),
+            currency: Some("USD".to_string()),
+            ..PnlLog::new(Some(id), timestamp, Decimal::from(pnl))
+        };
+        TransactionLog::insert_pnl(&db, item).await.unwrap();
+    }
+
+    let all = PnlFilter::default();
+    let days = pnl_rollup(&db, &all, PnlPeriod::Day).await.unwrap();
+    let days: Vec<_> = days
+        .iter()
+        .map(|d| (d.date.as_str(), d.pnl, d.cumulative_pnl))
+        .collect();
+    assert_eq!(
+        days,
+        [
+            ("2024-01-29", 5.into(), 5.into()),
+            ("2024-01-31", (-20).into(), (-15).into()),
+            ("2024-02-01", 17.into(), 2.into()),
+            ("2024-02-03", (-4).into(), (-2).into()),
+        ]
+    );
+
+    let weeks = pnl_rollup(&db, &all, PnlPeriod::Week).await.unwrap();
+    assert_eq!(weeks.len(), 1);
+    assert_eq!(weeks[0].date, "2024-01-29");
+    assert_eq!(weeks[0].num_entries, 5);
+    let months = pnl_rollup(&db, &all, PnlPeriod::Month).await.unwrap();
+    assert_eq!(months[1].date, "2024-02-01");
+    assert_eq!(months[1].pnl, Decimal::from(13));
+
+    let logs = pnl_logs(&db, &all).await.unwrap();
+    assert_eq!(best_pnl_days(&logs, 1)[0].date, "2024-02-01");
+    assert_eq!(worst_pnl_days(&logs, 2)[1].date, "2024-02-03");
+
+    // Time bounds only see migrated entries.
+    let filter = PnlFilter {
+        to: Some(feb_1),
+        ..Default::default()
+    };
+    assert!(pnl_logs(&db, &filter).await.unwrap().is_empty());
+    assert_eq!(TransactionLog::migrate_pnl_dates(&db).await.unwrap(), 2);
+    let logs = pnl_logs(&db, &filter).await.unwrap();
+    assert_eq!(logs.len(), 2);
+    assert_eq!(logs[1].timestamp, Some(feb_1 - day));
+
+    let filter = PnlFilter {
+        fund_name: Some("a".to_string()),
+        from: Some(feb_1),
+        ..Default::default()
+    };
+    let report = balance_performance(&db, &filter).await.unwrap();
+    assert_eq!(report.total_pnl, Decimal::from(6));
+    assert_eq!(report.equity_curve[0].timestamp, Some(feb_1));
+}
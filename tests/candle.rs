@@ -0,0 +1,137 @@
+// Candle aggregation with the Rust fallback and, when available, MongoDB.
+//
+// The MongoDB test is ignored by default; run it with
+// `MONGODB_TEST_URI=mongodb://localhost:27017 cargo test -- --ignored`.
+
+use debot_db::{
+    aggregate_candles, Candle, CandleInterval, MemoryBackend, PriceLog, PricePoint, StorageBackend,
+    TransactionLog,
+};
+use mongodb::Client;
+use rust_decimal::Decimal;
+
+fn tick(
+    id: u32,
+    token_name: &str,
+    timestamp: i64,
+    price: i64,
+    volume: Option<i64>,
+    funding_rate: Option<i64>,
+) -> PriceLog {
+    PriceLog {
+        id: Some(id),
+        name: "dex".to_string(),
+        token_name: token_name.to_string(),
+        price_point: PricePoint {
+            timestamp,
+            price: Decimal::new(price, 0),
+            volume: volume.map(|v| Decimal::new(v, 0)),
+            num_trades: volume.map(|_| 2),
+            funding_rate: funding_rate.map(|v| Decimal::new(v, 4)),
+            ..Default::default()
+        },
+    }
+}
+
+fn ticks() -> Vec<PriceLog> {
+    vec![
+        tick(1, "ETH", 60, 100, Some(1), Some(1)),
+        tick(2, "ETH", 75, 110, Some(3), None),
+        tick(3, "ETH", 90, 90, None, Some(2)),
+        tick(4, "ETH", 119, 105, Some(1), None),
+        tick(5, "ETH", 120, 106, None, None),
+        tick(6, "BTC", 61, 50000, Some(2), None),
+    ]
+}
+
+fn expected() -> Vec<Candle> {
+    vec![
+        Candle {
+            name: "dex".to_string(),
+            token_name: "BTC".to_string(),
+            start: 60,
+            open: Decimal::new(50000, 0),
+            high: Decimal::new(50000, 0),
+            low: Decimal::new(50000, 0),
+            close: Decimal::new(50000, 0),
+            volume: Decimal::new(2, 0),
+            num_trades: 2,
+            vwap: Some(Decimal::new(50000, 0)),
+            ..Default::default()
+        },
+        Candle {
+            name: "dex".to_string(),
+            token_name: "ETH".to_string(),
+            start: 60,
+            open: Decimal::new(100, 0),
+            high: Decimal::new(110, 0),
+            low: Decimal::new(90, 0),
+            close: Decimal::new(105, 0),
+            volume: Decimal::new(5, 0),
+            num_trades: 6,
+            // (100 * 1 + 110 * 3 + 105 * 1) / 5
+            vwap: Some(Decimal::new(107, 0)),
+            funding_rate: Some(Decimal::new(2, 4)),
+            ..Default::default()
+        },
+        Candle {
+            name: "dex".to_string(),
+            token_name: "ETH".to_string(),
+            start: 120,
+            open: Decimal::new(106, 0),
+            high: Decimal::new(106, 0),
+            low: Decimal::new(106, 0),
+            close: Decimal::new(106, 0),
+            ..Default::default()
+        },
+    ]
+}
+
+async fn assert_candles(db: &dyn StorageBackend) {
+    for price in ticks() {
+        TransactionLog::update_price(db, price).await.unwrap();
+    }
+
+    let candles =
+        TransactionLog::get_candles(db, CandleInterval::OneMinute, 0, 1000, Some("dex"), None)
+            .await
+            .unwrap();
+    assert_eq!(candles, expected());
+
+    let candles =
+        TransactionLog::get_candles(db, CandleInterval::OneHour, 0, 120, None, Some("ETH"))
+            .await
+            .unwrap();
+    assert_eq!(candles.len(), 1);
+    assert_eq!(candles[0].start, 0);
+    assert_eq!(candles[0].close, Decimal::new(105, 0));
+}
+
+#[test]
+fn candles_are_built_from_ticks() {
+    assert_eq!(
+        aggregate_candles(ticks(), CandleInterval::OneMinute),
+        expected()
+    );
+    assert_eq!(
+        "5m".parse::<CandleInterval>().unwrap(),
+        CandleInterval::FiveMinutes
+    );
+    assert!("2m".parse::<CandleInterval>().is_err());
+}
+
+#[tokio::test]
+async fn candles_fall_back_to_rust_aggregation() {
+    assert_candles(&MemoryBackend::new()).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn candles_use_the_aggregation_pipeline() {
+    let uri = std::env::var("MONGODB_TEST_URI")
+        .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+    let client = Client::with_uri_str(&uri).await.unwrap();
+    let db = client.database("debot_db_test_candle");
+    db.drop(None).await.unwrap();
+    assert_candles(&db).await;
+}
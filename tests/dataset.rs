@@ -0,0 +1,109 @@
+// Training datasets built from closed positions.
+
+use debot_db::{
+    build_dataset, write_dataset_csv, CandlePattern, Dataset, DebugLog, FeatureSchema,
+    FeatureVector, MemoryBackend, PositionFilter, PositionLog, PositionState, TransactionLog,
+    LABEL_COLUMNS,
+};
+use rust_decimal::Decimal;
+
+fn closed(id: u32, open_timestamp: i64, pnl: i64) -> PositionLog {
+    PositionLog {
+        id: Some(id),
+        state: PositionState::Closed,
+        open_timestamp,
+        close_timestamp: Some(open_timestamp + 60),
+        asset_in_usd: Decimal::new(100, 0),
+        close_price: Decimal::new(2000, 0),
+        pnl: Decimal::new(pnl, 0),
+        debug: DebugLog {
+            input_1: Decimal::new(id as i64, 0),
+            input_30: CandlePattern::Doji,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+async fn positions() -> MemoryBackend {
+    let db = MemoryBackend::new();
+    let open = PositionLog {
+        id: Some(3),
+        state: PositionState::Open,
+        open_timestamp: 15,
+        ..Default::default()
+    };
+    let other_schema = PositionLog {
+        features: Some(FeatureVector::default()),
+        ..closed(4, 25, 1)
+    };
+    let canceled = PositionLog {
+        state: PositionState::Canceled,
+        ..closed(5, 30, 0)
+    };
+    for position in [
+        closed(1, 20, -5),
+        closed(2, 10, 10),
+        open,
+        other_schema,
+        canceled,
+    ] {
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+    db
+}
+
+#[tokio::test]
+async fn closed_positions_become_rows() {
+    let db = positions().await;
+    let schema = FeatureSchema::debug_log();
+
+    let dataset = build_dataset(&db, &schema, &PositionFilter::default())
+        .await
+        .unwrap();
+    assert_eq!(dataset.position_ids, vec![2, 1]);
+    assert_eq!(dataset.feature_columns.len(), 29 + 10 * 16);
+    assert_eq!(dataset.feature_columns[29], "input_30.None");
+    assert!(dataset.features.iter().all(|row| row.len() == 29 + 160));
+    assert_eq!(dataset.features[0][0], 2.0);
+    // input_30 is a doji, the sixth pattern.
+    assert_eq!(dataset.features[0][29 + 5], 1.0);
+    // pnl, pnl_sign, return_ratio, holding_time, close_price
+    assert_eq!(dataset.labels[0], vec![10.0, 1.0, 0.1, 60.0, 2000.0]);
+    assert_eq!(dataset.labels[1][1], -1.0);
+
+    let restored = Dataset::from_bytes(&dataset.to_bytes().unwrap()).unwrap();
+    assert_eq!(restored, dataset);
+
+    let filter = PositionFilter {
+        open_from: Some(15),
+        ..Default::default()
+    };
+    let dataset = build_dataset(&db, &schema, &filter).await.unwrap();
+    assert_eq!(dataset.position_ids, vec![1]);
+}
+
+#[tokio::test]
+async fn dataset_is_written_as_csv() {
+    let db = positions().await;
+    let schema = FeatureSchema::debug_log();
+
+    let mut csv = vec![];
+    let num = write_dataset_csv(&db, &schema, &PositionFilter::default(), &mut csv)
+        .await
+        .unwrap();
+    assert_eq!(num, 2);
+
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    let header: Vec<_> = lines[0].split(',').collect();
+    assert_eq!(header[0], "position_id");
+    assert_eq!(header[header.len() - 5..], LABEL_COLUMNS);
+    let row: Vec<_> = lines[1].split(',').collect();
+    assert_eq!(row.len(), header.len());
+    assert_eq!(row[0], "2");
+    assert_eq!(row[row.len() - 5..], ["10", "1", "0.1", "60", "2000"]);
+}
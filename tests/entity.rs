@@ -0,0 +1,220 @@
+// Entity round trips against the in-memory backend and a live MongoDB.
+//
+// The MongoDB tests are ignored by default; run them with
+// `MONGODB_TEST_URI=mongodb://localhost:27017 cargo test -- --ignored`.
+
+use debot_db::{
+    delete_item, delete_item_all, insert_item, search_item, search_items, update_item, AppState,
+    DbError, Entity, MemoryBackend, PnlLog, PositionLog, PositionState, PriceLog, PricePoint,
+    SearchMode, StorageBackend,
+};
+use mongodb::{Client, Database};
+use rust_decimal::Decimal;
+
+async fn test_db(name: &str) -> Database {
+    let uri = std::env::var("MONGODB_TEST_URI")
+        .unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+    let client = Client::with_uri_str(&uri).await.unwrap();
+    let db = client.database(&format!("debot_db_test_{}", name));
+    db.drop(None).await.unwrap();
+    db
+}
+
+fn position(id: u32) -> PositionLog {
+    PositionLog {
+        id: Some(id),
+        fund_name: "fund".to_string(),
+        token_name: "BTC".to_string(),
+        state: PositionState::Open,
+        open_timestamp: id as i64,
+        ..Default::default()
+    }
+}
+
+fn price(id: u32) -> PriceLog {
+    PriceLog {
+        id: Some(id),
+        name: "dex".to_string(),
+        token_name: "BTC".to_string(),
+        price_point: PricePoint {
+            timestamp: id as i64,
+            price: Decimal::new(100, 0),
+            ..Default::default()
+        },
+    }
+}
+
+fn pnl(id: u32) -> PnlLog {
+    PnlLog {
+        id: Some(id),
+        date: "2024-01-01".to_string(),
+        pnl: Decimal::ONE,
+        ..Default::default()
+    }
+}
+
+async fn assert_round_trip<T, F>(db: &dyn StorageBackend, make: F, modify: fn(&mut T))
+where
+    T: Entity + Default + Send + Sync + std::fmt::Debug,
+    F: Fn(u32) -> T,
+{
+    insert_item(db, &make(1)).await.unwrap();
+    insert_item(db, &make(2)).await.unwrap();
+
+    let mut item = make(1);
+    modify(&mut item);
+    update_item(db, &item).await.unwrap();
+
+    let items = search_items(
+        db,
+        &T::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(items.len(), 2);
+
+    delete_item(db, &make(1)).await.unwrap();
+    let err = delete_item(db, &make(1)).await.unwrap_err();
+    assert!(matches!(err, DbError::NotFound(_)));
+
+    delete_item_all(db, &T::default()).await.unwrap();
+    let err = search_items(
+        db,
+        &T::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, DbError::NotFound(_)));
+}
+
+async fn assert_app_state_round_trip(db: &dyn StorageBackend) {
+    let state = AppState::default();
+
+    insert_item(db, &state).await.unwrap();
+    let err = insert_item(db, &state).await.unwrap_err();
+    assert!(matches!(err, DbError::DuplicateKey(_)));
+
+    let mut updated = state.clone();
+    updated.curcuit_break = true;
+    update_item(db, &updated).await.unwrap();
+    let stored = search_item(db, &AppState::default(), Some(1), Some("id"))
+        .await
+        .unwrap();
+    assert!(stored.curcuit_break);
+
+    delete_item(db, &state).await.unwrap();
+    let err = delete_item(db, &state).await.unwrap_err();
+    assert!(matches!(err, DbError::NotFound(_)));
+
+    insert_item(db, &state).await.unwrap();
+    delete_item_all(db, &state).await.unwrap();
+    let err = search_item(db, &AppState::default(), Some(1), Some("id"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::NotFound(_)));
+}
+
+async fn memory_db() -> MemoryBackend {
+    let db = MemoryBackend::new();
+    debot_db::create_unique_index(&db).await.unwrap();
+    db
+}
+
+#[tokio::test]
+async fn position_log_operations_in_memory() {
+    let db = memory_db().await;
+    assert_round_trip(&db, position, |p| p.state = PositionState::Closed).await;
+}
+
+#[tokio::test]
+async fn price_log_operations_in_memory() {
+    let db = memory_db().await;
+    assert_round_trip(&db, price, |p| p.price_point.price = Decimal::TWO).await;
+}
+
+#[tokio::test]
+async fn pnl_log_operations_in_memory() {
+    let db = memory_db().await;
+    assert_round_trip(&db, pnl, |p| p.pnl = Decimal::TEN).await;
+}
+
+#[tokio::test]
+async fn app_state_operations_in_memory() {
+    let db = memory_db().await;
+    assert_app_state_round_trip(&db).await;
+}
+
+#[tokio::test]
+async fn delete_without_id_is_rejected_in_memory() {
+    let db = memory_db().await;
+    let err = delete_item(&db, &PositionLog::default()).await.unwrap_err();
+    assert!(matches!(err, DbError::InvalidQuery(_)));
+}
+
+#[tokio::test]
+async fn update_without_id_is_rejected_in_memory() {
+    let db = memory_db().await;
+    let err = update_item(&db, &PositionLog::default()).await.unwrap_err();
+    assert!(matches!(err, DbError::InvalidQuery(_)));
+    let err = update_item(&db, &PriceLog::default()).await.unwrap_err();
+    assert!(matches!(err, DbError::InvalidQuery(_)));
+    assert!(search_items(
+        &db,
+        &PositionLog::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap_err()
+    .is_not_found());
+}
+
+#[tokio::test]
+#[ignore]
+async fn position_log_operations() {
+    let db = test_db("position").await;
+    assert_round_trip(&db, position, |p| p.state = PositionState::Closed).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn price_log_operations() {
+    let db = test_db("price").await;
+    assert_round_trip(&db, price, |p| p.price_point.price = Decimal::TWO).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn pnl_log_operations() {
+    let db = test_db("pnl").await;
+    assert_round_trip(&db, pnl, |p| p.pnl = Decimal::TEN).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn app_state_operations() {
+    let db = test_db("app_state").await;
+    debot_db::create_unique_index(&db).await.unwrap();
+    assert_app_state_round_trip(&db).await;
+}
+
+#[tokio::test]
+#[ignore]
+async fn delete_without_id_is_rejected() {
+    let db = test_db("no_id").await;
+    let err = delete_item(&db, &PositionLog::default()).await.unwrap_err();
+    assert!(matches!(err, DbError::InvalidQuery(_)));
+}
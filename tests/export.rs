@@ -0,0 +1,183 @@
+// CSV and Parquet export of entity collections.
+
+use debot_db::{
+    cell_to_string, export_csv, export_table, AppState, ExportOptions, FundConfig, MemoryBackend,
+    PriceLog, PricePoint, SampleTerm, SearchMode, TradingStrategy, TransactionLog,
+};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+fn price(id: u32, timestamp: i64, price: &str) -> PriceLog {
+    PriceLog {
+        id: Some(id),
+        name: "dex, inc".to_string(),
+        token_name: "ETH".to_string(),
+        price_point: PricePoint {
+            timestamp,
+            price: Decimal::from_str(price).unwrap(),
+            volume: Some(Decimal::ONE),
+            ..Default::default()
+        },
+    }
+}
+
+async fn prices() -> MemoryBackend {
+    let db = MemoryBackend::new();
+    for (id, timestamp, value) in [
+        (1, 300, "2000.123456789012345678"),
+        (2, 100, "1999.5"),
+        (3, 200, "2001"),
+    ] {
+        TransactionLog::update_price(&db, price(id, timestamp, value))
+            .await
+            .unwrap();
+    }
+    db
+}
+
+#[tokio::test]
+async fn nested_fields_become_dotted_columns() {
+    let db = prices().await;
+    let options = ExportOptions {
+        mode: SearchMode::Descending,
+        sort_key: Some("price_point.timestamp".to_string()),
+        from: Some(100),
+        to: Some(300),
+        ..Default::default()
+    };
+
+    let table = export_table(&db, &PriceLog::default(), &options)
+        .await
+        .unwrap();
+    assert_eq!(table.rows.len(), 2);
+    assert!(table.columns.contains(&"price_point.timestamp".to_string()));
+    assert!(table.columns.contains(&"price_point.debug".to_string()));
+
+    let mut csv = vec![];
+    let num = export_csv(&db, &PriceLog::default(), &options, &mut csv)
+        .await
+        .unwrap();
+    assert_eq!(num, 2);
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert!(lines[0].starts_with("id,name,token_name,price_point.timestamp,"));
+    assert!(lines[1].starts_with("3,\"dex, inc\",ETH,200,"));
+    assert!(lines[2].starts_with("2,\"dex, inc\",ETH,100,"));
+
+    let options = ExportOptions {
+        limit: Some(1),
+        sort_key: Some("price_point.timestamp".to_string()),
+        from: Some(250),
+        ..Default::default()
+    };
+    let mut csv = vec![];
+    export_csv(&db, &PriceLog::default(), &options, &mut csv)
+        .await
+        .unwrap();
+    // Decimal precision is kept.
+    assert!(String::from_utf8(csv)
+        .unwrap()
+        .contains(",2000.123456789012345678,"));
+
+    let options = ExportOptions {
+        from: Some(250),
+        ..Default::default()
+    };
+    assert!(export_table(&db, &PriceLog::default(), &options)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn app_state_is_flattened() {
+    let db = MemoryBackend::new();
+    let fund_config = FundConfig {
+        token: "ETH".to_string(),
+        trading_strategy: TradingStrategy::MarketMake,
+        balance_per_strategy: Decimal::new(100, 0),
+        risk_reward: Decimal::ONE,
+        take_profit_ratio: None,
+        atr_spread: Decimal::ONE,
+        atr_term: SampleTerm::ShortTerm,
+        entry_timeout_sec: 10,
+        max_holding_sec: 60,
+        order_size_multiplier: Decimal::ONE,
+        tick_spread: 1,
+        bias_ticks: 0,
+    };
+    let state = AppState {
+        last_execution_time: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(60)),
+        error_time: vec!["a".to_string(), "b".to_string()],
+        fund_configs: Some(vec![
+            fund_config.clone(),
+            FundConfig {
+                token: "BTC".to_string(),
+                ..fund_config
+            },
+        ]),
+        ..Default::default()
+    };
+    debot_db::insert_item(&db, &state).await.unwrap();
+
+    let table = export_table(&db, &AppState::default(), &ExportOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(table.rows.len(), 1);
+    let cell = |name: &str| {
+        let column = table.columns.iter().position(|c| c == name).unwrap();
+        cell_to_string(&table.rows[0][column])
+    };
+    assert_eq!(cell("last_execution_time.secs_since_epoch"), "60");
+    assert_eq!(cell("error_time.1"), "b");
+    assert_eq!(cell("last_equity"), "");
+    assert_eq!(cell("fund_configs.0.token"), "ETH");
+    assert_eq!(cell("fund_configs.1.token"), "BTC");
+    assert_eq!(cell("fund_configs.1.atr_term"), "ShortTerm");
+    assert!(table.columns.iter().all(|c| c != "fund_configs"));
+}
+
+#[cfg(feature = "parquet")]
+#[tokio::test]
+async fn parquet_has_typed_and_text_columns() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    let db = prices().await;
+    let path = std::env::temp_dir().join(format!("debot-db-{}.parquet", std::process::id()));
+    let options = ExportOptions {
+        sort_key: Some("price_point.timestamp".to_string()),
+        ..Default::default()
+    };
+    let num = debot_db::export_parquet(
+        &db,
+        &PriceLog::default(),
+        &options,
+        std::fs::File::create(&path).unwrap(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(num, 3);
+
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let schema = reader.metadata().file_metadata().schema_descr();
+    let column = |name: &str| {
+        (0..schema.num_columns())
+            .find(|&i| schema.column(i).name() == name)
+            .unwrap()
+    };
+    let (timestamp, price) = (column("price_point.timestamp"), column("price_point.price"));
+
+    let rows: Vec<_> = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(|row| row.unwrap())
+        .collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0].get_long(timestamp).unwrap(), 100);
+    assert_eq!(
+        rows[2].get_string(price).unwrap(),
+        "2000.123456789012345678"
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,136 @@
+// Named feature vectors, their schemas and the DebugLog adapter.
+
+use debot_db::{
+    CandlePattern, CounterMode, DbError, DebugLog, FeatureRegistry, FeatureSchema, FeatureSpec,
+    FeatureVector, MemoryBackend, PriceLog, PricePoint, StorageBackend, TransactionLog,
+    DEBUG_LOG_SCHEMA,
+};
+use rust_decimal::Decimal;
+
+fn schema(version: u32) -> FeatureSchema {
+    FeatureSchema {
+        name: "mm".to_string(),
+        version,
+        inputs: vec![
+            FeatureSpec::numeric("spread"),
+            FeatureSpec::pattern("pattern"),
+        ],
+        outputs: vec!["fill_rate".to_string()],
+    }
+}
+
+fn vector(schema: &FeatureSchema) -> FeatureVector {
+    FeatureVector::new(schema)
+        .with_input("spread", Decimal::new(15, 4))
+        .with_input("pattern", CandlePattern::Doji)
+        .with_output("fill_rate", Decimal::new(8, 1))
+}
+
+#[test]
+fn vectors_follow_their_schema() {
+    let schema = schema(1);
+    let vector = vector(&schema);
+
+    let inputs = schema.to_inputs(&vector).unwrap();
+    assert_eq!(inputs.len(), 1 + 16);
+    assert_eq!(inputs[0], Decimal::new(15, 4));
+    assert_eq!(inputs[1 + 5], Decimal::ONE);
+    assert_eq!(
+        schema.to_outputs(&vector).unwrap(),
+        vec![Decimal::new(8, 1)]
+    );
+
+    let wrong_kind = vector.clone().with_input("pattern", Decimal::ONE);
+    assert!(matches!(
+        schema.validate(&wrong_kind),
+        Err(DbError::InvalidQuery(_))
+    ));
+    let unknown = vector.clone().with_input("depth", Decimal::ONE);
+    assert!(schema.validate(&unknown).is_err());
+    assert!(schema.validate(&FeatureVector::new(&schema)).is_err());
+    assert!(self::schema(2).validate(&vector).is_err());
+
+    let document = bson::to_document(&vector).unwrap();
+    let restored: FeatureVector = bson::from_document(document).unwrap();
+    assert_eq!(restored, vector);
+}
+
+#[test]
+fn debug_log_is_adapted_to_a_vector() {
+    let debug = DebugLog {
+        input_1: Decimal::new(1, 0),
+        input_29: Decimal::new(29, 0),
+        input_30: CandlePattern::Hammer,
+        output_5: Decimal::new(5, 0),
+        ..Default::default()
+    };
+    let vector = FeatureVector::from(&debug);
+    assert_eq!(vector.schema, DEBUG_LOG_SCHEMA);
+
+    let schema = FeatureSchema::debug_log();
+    let inputs = schema.to_inputs(&vector).unwrap();
+    assert_eq!(inputs.len(), 29 + 10 * 16);
+    assert_eq!(inputs[0], Decimal::new(1, 0));
+    assert_eq!(inputs[28], Decimal::new(29, 0));
+    assert_eq!(&inputs[29..29 + 16], &CandlePattern::Hammer.to_one_hot());
+    assert_eq!(schema.to_outputs(&vector).unwrap()[4], Decimal::new(5, 0));
+
+    let point = PricePoint {
+        debug: Some(debug),
+        ..Default::default()
+    };
+    assert_eq!(point.feature_vector(), Some(vector));
+}
+
+#[tokio::test]
+async fn schemas_are_saved_in_the_registry() {
+    let db = MemoryBackend::new();
+    FeatureRegistry::create_indexes(&db).await.unwrap();
+    // As left behind by creating the indexes on MongoDB.
+    db.insert_one("feature_schemas", bson::doc! {"_id": "placeholder"})
+        .await
+        .unwrap();
+
+    let mut registry = FeatureRegistry::load(&db).await.unwrap();
+    assert!(registry.get(DEBUG_LOG_SCHEMA, 1).is_some());
+    registry.save(&db, schema(1)).await.unwrap();
+    registry.save(&db, schema(2)).await.unwrap();
+    registry.save(&db, schema(1)).await.unwrap();
+
+    let mut changed = schema(1);
+    changed.outputs.clear();
+    assert!(matches!(
+        registry.save(&db, changed).await,
+        Err(DbError::DuplicateKey(_))
+    ));
+
+    let registry = FeatureRegistry::load(&db).await.unwrap();
+    assert_eq!(registry.latest("mm").unwrap().version, 2);
+    assert_eq!(registry.schemas().count(), 3);
+    let vector = vector(&schema(1));
+    assert_eq!(registry.schema_of(&vector), Some(&schema(1)));
+}
+
+#[tokio::test]
+async fn features_are_stored_with_prices() {
+    let log =
+        TransactionLog::new(None, None, None, "", "", "", true, true, CounterMode::Local).await;
+    let db = log.get_w_db().await.unwrap();
+
+    let features = vector(&schema(1));
+    let price = PriceLog {
+        id: Some(1),
+        name: "dex".to_string(),
+        token_name: "ETH".to_string(),
+        price_point: PricePoint {
+            timestamp: 1,
+            price: Decimal::ONE,
+            features: Some(features.clone()),
+            ..Default::default()
+        },
+    };
+    TransactionLog::update_price(&db, price).await.unwrap();
+
+    let prices = TransactionLog::get_price_market_data(&db, None, None, true).await;
+    assert_eq!(prices["dex"]["ETH"][0].feature_vector(), Some(features));
+}
@@ -0,0 +1,143 @@
+// Bulk import of historical prices.
+
+use debot_db::{
+    import_prices, search_items, Counter, ImportFormat, ImportSummary, MemoryBackend, PriceColumns,
+    PriceImportOptions, PriceLog, SearchMode, TransactionLog,
+};
+use rust_decimal::Decimal;
+use std::io::Cursor;
+
+async fn stored_prices(db: &MemoryBackend) -> Vec<PriceLog> {
+    search_items(
+        db,
+        &PriceLog::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        Some("id"),
+        None,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn csv_columns_are_mapped_and_duplicates_skipped() {
+    let db = MemoryBackend::new();
+    let existing = PriceLog {
+        id: Some(1),
+        name: "dex".to_string(),
+        token_name: "ETH".to_string(),
+        price_point: debot_db::PricePoint {
+            timestamp: 60,
+            price: Decimal::new(1999, 0),
+            ..Default::default()
+        },
+    };
+    TransactionLog::update_price(&db, existing).await.unwrap();
+
+    let csv = "\
+time_ms,symbol,close,vol
+60000,ETH,2000,1.5
+120000,ETH,\"2001.25\",
+120000,ETH,2002,
+180000,BTC,1e4,0.1
+";
+    let options = PriceImportOptions {
+        columns: PriceColumns {
+            token_name: "symbol".to_string(),
+            timestamp: "time_ms".to_string(),
+            price: "close".to_string(),
+            volume: Some("vol".to_string()),
+            ..Default::default()
+        },
+        name: Some("dex".to_string()),
+        timestamp_in_millis: true,
+        batch_size: 2,
+        ..Default::default()
+    };
+    let counter = Counter::new(None, None, None, 0, 1, 0);
+    let summary = import_prices(&db, Cursor::new(csv), &options, Some(&counter))
+        .await
+        .unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            read: 4,
+            inserted: 2,
+            skipped: 2,
+        }
+    );
+
+    let prices = stored_prices(&db).await;
+    assert_eq!(prices.len(), 3);
+    assert_eq!(prices[1].id, Some(2));
+    assert_eq!(prices[1].price_point.timestamp, 120);
+    assert_eq!(prices[1].price_point.price, Decimal::new(200125, 2));
+    assert_eq!(prices[1].price_point.volume, None);
+    assert_eq!(prices[2].token_name, "BTC");
+    assert_eq!(prices[2].price_point.price, Decimal::new(10000, 0));
+    assert_eq!(prices[2].price_point.volume, Some(Decimal::new(1, 1)));
+    assert!(prices[2]
+        .price_point
+        .timestamp_str
+        .starts_with("1970-01-01T00:03:00"));
+
+    // Importing again adds nothing.
+    let summary = import_prices(&db, Cursor::new(csv), &options, Some(&counter))
+        .await
+        .unwrap();
+    assert_eq!(summary.inserted, 0);
+    assert_eq!(summary.skipped, 4);
+
+    // Live ticks in the same second are still stored.
+    let tick = PriceLog {
+        id: Some(10),
+        ..prices[2].clone()
+    };
+    TransactionLog::update_price(&db, tick).await.unwrap();
+    assert_eq!(stored_prices(&db).await.len(), 4);
+}
+
+#[tokio::test]
+async fn json_lines_keep_source_ids() {
+    let db = MemoryBackend::new();
+    let jsonl = r#"
+{"id": 7, "name": "dex", "token_name": "ETH", "timestamp": 10, "price": "2000", "num_trades": 3}
+{"id": 9, "name": "dex", "token_name": "ETH", "timestamp": 20, "price": 2001.5, "funding_rate": null}
+"#;
+    let options = PriceImportOptions {
+        format: ImportFormat::JsonLines,
+        columns: PriceColumns {
+            id: Some("id".to_string()),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let summary = import_prices(&db, Cursor::new(jsonl), &options, None)
+        .await
+        .unwrap();
+    assert_eq!(summary.inserted, 2);
+
+    let prices = stored_prices(&db).await;
+    assert_eq!(prices[0].id, Some(7));
+    assert_eq!(prices[0].price_point.num_trades, Some(3));
+    assert_eq!(prices[1].id, Some(9));
+    assert_eq!(prices[1].price_point.price, Decimal::new(20015, 1));
+
+    // Without an id column, ids need a counter.
+    let options = PriceImportOptions {
+        format: ImportFormat::JsonLines,
+        ..Default::default()
+    };
+    let jsonl = r#"{"name": "dex", "token_name": "ETH", "timestamp": 30, "price": "1"}"#;
+    assert!(import_prices(&db, Cursor::new(jsonl), &options, None)
+        .await
+        .is_err());
+
+    let jsonl = r#"{"name": "dex", "token_name": "ETH", "timestamp": "soon", "price": "1"}"#;
+    let e = import_prices(&db, Cursor::new(jsonl), &options, None)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("line 1"));
+}
@@ -0,0 +1,114 @@
+// JSON Lines backend: durability across reopen and bulk load into another store.
+
+use debot_db::{
+    delete_item, CounterMode, JsonlBackend, MemoryBackend, PositionLog, PositionState, PriceLog,
+    PricePoint, StorageBackend, TransactionLog,
+};
+use rust_decimal::Decimal;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("debot-db-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn price(id: u32, timestamp: i64) -> PriceLog {
+    PriceLog {
+        id: Some(id),
+        name: "dex".to_string(),
+        token_name: "SOL".to_string(),
+        price_point: PricePoint {
+            timestamp,
+            price: Decimal::new(2050, 1),
+            ..Default::default()
+        },
+    }
+}
+
+#[tokio::test]
+async fn jsonl_log_is_replayed_on_open() {
+    let dir = temp_dir("replay");
+
+    {
+        let backend: Arc<dyn StorageBackend> = Arc::new(JsonlBackend::open(&dir).unwrap());
+        let log = TransactionLog::with_backend(
+            None,
+            None,
+            None,
+            backend.clone(),
+            backend,
+            false,
+            CounterMode::Local,
+        )
+        .await;
+        let db = log.get_w_db().await.unwrap();
+
+        for id in 1..=3 {
+            let position = PositionLog {
+                id: Some(id),
+                open_timestamp: id as i64,
+                state: PositionState::Open,
+                ..Default::default()
+            };
+            TransactionLog::update_transaction(&db, &position)
+                .await
+                .unwrap();
+        }
+        let mut position = PositionLog {
+            id: Some(2),
+            open_timestamp: 2,
+            ..Default::default()
+        };
+        position.state = PositionState::Closed;
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+        delete_item(
+            &db,
+            &PositionLog {
+                id: Some(3),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        TransactionLog::update_price(&db, price(1, 100))
+            .await
+            .unwrap();
+        TransactionLog::update_price(&db, price(2, 200))
+            .await
+            .unwrap();
+    }
+
+    assert!(dir.join("position.jsonl").exists());
+    assert!(dir.join("price.jsonl").exists());
+
+    let backend: Arc<dyn StorageBackend> = Arc::new(JsonlBackend::open(&dir).unwrap());
+    let log = TransactionLog::with_backend(
+        None,
+        None,
+        None,
+        backend.clone(),
+        backend,
+        false,
+        CounterMode::Local,
+    )
+    .await;
+    let db = log.get_r_db().await.unwrap();
+
+    let positions = TransactionLog::get_all_positions(&db, None, None, true).await;
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 2]);
+    assert_eq!(positions[1].state, PositionState::Closed);
+
+    // Bulk load into another store through the regular copy flow.
+    let target = MemoryBackend::new();
+    TransactionLog::copy_price(&db, &target, None).await;
+    let prices = TransactionLog::get_price_market_data(&target, None, None, true).await;
+    assert_eq!(prices["dex"]["SOL"].len(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,1281 @@
+// TransactionLog behaviour on the in-memory backend.
+
+use bson::{doc, Bson};
+use debot_db::{
+    insert_item, replay_position_events, retry_on_conflict, search_items, search_page,
+    search_stream, AppState, AppStateField, AppStatePatch, CounterMode, CounterType, DbError,
+    Entity, Fill, IndexSpec, MemoryBackend, OrderSide, PageToken, PnlLog, PositionFilter,
+    PositionLog, PositionState, PositionTime, PositionType, PriceLog, PricePoint, SearchMode,
+    StorageBackend, TransactionLog,
+};
+use futures::TryStreamExt;
+use rust_decimal::Decimal;
+use std::sync::Arc;
+
+async fn in_memory_log() -> TransactionLog {
+    TransactionLog::new(None, None, None, "", "", "", true, true, CounterMode::Local).await
+}
+
+fn position(id: u32, open_timestamp: i64) -> PositionLog {
+    PositionLog {
+        id: Some(id),
+        token_name: "ETH".to_string(),
+        open_timestamp,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn positions_are_sorted_and_limited() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    for (id, open_timestamp) in [(1, 30), (2, 10), (3, 20)] {
+        TransactionLog::update_transaction(&db, &position(id, open_timestamp))
+            .await
+            .unwrap();
+    }
+
+    let positions = TransactionLog::get_all_positions(&db, None, None, true).await;
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![2, 3, 1]);
+
+    let positions = TransactionLog::get_all_positions(&db, Some(2), None, false).await;
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 3]);
+
+    let positions = TransactionLog::get_all_positions(&db, None, Some(3), true).await;
+    assert_eq!(positions.len(), 1);
+    assert_eq!(positions[0].open_timestamp, 20);
+}
+
+#[tokio::test]
+async fn unique_id_index_is_enforced() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    let pnl = PnlLog {
+        id: Some(log.increment_counter(CounterType::Pnl).await.unwrap()),
+        date: "2024-01-01".to_string(),
+        pnl: Decimal::ONE,
+        ..Default::default()
+    };
+    TransactionLog::insert_pnl(&db, pnl.clone()).await.unwrap();
+    let err = insert_item(&db, &pnl).await.unwrap_err();
+    assert!(matches!(err, DbError::DuplicateKey(_)));
+}
+
+#[tokio::test]
+async fn indexes_follow_updates_and_deletes() {
+    let db = MemoryBackend::new();
+    let documents: Vec<_> = (1..=20_000).map(|id| doc! {"id": id, "n": 0}).collect();
+    db.insert_many("items", documents).await.unwrap();
+    db.create_indexes("items", vec![IndexSpec::new("id_1", doc! {"id": 1}, true)])
+        .await
+        .unwrap();
+
+    // Numbers of any width are the same key.
+    let err = db
+        .insert_one("items", doc! {"id": 7_i64})
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::DuplicateKey(_)));
+    db.update_one(
+        "items",
+        doc! {"id": 7},
+        doc! {"$set": {"id": 20_001}},
+        false,
+    )
+    .await
+    .unwrap();
+    db.insert_one("items", doc! {"id": 7.0}).await.unwrap();
+    db.delete_one("items", doc! {"id": 8}).await.unwrap();
+    db.insert_one("items", doc! {"id": 8}).await.unwrap();
+
+    let found = db
+        .search(
+            "items",
+            doc! {"id": 20_001},
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert_eq!(found[0].get_i32("n").unwrap(), 0);
+    let found = db
+        .search(
+            "items",
+            doc! {"n": 0},
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 19_999);
+}
+
+#[tokio::test]
+async fn empty_search_reports_not_found() {
+    let log = in_memory_log().await;
+    let db = log.get_r_db().await.unwrap();
+
+    let err = search_items(
+        &db,
+        &PositionLog::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, DbError::NotFound(_)));
+
+    let err = search_items(
+        &db,
+        &PositionLog::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        Some("pnl"),
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(err, DbError::InvalidQuery(_)));
+
+    assert!(TransactionLog::get_price_market_data(&db, None, None, true)
+        .await
+        .is_empty());
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn app_state_round_trips() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    TransactionLog::update_app_state(
+        &db,
+        None,
+        Some(Decimal::new(1000, 0)),
+        None,
+        Some(Decimal::new(5, 0)),
+        Some(Decimal::new(3, 0)),
+        None,
+        None,
+        None,
+        None,
+        false,
+        Some("error".to_string()),
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    TransactionLog::update_app_state(
+        &db,
+        None,
+        None,
+        None,
+        Some(Decimal::new(2, 0)),
+        Some(Decimal::new(4, 0)),
+        None,
+        None,
+        None,
+        None,
+        false,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let state = TransactionLog::get_app_state(&db).await;
+    assert_eq!(state.last_equity, Some(Decimal::new(1000, 0)));
+    assert_eq!(state.max_dd, Some(Decimal::new(5, 0)));
+    assert_eq!(state.cumulative_return, Decimal::new(7, 0));
+    assert_eq!(state.error_time, vec!["error".to_string()]);
+}
+
+#[tokio::test]
+async fn app_state_patches_are_applied_atomically() {
+    let db = MemoryBackend::new();
+    let patch = AppStatePatch::new()
+        .set(AppStateField::LastEquity, Decimal::new(10005, 1))
+        .max(AppStateField::MaxDd, Decimal::new(5, 0))
+        .add(AppStateField::CumulativeReturn, Decimal::new(3, 0))
+        .push_error_time("a".to_string());
+    let state = TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap();
+    assert_eq!(state.last_equity, Some(Decimal::new(10005, 1)));
+    assert_eq!(state.cumulative_dd, Decimal::ZERO);
+    assert!(!state.curcuit_break);
+
+    let patch = AppStatePatch::new()
+        .max(AppStateField::MaxDd, Decimal::new(2, 0))
+        .add(AppStateField::CumulativeReturn, Decimal::new(-1, 0))
+        .push_error_time("b".to_string())
+        .curcuit_break(true);
+    TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap();
+
+    let state = TransactionLog::get_app_state(&db).await;
+    assert_eq!(state.last_equity, Some(Decimal::new(10005, 1)));
+    assert_eq!(state.max_dd, Some(Decimal::new(5, 0)));
+    assert_eq!(state.cumulative_return, Decimal::new(2, 0));
+    assert_eq!(state.error_time, vec!["a".to_string(), "b".to_string()]);
+    assert!(state.curcuit_break);
+
+    // A later operation on the same field replaces the earlier one.
+    let patch = AppStatePatch::new()
+        .add(AppStateField::CumulativeReturn, Decimal::ONE)
+        .set(AppStateField::CumulativeReturn, Decimal::ZERO);
+    let state = TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap();
+    assert_eq!(state.cumulative_return, Decimal::ZERO);
+}
+
+#[tokio::test]
+async fn app_state_numbers_keep_their_precision() {
+    let db = MemoryBackend::new();
+    let patch = AppStatePatch::new().add(AppStateField::CumulativeReturn, Decimal::new(1, 1));
+    for _ in 0..3 {
+        TransactionLog::apply_app_state_patch(&db, &patch)
+            .await
+            .unwrap();
+    }
+    let documents = db
+        .search(
+            "app-state",
+            doc! {},
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        documents[0].get("cumulative_return"),
+        Some(Bson::Decimal128(_))
+    ));
+    let state = TransactionLog::get_app_state(&db).await;
+    assert_eq!(state.cumulative_return, Decimal::new(3, 1));
+
+    // Values that cannot be stored fail the patch instead of being dropped.
+    let before_epoch = std::time::UNIX_EPOCH - std::time::Duration::from_secs(1);
+    let patch = AppStatePatch::new()
+        .curcuit_break(true)
+        .last_execution_time(before_epoch);
+    assert!(matches!(
+        TransactionLog::apply_app_state_patch(&db, &patch).await,
+        Err(DbError::Serialization(_))
+    ));
+    assert!(!TransactionLog::get_app_state(&db).await.curcuit_break);
+}
+
+#[tokio::test]
+async fn legacy_app_state_is_migrated() {
+    let db = MemoryBackend::new();
+    let legacy = doc! {
+        "id": 1,
+        "last_execution_time": null,
+        "last_equity": "100",
+        "ave_dd": null,
+        "max_dd": "7",
+        "cumulative_return": "1.5",
+        "cumulative_dd": "0",
+        "score": null,
+        "score_2": null,
+        "score_3": null,
+        "curcuit_break": false,
+        "error_time": [],
+        "max_invested_amount": 0.5,
+        "fund_configs": [],
+    };
+    db.insert_one("app-state", legacy).await.unwrap();
+    assert_eq!(
+        TransactionLog::get_app_state(&db).await.cumulative_return,
+        Decimal::new(15, 1)
+    );
+
+    let patch = AppStatePatch::new().add(AppStateField::CumulativeReturn, Decimal::ONE);
+    assert!(TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .is_err());
+
+    assert!(TransactionLog::migrate_app_state(&db).await.unwrap());
+    assert!(!TransactionLog::migrate_app_state(&db).await.unwrap());
+    let state = TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap();
+    assert_eq!(state.cumulative_return, Decimal::new(25, 1));
+    assert_eq!(state.max_dd, Some(Decimal::new(7, 0)));
+    assert_eq!(state.max_invested_amount, Decimal::new(5, 1));
+    let documents = db
+        .search(
+            "app-state",
+            doc! {},
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        documents[0].get("max_invested_amount"),
+        Some(Bson::Decimal128(_))
+    ));
+}
+
+#[tokio::test]
+async fn app_states_are_kept_per_instance() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+    // A state stored before instance keys existed is the default one.
+    let legacy = doc! {
+        "id": 1,
+        "last_execution_time": null,
+        "cumulative_return": 5.0,
+        "cumulative_dd": 0.0,
+        "curcuit_break": false,
+        "error_time": [],
+        "max_invested_amount": 0.0,
+        "fund_configs": [],
+    };
+    db.insert_one("app-state", legacy).await.unwrap();
+    // MongoDB keeps such a document in collections made for indexes.
+    db.insert_one("app-state", doc! {"_id": "placeholder"})
+        .await
+        .unwrap();
+
+    let patch = AppStatePatch::new().add(AppStateField::CumulativeReturn, Decimal::ONE);
+    let a = TransactionLog::apply_app_state_patch_for(&db, "bot-a", &patch)
+        .await
+        .unwrap();
+    assert_eq!((a.id, a.version), (2, 1));
+    assert_eq!(a.cumulative_return, Decimal::ONE);
+    let b = TransactionLog::apply_app_state_patch_for(
+        &*db,
+        "bot-b",
+        &patch.clone().curcuit_break(true),
+    )
+    .await
+    .unwrap();
+    assert_eq!(b.id, 3);
+    TransactionLog::apply_app_state_patch_for(&db, "bot-a", &patch)
+        .await
+        .unwrap();
+
+    let state = TransactionLog::get_app_state(&db).await;
+    assert_eq!(state.instance_key, None);
+    assert_eq!(state.cumulative_return, Decimal::new(5, 0));
+    let a = TransactionLog::get_app_state_for(&db, "bot-a").await;
+    assert_eq!(a.cumulative_return, Decimal::new(2, 0));
+    assert!(!a.curcuit_break);
+    let unknown = TransactionLog::get_app_state_for(&db, "bot-c").await;
+    assert_eq!(unknown.instance_key.as_deref(), Some("bot-c"));
+    assert_eq!(unknown.version, 0);
+
+    let mut b = TransactionLog::get_app_state_for(&db, "bot-b").await;
+    b.curcuit_break = false;
+    let b = TransactionLog::update_app_state_if_unchanged(&db, &b)
+        .await
+        .unwrap();
+    assert_eq!((b.id, b.version), (3, 2));
+
+    let keys: Vec<_> = TransactionLog::list_app_states(&db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|state| state.instance_key)
+        .collect();
+    assert_eq!(
+        keys,
+        [None, Some("bot-a".to_string()), Some("bot-b".to_string())]
+    );
+
+    TransactionLog::delete_app_state_for(&db, "bot-a")
+        .await
+        .unwrap();
+    assert_eq!(TransactionLog::list_app_states(&db).await.unwrap().len(), 2);
+    assert_eq!(
+        TransactionLog::get_app_state_for(&db, "bot-a")
+            .await
+            .version,
+        0
+    );
+}
+
+#[tokio::test]
+async fn app_states_without_a_key_are_not_unique() {
+    let db = MemoryBackend::new();
+    let state = |id: u32| {
+        let mut document = bson::to_document(&AppState {
+            id,
+            ..Default::default()
+        })
+        .unwrap();
+        document.insert("instance_key", Bson::Null);
+        document
+    };
+    // Missing and null keys are the same key to a unique index, as in
+    // MongoDB, until the nulls are migrated away.
+    db.insert_one("app-state", state(1)).await.unwrap();
+    db.insert_one("app-state", state(2)).await.unwrap();
+    assert!(TransactionLog::migrate_app_state(&db).await.unwrap());
+    AppState::default().create_indexes(&db).await.unwrap();
+    let documents = db
+        .search(
+            "app-state",
+            doc! {},
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert!(documents.iter().all(|d| !d.contains_key("instance_key")));
+
+    insert_item(
+        &db,
+        &AppState {
+            id: 3,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    // Only one null key is indexed, so it does not collide with state 3.
+    db.insert_one("app-state", state(4)).await.unwrap();
+    assert!(matches!(
+        db.insert_one("app-state", state(5)).await,
+        Err(DbError::DuplicateKey(_))
+    ));
+
+    let keyed = AppState {
+        id: 6,
+        ..AppState::for_instance("bot-a")
+    };
+    insert_item(&db, &keyed).await.unwrap();
+    assert!(matches!(
+        insert_item(
+            &db,
+            &AppState {
+                id: 7,
+                ..keyed.clone()
+            }
+        )
+        .await,
+        Err(DbError::DuplicateKey(_))
+    ));
+    // An instance state is deleted by its key, not by its id.
+    AppState::for_instance("bot-a").delete(&db).await.unwrap();
+    assert_eq!(TransactionLog::list_app_states(&db).await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn back_test_start_keeps_other_instances_state() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let patch = AppStatePatch::new().curcuit_break(true);
+    TransactionLog::apply_app_state_patch(&backend, &patch)
+        .await
+        .unwrap();
+    TransactionLog::apply_app_state_patch_for(&backend, "bot-a", &patch)
+        .await
+        .unwrap();
+
+    TransactionLog::with_backend(
+        None,
+        None,
+        None,
+        backend.clone(),
+        backend.clone(),
+        true,
+        CounterMode::Local,
+    )
+    .await;
+    assert!(!TransactionLog::get_app_state(&backend).await.curcuit_break);
+    assert!(
+        TransactionLog::get_app_state_for(&backend, "bot-a")
+            .await
+            .curcuit_break
+    );
+}
+
+#[tokio::test]
+async fn distributed_counters_do_not_collide() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    TransactionLog::update_transaction(&backend, &position(5, 0))
+        .await
+        .unwrap();
+
+    let first = TransactionLog::with_backend(
+        None,
+        None,
+        None,
+        backend.clone(),
+        backend.clone(),
+        false,
+        CounterMode::Distributed,
+    )
+    .await;
+    let second = TransactionLog::with_backend(
+        None,
+        None,
+        None,
+        backend.clone(),
+        backend.clone(),
+        false,
+        CounterMode::Distributed,
+    )
+    .await;
+
+    let mut ids = vec![];
+    for _ in 0..3 {
+        ids.push(first.allocate_id(CounterType::Position).await.unwrap());
+        ids.push(second.allocate_id(CounterType::Position).await.unwrap());
+    }
+    assert_eq!(ids, vec![6, 7, 8, 9, 10, 11]);
+
+    // Other counter types have their own sequence.
+    assert_eq!(first.allocate_id(CounterType::Pnl).await.unwrap(), 1);
+}
+
+#[tokio::test]
+async fn distributed_counter_wraps_like_local_counter() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let distributed = TransactionLog::with_backend(
+        Some(3),
+        None,
+        None,
+        backend.clone(),
+        backend,
+        false,
+        CounterMode::Distributed,
+    )
+    .await;
+    let local = TransactionLog::new(
+        Some(3),
+        None,
+        None,
+        "",
+        "",
+        "",
+        true,
+        true,
+        CounterMode::Local,
+    )
+    .await;
+
+    for _ in 0..5 {
+        assert_eq!(
+            distributed
+                .allocate_id(CounterType::Position)
+                .await
+                .unwrap(),
+            local.allocate_id(CounterType::Position).await.unwrap()
+        );
+    }
+}
+
+#[tokio::test]
+async fn capped_counter_overwrites_recycled_ids_and_resumes_after_restart() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let log = TransactionLog::with_backend(
+        None,
+        None,
+        Some(3),
+        backend.clone(),
+        backend.clone(),
+        false,
+        CounterMode::Local,
+    )
+    .await;
+
+    let mut ids = vec![];
+    for day in 1..=3 {
+        let id = log.increment_counter(CounterType::Pnl).await.unwrap();
+        ids.push(id);
+        let pnl = PnlLog {
+            id: Some(id),
+            date: format!("2024-01-0{}", day),
+            pnl: Decimal::new(day, 0),
+            ..Default::default()
+        };
+        TransactionLog::insert_pnl(&backend, pnl).await.unwrap();
+    }
+    assert_eq!(ids, vec![1, 2, 1]);
+
+    let pnls = search_items(
+        &backend,
+        &PnlLog::default(),
+        SearchMode::Ascending,
+        None,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(pnls.len(), 2);
+    assert_eq!(pnls[0].date, "2024-01-03");
+    assert_eq!(pnls[1].date, "2024-01-02");
+
+    // The highest stored id is 2, but the ring buffer continues at slot 2
+    // of the second generation rather than starting over at 1.
+    let restarted = TransactionLog::with_backend(
+        None,
+        None,
+        Some(3),
+        backend.clone(),
+        backend,
+        false,
+        CounterMode::Local,
+    )
+    .await;
+    assert_eq!(
+        restarted.increment_counter(CounterType::Pnl).await.unwrap(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn time_range_queries_filter_and_sort() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    for (id, open_timestamp, close_timestamp, token_name) in [
+        (1, 100, Some(150), "ETH"),
+        (2, 200, None, "ETH"),
+        (3, 120, Some(300), "BTC"),
+        (4, 50, Some(120), "ETH"),
+    ] {
+        let position = PositionLog {
+            id: Some(id),
+            fund_name: "fund".to_string(),
+            token_name: token_name.to_string(),
+            open_timestamp,
+            close_timestamp,
+            ..Default::default()
+        };
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+
+    let opened = TransactionLog::get_positions_in_range(
+        &db,
+        PositionTime::Open,
+        100,
+        200,
+        Some("fund"),
+        None,
+    )
+    .await
+    .unwrap();
+    let ids: Vec<_> = opened.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 3]);
+
+    let closed = TransactionLog::get_positions_in_range(
+        &db,
+        PositionTime::Close,
+        0,
+        1000,
+        None,
+        Some("ETH"),
+    )
+    .await
+    .unwrap();
+    let ids: Vec<_> = closed.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![4, 1]);
+
+    for (id, name, timestamp) in [(1, "dex", 10), (2, "cex", 20), (3, "dex", 30)] {
+        let price = PriceLog {
+            id: Some(id),
+            name: name.to_string(),
+            token_name: "ETH".to_string(),
+            price_point: PricePoint {
+                timestamp,
+                ..Default::default()
+            },
+        };
+        TransactionLog::update_price(&db, price).await.unwrap();
+    }
+
+    let prices = TransactionLog::get_prices_in_range(&db, 10, 30, None, Some("ETH"))
+        .await
+        .unwrap();
+    let ids: Vec<_> = prices.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 2]);
+
+    let prices = TransactionLog::get_prices_in_range(&db, 0, 100, Some("dex"), None)
+        .await
+        .unwrap();
+    let ids: Vec<_> = prices.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 3]);
+
+    assert!(
+        TransactionLog::get_prices_in_range(&db, 100, 200, None, None)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn position_filter_is_passed_through_search_items() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    for (id, fund_name, token_name, state, open_timestamp) in [
+        (1, "fund-a", "ETH", PositionState::Open, 10),
+        (2, "fund-a", "ETH", PositionState::Closed, 20),
+        (3, "fund-a", "BTC", PositionState::Open, 30),
+        (4, "fund-b", "ETH", PositionState::Open, 40),
+        (5, "fund-a", "ETH", PositionState::Open, 50),
+    ] {
+        let position = PositionLog {
+            id: Some(id),
+            fund_name: fund_name.to_string(),
+            token_name: token_name.to_string(),
+            state,
+            position_type: PositionType::Long,
+            open_timestamp,
+            ..Default::default()
+        };
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+
+    let filter = PositionFilter {
+        fund_name: Some("fund-a".to_string()),
+        token_name: Some("ETH".to_string()),
+        state: Some(PositionState::Open),
+        ..Default::default()
+    };
+    let positions = search_items(
+        &db,
+        &PositionLog::default(),
+        SearchMode::Descending,
+        None,
+        None,
+        Some("open_timestamp"),
+        Some(filter.clone().into()),
+    )
+    .await
+    .unwrap();
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![5, 1]);
+
+    let filter = PositionFilter {
+        open_to: Some(50),
+        ..filter
+    };
+    let positions = TransactionLog::search_positions(&db, &filter, None, true)
+        .await
+        .unwrap();
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1]);
+
+    let filter = PositionFilter {
+        position_type: Some(PositionType::Short),
+        ..Default::default()
+    };
+    assert!(TransactionLog::search_positions(&db, &filter, None, true)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn keyset_pages_and_stream_cover_every_item_once() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    // Several prices share a timestamp, so pages must break ties by id.
+    for (id, timestamp) in [(1, 30), (2, 10), (3, 10), (4, 20), (5, 10), (6, 30)] {
+        let price = PriceLog {
+            id: Some(id),
+            name: "dex".to_string(),
+            token_name: "ETH".to_string(),
+            price_point: PricePoint {
+                timestamp,
+                ..Default::default()
+            },
+        };
+        TransactionLog::update_price(&db, price).await.unwrap();
+    }
+
+    let item = PriceLog::default();
+    let sort_key = Some("price_point.timestamp");
+    let mut ids = vec![];
+    let mut token: Option<String> = None;
+    loop {
+        // Round-trip the token through its string form, as a client would.
+        let after = token.as_deref().map(|t| PageToken::decode(t).unwrap());
+        let page = search_page(
+            &db,
+            &item,
+            SearchMode::Ascending,
+            4,
+            sort_key,
+            None,
+            after.as_ref(),
+        )
+        .await
+        .unwrap();
+        ids.extend(page.items.iter().map(|p| p.id.unwrap()));
+        match page.next {
+            Some(next) => token = Some(next.encode()),
+            None => break,
+        }
+    }
+    assert_eq!(ids, vec![2, 3, 5, 4, 1, 6]);
+
+    let stream = search_stream(&db, &item, SearchMode::Descending, 2, sort_key, None);
+    let prices: Vec<PriceLog> = stream.try_collect().await.unwrap();
+    let ids: Vec<_> = prices.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![6, 1, 4, 5, 3, 2]);
+
+    let copy = MemoryBackend::new();
+    TransactionLog::copy_price(&db, &copy, Some(3)).await;
+    let copied = search_items(&copy, &item, SearchMode::Ascending, None, None, None, None)
+        .await
+        .unwrap();
+    let ids: Vec<_> = copied.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn keyset_pages_survive_null_sort_values_and_concurrent_inserts() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    // Open positions have no close time and sort before the closed ones.
+    for (id, close_timestamp) in [(2, None), (3, Some(20)), (4, None), (5, Some(10))] {
+        let mut position = position(id, 0);
+        position.close_timestamp = close_timestamp;
+        insert_item(&db, &position).await.unwrap();
+    }
+
+    let item = PositionLog::default();
+    let sort_key = Some("close_timestamp");
+    let first = search_page(&db, &item, SearchMode::Ascending, 2, sort_key, None, None)
+        .await
+        .unwrap();
+    let mut ids: Vec<_> = first.items.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![2, 4]);
+
+    // Written between pages with the sort value of the last item: the one
+    // after it in (sort_key, id) order is returned, the one before is not.
+    for id in [1, 6] {
+        insert_item(&db, &position(id, 0)).await.unwrap();
+    }
+    let mut after = first.next;
+    while let Some(token) = after {
+        let page = search_page(
+            &db,
+            &item,
+            SearchMode::Ascending,
+            2,
+            sort_key,
+            None,
+            Some(&token),
+        )
+        .await
+        .unwrap();
+        ids.extend(page.items.iter().map(|p| p.id.unwrap()));
+        after = page.next;
+    }
+    assert_eq!(ids, vec![2, 4, 6, 5, 3]);
+
+    let stream = search_stream(&db, &item, SearchMode::Descending, 2, sort_key, None);
+    let positions: Vec<PositionLog> = stream.try_collect().await.unwrap();
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![3, 5, 6, 4, 2, 1]);
+}
+
+#[tokio::test]
+async fn update_transaction_rejects_illegal_state_changes() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    let mut position = position(1, 0);
+    for state in [
+        PositionState::Opening,
+        PositionState::Open,
+        PositionState::Closing,
+        PositionState::Closed,
+    ] {
+        position.state = state;
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+
+    position.state = PositionState::Open;
+    let err = TransactionLog::update_transaction(&db, &position)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::InvalidTransition(_)));
+
+    let stored = TransactionLog::get_all_positions(&db, None, Some(1), true).await;
+    assert_eq!(stored[0].state, PositionState::Closed);
+}
+
+#[tokio::test]
+async fn concurrent_position_updates_conflict() {
+    let db = MemoryBackend::new();
+    let stored = TransactionLog::update_transaction_if_unchanged(&db, &position(1, 0), "open")
+        .await
+        .unwrap();
+    assert_eq!(stored.version, 1);
+
+    let mut first = stored.clone();
+    first.pnl = Decimal::ONE;
+    let mut second = stored.clone();
+    second.pnl = Decimal::TWO;
+    let first = TransactionLog::update_transaction_if_unchanged(&db, &first, "update")
+        .await
+        .unwrap();
+    assert_eq!(first.version, 2);
+    let err = TransactionLog::update_transaction_if_unchanged(&db, &second, "update")
+        .await
+        .unwrap_err();
+    assert!(err.is_conflict());
+
+    // Reading again before each attempt gets the change in.
+    let updated = retry_on_conflict(3, || async {
+        let mut current = TransactionLog::get_all_positions(&db, None, Some(1), true)
+            .await
+            .remove(0);
+        current.pnl += Decimal::TWO;
+        TransactionLog::update_transaction_if_unchanged(&db, &current, "update").await
+    })
+    .await
+    .unwrap();
+    assert_eq!(updated.version, 3);
+    assert_eq!(updated.pnl, Decimal::new(3, 0));
+
+    // Unconditional updates still bump the version.
+    TransactionLog::update_transaction(&db, &second)
+        .await
+        .unwrap();
+    let stored = TransactionLog::get_all_positions(&db, None, Some(1), true).await;
+    assert_eq!(stored[0].version, 4);
+}
+
+#[tokio::test]
+async fn stale_app_state_is_not_written() {
+    let db = MemoryBackend::new();
+    let mut state = TransactionLog::get_app_state(&db).await;
+    state.cumulative_return = Decimal::ONE;
+    let state = TransactionLog::update_app_state_if_unchanged(&db, &state)
+        .await
+        .unwrap();
+    assert_eq!(state.version, 1);
+
+    let patch = AppStatePatch::new().push_error_time("a".to_string());
+    TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap();
+    let err = TransactionLog::update_app_state_if_unchanged(&db, &state)
+        .await
+        .unwrap_err();
+    assert!(err.is_conflict());
+    let patch = AppStatePatch::new().curcuit_break(true).if_version(1);
+    assert!(TransactionLog::apply_app_state_patch(&db, &patch)
+        .await
+        .unwrap_err()
+        .is_conflict());
+
+    let state = TransactionLog::apply_app_state_patch(&db, &patch.if_version(2))
+        .await
+        .unwrap();
+    assert_eq!(state.version, 3);
+    assert_eq!(state.error_time, vec!["a".to_string()]);
+    assert_eq!(state.cumulative_return, Decimal::ONE);
+    assert!(state.curcuit_break);
+}
+
+#[tokio::test]
+async fn recycled_position_ids_start_a_new_position() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let log = TransactionLog::with_backend(
+        Some(3),
+        None,
+        None,
+        backend.clone(),
+        backend.clone(),
+        false,
+        CounterMode::Local,
+    )
+    .await;
+
+    let mut ids = vec![];
+    for open_timestamp in [10, 20, 30] {
+        let id = log.increment_counter(CounterType::Position).await.unwrap();
+        ids.push(id);
+        let mut position = position(id, open_timestamp);
+        // The counter may hand out an id in use, so a new position is
+        // written as a replacement.
+        position.state = PositionState::Opening;
+        TransactionLog::replace_transaction(&backend, &position)
+            .await
+            .unwrap();
+        for state in [PositionState::Open, PositionState::Closed] {
+            position.state = state;
+            TransactionLog::update_transaction(&backend, &position)
+                .await
+                .unwrap();
+        }
+    }
+    assert_eq!(ids, vec![1, 2, 1]);
+
+    let stored = TransactionLog::get_all_positions(&backend, None, Some(1), true).await;
+    assert_eq!(stored[0].open_timestamp, 30);
+    assert_eq!(stored[0].state, PositionState::Closed);
+    assert_eq!(stored[0].version, 6);
+
+    // Neither the same position nor a new one on its id may reopen it
+    // through an update.
+    let mut reopened = stored[0].clone();
+    reopened.state = PositionState::Open;
+    let err = TransactionLog::update_transaction(&backend, &reopened)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::InvalidTransition(_)));
+    let err = TransactionLog::update_transaction_if_unchanged(&backend, &position(1, 40), "open")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::Conflict(_)));
+    let err = TransactionLog::update_transaction(&backend, &position(1, 40))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, DbError::InvalidTransition(_)));
+
+    // Only the events of the position now kept under the id are replayed.
+    let history = TransactionLog::get_position_history(&backend, 1)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 3);
+    assert!(history[0].previous.is_none());
+    let replayed = replay_position_events(&history).unwrap();
+    assert!(replayed.iter().all(|p| p.open_timestamp == 30));
+    assert_eq!(replayed[2].state, PositionState::Closed);
+
+    TransactionLog::replace_transaction(&backend, &position(2, 50))
+        .await
+        .unwrap();
+    let history = TransactionLog::get_position_history(&backend, 2)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].changes.get_i64("open_timestamp").unwrap(), 50);
+}
+
+#[tokio::test]
+async fn legacy_state_spellings_are_found_and_migrated() {
+    let db = MemoryBackend::new();
+    for (id, state) in [
+        (1, "Closed"),
+        (2, "closed"),
+        (3, "CLOSED"),
+        (4, "Closed(TakeProfit)"),
+        (5, "open"),
+        (6, ""),
+    ] {
+        let mut document = bson::to_document(&position(id, id as i64)).unwrap();
+        document.insert("state", state);
+        db.insert_one("position", document).await.unwrap();
+    }
+    let filter = PositionFilter {
+        state: Some(PositionState::Closed),
+        open_to: Some(6),
+        ..Default::default()
+    };
+    async fn found(db: &MemoryBackend, filter: &PositionFilter) -> Vec<u32> {
+        TransactionLog::search_positions(db, filter, None, true)
+            .await
+            .unwrap()
+            .iter()
+            .map(|p| p.id.unwrap())
+            .collect()
+    }
+    assert_eq!(found(&db, &filter).await, vec![1, 2, 3]);
+
+    assert_eq!(
+        TransactionLog::migrate_position_names(&db).await.unwrap(),
+        4
+    );
+    assert_eq!(found(&db, &filter).await, vec![1, 2, 3, 4]);
+    let stored = db
+        .search(
+            "position",
+            doc! { "id": 6 },
+            SearchMode::Ascending,
+            None,
+            None,
+            "id",
+        )
+        .await
+        .unwrap();
+    assert_eq!(stored[0].get_str("state").unwrap(), "");
+}
+
+#[test]
+fn legacy_state_strings_still_deserialize() {
+    let document = bson::doc! {
+        "id": 1,
+        "state": "closed",
+        "position_type": "SHORT",
+    };
+    let mut position = bson::to_document(&PositionLog::default()).unwrap();
+    position.extend(document);
+    let position: PositionLog = bson::from_document(position).unwrap();
+    assert_eq!(position.state, PositionState::Closed);
+    assert_eq!(position.position_type, PositionType::Short);
+
+    assert_eq!(
+        "Cancelled".parse::<PositionState>().unwrap(),
+        PositionState::Canceled
+    );
+    assert_eq!(
+        "Closed(TakeProfit)".parse::<PositionState>().unwrap(),
+        PositionState::Closed
+    );
+    assert!("Closd".parse::<PositionState>().is_err());
+    assert!("".parse::<PositionState>().is_err());
+    assert!(" ".parse::<PositionType>().is_err());
+    assert_eq!(
+        bson::to_bson(&PositionState::Liquidated).unwrap(),
+        bson::Bson::String("Liquidated".to_string())
+    );
+}
+
+#[tokio::test]
+async fn position_changes_are_recorded_as_events() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    let mut position = position(1, 10);
+    TransactionLog::update_transaction(&db, &position)
+        .await
+        .unwrap();
+    position.state = PositionState::Open;
+    position.asset_in_usd = Decimal::new(50, 0);
+    TransactionLog::update_transaction_with_reason(&db, &position, "partial fill")
+        .await
+        .unwrap();
+    // Nothing changed, so nothing is recorded.
+    TransactionLog::update_transaction(&db, &position)
+        .await
+        .unwrap();
+    position.asset_in_usd = Decimal::new(100, 0);
+    TransactionLog::update_transaction_with_reason(&db, &position, "fill")
+        .await
+        .unwrap();
+    TransactionLog::update_transaction(&db, &self::position(2, 20))
+        .await
+        .unwrap();
+
+    let events = TransactionLog::get_position_history(&db, 1).await.unwrap();
+    let reasons: Vec<_> = events.iter().map(|e| e.reason.as_str()).collect();
+    assert_eq!(reasons, vec!["update", "partial fill", "fill"]);
+    assert!(events[0].previous.is_none());
+    let previous = events[1].previous.as_ref().unwrap();
+    assert_eq!(previous.get_str("state").unwrap(), "Opening");
+    assert_eq!(events[1].changes.get_str("state").unwrap(), "Open");
+    assert_eq!(events[2].changes.len(), 1);
+
+    let snapshots = debot_db::replay_position_events(&events).unwrap();
+    let assets: Vec<_> = snapshots.iter().map(|p| p.asset_in_usd).collect();
+    assert_eq!(
+        assets,
+        vec![Decimal::ZERO, Decimal::new(50, 0), Decimal::new(100, 0)]
+    );
+    assert_eq!(snapshots[1].state, PositionState::Open);
+
+    let timestamp = events[0].timestamp;
+    assert!(TransactionLog::get_position_at(&db, 1, timestamp - 1)
+        .await
+        .unwrap()
+        .is_none());
+    let latest = TransactionLog::get_position_at(&db, 1, timestamp + 60)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(latest.asset_in_usd, Decimal::new(100, 0));
+
+    assert!(TransactionLog::get_position_history(&db, 3)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+fn fill(side: OrderSide, price: i64, size: i64, timestamp: i64) -> Fill {
+    Fill {
+        side,
+        price: Decimal::new(price, 0),
+        size: Decimal::new(size, 0),
+        fee: Decimal::new(1, 1),
+        timestamp,
+        order_id: format!("order-{}", timestamp),
+    }
+}
+
+#[tokio::test]
+async fn fills_derive_prices_pnl_and_fees() {
+    let log = in_memory_log().await;
+    let db = log.get_w_db().await.unwrap();
+
+    let mut position = PositionLog {
+        position_type: PositionType::Short,
+        ..position(1, 10)
+    };
+    // Scaled entry, then a partial close.
+    position.add_fill(fill(OrderSide::Sell, 100, 1, 10));
+    position.add_fill(fill(OrderSide::Sell, 110, 3, 20));
+    position.add_fill(fill(OrderSide::Buy, 95, 2, 30));
+
+    assert_eq!(position.ordered_price, Decimal::new(100, 0));
+    assert_eq!(position.average_open_price, Decimal::new(1075, 1));
+    assert_eq!(position.close_price, Decimal::new(95, 0));
+    assert_eq!(position.open_size(), Decimal::new(2, 0));
+    // (107.5 - 95) * 2 on a short.
+    assert_eq!(position.pnl, Decimal::new(25, 0));
+    assert_eq!(position.fee, Decimal::new(3, 1));
+
+    TransactionLog::update_transaction(&db, &position)
+        .await
+        .unwrap();
+    let stored = TransactionLog::get_all_positions(&db, None, Some(1), true).await;
+    assert_eq!(stored[0].fills, position.fills);
+    assert_eq!(stored[0].realized_pnl(), Decimal::new(25, 0));
+
+    // Positions stored before fills existed keep their flat fields.
+    let mut document = bson::to_document(&PositionLog {
+        average_open_price: Decimal::new(42, 0),
+        ..self::position(2, 20)
+    })
+    .unwrap();
+    document.remove("fills");
+    let mut legacy: PositionLog = bson::from_document(document).unwrap();
+    legacy.update_from_fills();
+    assert!(legacy.fills.is_empty());
+    assert_eq!(legacy.average_open_price, Decimal::new(42, 0));
+}
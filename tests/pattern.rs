@@ -0,0 +1,108 @@
+// Candlestick pattern fixtures, one per CandlePattern variant.
+
+use debot_db::{Candle, CandlePattern, PatternDetector, PatternThresholds};
+use rust_decimal::Decimal;
+
+fn candle(open: i64, high: i64, low: i64, close: i64) -> Candle {
+    Candle {
+        open: Decimal::new(open, 0),
+        high: Decimal::new(high, 0),
+        low: Decimal::new(low, 0),
+        close: Decimal::new(close, 0),
+        ..Default::default()
+    }
+}
+
+// (open, high, low, close)
+type Ohlc = (i64, i64, i64, i64);
+
+const LONG_BEARISH: Ohlc = (110, 111, 99, 100);
+const LONG_BULLISH: Ohlc = (100, 111, 99, 110);
+
+fn fixtures() -> Vec<(CandlePattern, Vec<Ohlc>)> {
+    vec![
+        (CandlePattern::Doji, vec![(100, 110, 90, 101)]),
+        (CandlePattern::Hammer, vec![(96, 101, 80, 100)]),
+        (CandlePattern::InvertedHammer, vec![(100, 120, 99, 104)]),
+        (CandlePattern::SpinningTop, vec![(100, 110, 90, 104)]),
+        (CandlePattern::Marubozu, vec![(100, 120, 100, 120)]),
+        (
+            CandlePattern::BullishEngulfing,
+            vec![LONG_BEARISH, (99, 113, 98, 112)],
+        ),
+        (
+            CandlePattern::BearishEngulfing,
+            vec![LONG_BULLISH, (111, 112, 98, 99)],
+        ),
+        (
+            CandlePattern::PiercingPattern,
+            vec![LONG_BEARISH, (98, 108, 97, 107)],
+        ),
+        (
+            CandlePattern::DarkCloudCover,
+            vec![LONG_BULLISH, (112, 113, 102, 103)],
+        ),
+        (
+            CandlePattern::Harami,
+            vec![LONG_BEARISH, (102, 108, 101, 107)],
+        ),
+        (
+            CandlePattern::HaramiCross,
+            vec![LONG_BEARISH, (105, 110, 100, 105)],
+        ),
+        (
+            CandlePattern::MorningStar,
+            vec![LONG_BEARISH, (97, 99, 95, 96), (97, 108, 96, 107)],
+        ),
+        (
+            CandlePattern::EveningStar,
+            vec![LONG_BULLISH, (113, 115, 111, 114), (112, 113, 102, 103)],
+        ),
+        (
+            CandlePattern::ThreeWhiteSoldiers,
+            vec![LONG_BULLISH, (105, 116, 104, 115), (110, 121, 109, 120)],
+        ),
+        (
+            CandlePattern::ThreeBlackCrows,
+            vec![(120, 121, 109, 110), (115, 116, 104, 105), LONG_BEARISH],
+        ),
+        (CandlePattern::None, vec![(100, 100, 100, 100)]),
+    ]
+}
+
+#[test]
+fn every_fixture_is_detected_as_its_pattern() {
+    let detector = PatternDetector::default();
+    for (pattern, candles) in fixtures() {
+        let candles: Vec<_> = candles
+            .into_iter()
+            .map(|(o, h, l, c)| candle(o, h, l, c))
+            .collect();
+        assert_eq!(
+            detector.detect_primary(&candles),
+            pattern,
+            "fixture for {:?} detected as {:?}",
+            pattern,
+            detector.detect(&candles)
+        );
+    }
+}
+
+#[test]
+fn patterns_only_look_at_the_last_candles() {
+    let detector = PatternDetector::default();
+    let mut candles = vec![candle(96, 101, 80, 100)];
+    candles.push(candle(100, 120, 100, 120));
+    assert_eq!(detector.detect(&candles), vec![CandlePattern::Marubozu]);
+    assert!(detector.detect(&[]).is_empty());
+}
+
+#[test]
+fn thresholds_are_configurable() {
+    let doji = [candle(100, 110, 90, 101)];
+    let strict = PatternDetector::new(PatternThresholds {
+        doji_body: Decimal::new(1, 2),
+        ..Default::default()
+    });
+    assert_eq!(strict.detect(&doji), vec![CandlePattern::SpinningTop]);
+}
@@ -0,0 +1,166 @@
+// TransactionLog behaviour on the SQLite backend.
+#![cfg(feature = "sqlite")]
+
+use debot_db::{
+    pnl_logs, search_stream, CounterMode, PnlFilter, PositionLog, PositionTime, PriceLog,
+    PricePoint, SearchMode, SqliteBackend, StorageBackend, TransactionLog,
+};
+use futures::TryStreamExt;
+use rust_decimal::Decimal;
+use std::sync::Arc;
+
+async fn sqlite_log(backend: Arc<dyn StorageBackend>) -> TransactionLog {
+    TransactionLog::with_backend(
+        None,
+        None,
+        None,
+        backend.clone(),
+        backend,
+        false,
+        CounterMode::Local,
+    )
+    .await
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn entities_round_trip_through_sqlite() {
+    let backend: Arc<dyn StorageBackend> = Arc::new(SqliteBackend::open_in_memory().unwrap());
+    let log = sqlite_log(backend).await;
+    let db = log.get_w_db().await.unwrap();
+
+    for (id, open_timestamp) in [(1, 30), (2, 10)] {
+        let position = PositionLog {
+            id: Some(id),
+            token_name: "BTC".to_string(),
+            open_timestamp,
+            ..Default::default()
+        };
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+    let positions = TransactionLog::get_all_positions(&db, None, None, true).await;
+    let ids: Vec<_> = positions.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![2, 1]);
+
+    let positions =
+        TransactionLog::get_positions_in_range(&db, PositionTime::Open, 20, 40, None, Some("BTC"))
+            .await
+            .unwrap();
+    assert_eq!(positions.len(), 1);
+    assert_eq!(positions[0].id, Some(1));
+
+    let events = TransactionLog::get_position_history(&db, 1).await.unwrap();
+    assert_eq!(events.len(), 1);
+    let replayed = debot_db::replay_position_events(&events).unwrap();
+    assert_eq!(replayed[0].open_timestamp, 30);
+
+    for (id, timestamp) in [(1, 200), (2, 100)] {
+        let price = PriceLog {
+            id: Some(id),
+            name: "dex".to_string(),
+            token_name: "BTC".to_string(),
+            price_point: PricePoint {
+                timestamp,
+                price: Decimal::new(12345, 2),
+                ..Default::default()
+            },
+        };
+        TransactionLog::update_price(&db, price).await.unwrap();
+    }
+    let prices = TransactionLog::get_price_market_data(&db, Some(1), None, false).await;
+    let points = &prices["dex"]["BTC"];
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].timestamp, 200);
+    assert_eq!(points[0].price, Decimal::new(12345, 2));
+
+    let item = PriceLog::default();
+    let stream = search_stream(
+        &db,
+        &item,
+        SearchMode::Ascending,
+        1,
+        Some("price_point.timestamp"),
+        None,
+    );
+    let prices: Vec<PriceLog> = stream.try_collect().await.unwrap();
+    let ids: Vec<_> = prices.iter().map(|p| p.id.unwrap()).collect();
+    assert_eq!(ids, vec![2, 1]);
+
+    TransactionLog::update_app_state(
+        &db,
+        None,
+        Some(Decimal::new(42, 0)),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        true,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    let state = TransactionLog::get_app_state(&db).await;
+    assert_eq!(state.last_equity, Some(Decimal::new(42, 0)));
+    assert!(state.curcuit_break);
+}
+
+#[tokio::test]
+async fn sqlite_file_survives_reopen() {
+    let path = std::env::temp_dir().join(format!("debot-db-{}.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    {
+        let backend: Arc<dyn StorageBackend> = Arc::new(SqliteBackend::open(&path).unwrap());
+        let log = sqlite_log(backend).await;
+        let db = log.get_w_db().await.unwrap();
+        let position = PositionLog {
+            id: Some(7),
+            ..Default::default()
+        };
+        TransactionLog::update_transaction(&db, &position)
+            .await
+            .unwrap();
+    }
+
+    let backend: Arc<dyn StorageBackend> = Arc::new(SqliteBackend::open(&path).unwrap());
+    let positions = TransactionLog::get_all_positions(&backend, None, Some(7), true).await;
+    assert_eq!(positions.len(), 1);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn columns_added_later_are_filled_from_documents() {
+    let path = std::env::temp_dir().join(format!("debot-db-{}-pnl.sqlite", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    {
+        // A "balance" table from before PnlLog had a timestamp column.
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                r#"CREATE TABLE "balance" (document TEXT NOT NULL, "id");
+                INSERT INTO "balance" VALUES
+                    ('{"id": 1, "timestamp": 100, "date": "", "pnl": "1"}', 1),
+                    ('{"id": 2, "timestamp": 200, "date": "", "pnl": "2"}', 2);"#,
+            )
+            .unwrap();
+    }
+
+    let backend = SqliteBackend::open(&path).unwrap();
+    let filter = PnlFilter {
+        from: Some(150),
+        ..Default::default()
+    };
+    let logs = pnl_logs(&backend, &filter).await.unwrap();
+    assert_eq!(logs.len(), 1);
+    assert_eq!(logs[0].id, Some(2));
+
+    std::fs::remove_file(&path).unwrap();
+}